@@ -1,4 +1,5 @@
 use criterion::{criterion_group, criterion_main, Criterion};
+use jam_rs::hash_functions::DEFAULT_SEED;
 use std::time::Duration;
 
 #[inline]
@@ -18,10 +19,10 @@ fn criterion_benchmark(c: &mut Criterion) {
 
     for x in u64::MAX - 20..u64::MAX {
         group.bench_with_input(format!("xxhash_{}", x), &x, |b, &x| {
-            b.iter(|| jam_rs::hash_functions::xxhash3(&x.to_be_bytes()));
+            b.iter(|| jam_rs::hash_functions::xxhash3(&x.to_be_bytes(), DEFAULT_SEED));
         });
         group.bench_with_input(format!("ahash_{}", x), &x, |b, &x| {
-            b.iter(|| jam_rs::hash_functions::ahash(x));
+            b.iter(|| jam_rs::hash_functions::ahash(x, DEFAULT_SEED));
         });
         group.bench_with_input(format!("murmur3_old_{}", x), &x, |b, &x| {
             b.iter(|| murmur3_old(&x.to_be_bytes()));
@@ -34,12 +34,62 @@ pub enum OutputFormats {
     Sourmash,
 }
 
-#[derive(ValueEnum, Debug, Clone, Deserialize, Serialize)]
+#[derive(ValueEnum, Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub enum HashAlgorithms {
-    Default, // AHash < 32 | Xxhash >= 32
+    Default, // AHash < 64 | Xxhash >= 64
     Ahash,
     Xxhash,
     Murmur3,
+    #[value(skip)]
+    Custom(String), // Hasher registered with `hash_functions::register_hasher`
+}
+
+#[derive(ValueEnum, Debug, Clone, Default, PartialEq, Eq)]
+pub enum ResultFormat {
+    #[default]
+    Tsv, // Tab separated with header
+    Csv,   // Comma separated with header
+    Jsonl, // One json object per line
+    Json,  // Json array
+}
+
+#[derive(ValueEnum, Debug, Clone, PartialEq, Eq)]
+pub enum MatrixMetric {
+    Jaccard,
+    Containment, // Maximum containment of both directions
+    Ani,         // Estimated from the maximum containment
+}
+
+#[derive(ValueEnum, Debug, Clone, PartialEq, Eq)]
+pub enum MatrixFormat {
+    Tsv,    // Tab separated matrix with labels
+    Npy,    // Numpy array (float64), labels are written to <output>.labels.txt
+    Phylip, // Distance matrix (1 - similarity)
+}
+
+#[derive(ValueEnum, Debug, Clone, PartialEq, Eq)]
+pub enum ClusterMethod {
+    Single,  // Single linkage
+    Average, // Average linkage (UPGMA)
+    Greedy,  // Greedy centroid clustering, largest sketches first
+}
+
+#[derive(ValueEnum, Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Molecule {
+    #[default]
+    Dna,
+    Protein, // Amino acids
+    Dayhoff, // Reduced amino acid alphabet (6 letters)
+    Hp,      // Hydrophobic-polar alphabet (2 letters)
+}
+
+#[derive(ValueEnum, Debug, Clone, Default, PartialEq, Eq)]
+pub enum Sampling {
+    #[default]
+    All, // Hash every kmer
+    OpenSyncmer,   // Kmers with the smallest s-mer in the middle
+    ClosedSyncmer, // Kmers with the smallest s-mer at the start or end
+    Minimizer,     // Kmers with the smallest hash of w consecutive kmers
 }
 
 #[derive(Debug, Subcommand, Clone)]
@@ -54,15 +104,31 @@ pub enum Commands {
         #[arg(short, long)]
         #[arg(value_parser = clap::value_parser!(std::path::PathBuf))]
         output: Option<PathBuf>,
-        /// kmer size, all sketches must have the same size to be compared
-        #[arg(short = 'k', long = "kmer-size", default_value = "21")]
-        kmer_size: u8,
+        /// Name of the signature (default: input path), requires a single input
+        /// Useful for stdin (-) and named pipes that do not have a meaningful file name
+        #[arg(long)]
+        name: Option<String>,
+        /// kmer size(s), all sketches must have the same size to be compared
+        /// Multiple comma separated sizes are sketched in a single pass (e.g. 21,31,51)
+        #[arg(
+            short = 'k',
+            long = "kmer-size",
+            default_value = "21",
+            value_delimiter = ','
+        )]
+        kmer_size: Vec<u8>,
         /// Scale the hash space to a minimum fraction of the maximum hash value (FracMinHash)
         #[arg(long)]
         fscale: Option<u64>,
         /// Maximum number of k-mers (per record) to be hashed, top cut-off
         #[arg(long)]
         nmax: Option<u64>,
+        /// Minimum number of hashes (per record), filled up with the smallest hashes above the max hash
+        #[arg(long)]
+        nmin: Option<u64>,
+        /// Keep the smallest hashes of 1 / kscale of all kmers per record (KmerCountScaling)
+        #[arg(long, conflicts_with = "fscale")]
+        kscale: Option<u64>,
         /// Change to other output formats
         #[arg(long, default_value = "lmdb")]
         format: OutputFormats,
@@ -73,23 +139,65 @@ pub enum Commands {
         /// Will increase the size of the output file if lmdb is used
         #[arg(long)]
         singleton: bool,
+        /// Molecule type, amino acid kmers for protein, dayhoff and hp (kmer size in amino acids)
+        #[arg(long, default_value = "dna")]
+        molecule: Molecule,
+        /// Input files contain protein sequences, otherwise DNA is translated in all six frames
+        #[arg(long)]
+        input_is_protein: bool,
+        /// Keep the number of occurrences for each hash (abundance)
+        #[arg(long)]
+        track_abundance: bool,
+        /// Append to an existing lmdb database in the output folder
+        /// kmer size and fscale must match the existing database
+        #[arg(long)]
+        append: bool,
+        /// Csv file with lineages (identifier as first column), stored in lmdb databases
+        /// Either a `;` separated lineage, one column per rank or a NCBI taxid (requires --taxdump)
+        #[arg(long)]
+        lineages: Option<PathBuf>,
+        /// NCBI taxdump folder (nodes.dmp, names.dmp) to resolve the taxids of the lineage file
+        #[arg(long, requires = "lineages")]
+        taxdump: Option<PathBuf>,
+        /// Create one sketch per window of each sequence record (positional sketches)
+        /// Sketches are named `<id>:<start>-<end>`, only supported for the lmdb output format
+        #[arg(long)]
+        window: Option<u64>,
+        /// Distance between the starts of two windows [default: window size]
+        #[arg(long, requires = "window")]
+        step: Option<u64>,
+        /// Keep the length, GC and N content of each sketch (sequence record with --singleton)
+        #[arg(long)]
+        stats: bool,
+        /// Select kmers before hashing, syncmers by their smallest s-mer, minimizers per window
+        #[arg(long, default_value = "all")]
+        sampling: Sampling,
+        /// S-mer size of open and closed syncmers, must be smaller than the kmer size
+        #[arg(long)]
+        smer_size: Option<u8>,
+        /// Number of consecutive kmers of each minimizer window
+        #[arg(long)]
+        minimizer_window: Option<u8>,
+        /// Seed of the hash functions, sketches are only comparable with the same seed
+        #[arg(long, default_value = "42")]
+        seed: u64,
     },
     /// Merge multiple input sketches into a single sketch
-    // #[command(arg_required_else_help = true)]
-    // Merge {
-    //     /// One or more input sketches
-    //     #[arg(value_parser = clap::value_parser!(std::path::PathBuf))]
-    //     inputs: Vec<PathBuf>,
-    //     /// Output file
-    //     #[arg(short, long, required = true)]
-    //     #[arg(value_parser = clap::value_parser!(std::path::PathBuf))]
-    //     output: PathBuf,
-    // },
+    #[command(arg_required_else_help = true)]
+    Merge {
+        /// One or more input sketches, either lmdb databases (.mdb) or sourmash json files
+        #[arg(value_parser = clap::value_parser!(std::path::PathBuf))]
+        inputs: Vec<PathBuf>,
+        /// Output file (sourmash) or output folder (lmdb)
+        #[arg(short, long, required = true)]
+        #[arg(value_parser = clap::value_parser!(std::path::PathBuf))]
+        output: PathBuf,
+    },
     /// Estimate containment of a (small) sketch against a subset of one or more sketches as database.
     /// Requires all sketches to have the same kmer size
     #[command(arg_required_else_help = true)]
     Dist {
-        /// Input sketch or raw file
+        /// Input raw file or precomputed sketch (sourmash json, .sig.gz or lmdb .mdb)
         #[arg(short, long)]
         input: PathBuf,
         /// Database sketch(es), 1 lmdb file or multiple sourmash json files
@@ -102,6 +210,160 @@ pub enum Commands {
         /// Cut-off value for similarity
         #[arg(short, long, default_value = "0.0")]
         cutoff: f64,
+        /// Only report results with a p-value (random match probability) below or equal to this value
+        #[arg(long)]
+        max_pvalue: Option<f64>,
+        /// Format of the results, csv and json contain named columns
+        #[arg(long, default_value = "tsv")]
+        output_format: ResultFormat,
+        /// Only report the best n hits (highest estimated containment) per query sketch
+        #[arg(long, conflicts_with = "best_hit")]
+        top_n: Option<usize>,
+        /// Only report the best hit per query sketch (same as --top-n 1)
+        #[arg(long)]
+        best_hit: bool,
+        /// Sketch raw inputs with one sketch per window to report the containment per window
+        #[arg(long)]
+        window: Option<u64>,
+        /// Distance between the starts of two windows [default: window size]
+        #[arg(long, requires = "window")]
+        step: Option<u64>,
+        /// Only report matches with a GC content of at least x%, requires a database sketched with --stats
+        #[arg(long)]
+        gc_lower: Option<f64>,
+        /// Only report matches with a GC content of at most y%, requires a database sketched with --stats
+        #[arg(long)]
+        gc_upper: Option<f64>,
+    },
+    /// Compare all inputs against each other and write a symmetric similarity matrix
+    #[command(arg_required_else_help = true)]
+    Compare {
+        /// Input raw files or precomputed sketches (sourmash json, .sig.gz or lmdb .mdb)
+        #[arg(value_parser = clap::value_parser!(std::path::PathBuf))]
+        input: Vec<PathBuf>,
+        /// Output to file instead of stdout (required for npy)
+        #[arg(short, long)]
+        #[arg(value_parser = clap::value_parser!(std::path::PathBuf))]
+        output: Option<PathBuf>,
+        /// Similarity metric of the matrix
+        #[arg(long, default_value = "jaccard")]
+        metric: MatrixMetric,
+        /// Output format of the matrix
+        #[arg(long, default_value = "tsv")]
+        format: MatrixFormat,
+        /// kmer size to sketch raw files if no precomputed sketch is given
+        #[arg(short = 'k', long = "kmer-size", default_value = "21")]
+        kmer_size: u8,
+        /// FracMinHash scale to sketch raw files if no precomputed sketch is given
+        #[arg(long)]
+        fscale: Option<u64>,
+        /// Hash algorithm to sketch raw files if no precomputed sketch is given
+        #[arg(long, default_value = "default")]
+        algorithm: HashAlgorithms,
+        /// Molecule type to sketch raw files if no precomputed sketch is given
+        #[arg(long, default_value = "dna")]
+        molecule: Molecule,
+        /// Kmer sampling to sketch raw files if no precomputed sketch is given
+        #[arg(long, default_value = "all")]
+        sampling: Sampling,
+        /// S-mer size of open and closed syncmers to sketch raw files
+        #[arg(long)]
+        smer_size: Option<u8>,
+        /// Number of consecutive kmers of each minimizer window to sketch raw files
+        #[arg(long)]
+        minimizer_window: Option<u8>,
+        /// Seed of the hash functions to sketch raw files if no precomputed sketch is given
+        #[arg(long, default_value = "42")]
+        seed: u64,
+    },
+    /// Cluster all inputs by their pairwise ANI and select one representative per cluster
+    #[command(arg_required_else_help = true)]
+    Cluster {
+        /// Input raw files or precomputed sketches (sourmash json, .sig.gz or lmdb .mdb)
+        #[arg(value_parser = clap::value_parser!(std::path::PathBuf))]
+        input: Vec<PathBuf>,
+        /// Output cluster memberships to file instead of stdout
+        #[arg(short, long)]
+        #[arg(value_parser = clap::value_parser!(std::path::PathBuf))]
+        output: Option<PathBuf>,
+        /// Minimum ANI of two sketches in the same cluster
+        #[arg(long, default_value = "0.95")]
+        ani: f64,
+        /// Clustering method
+        #[arg(long, default_value = "greedy")]
+        method: ClusterMethod,
+        /// Write an lmdb database with only the representatives to this output folder
+        #[arg(long)]
+        dereplicated: Option<PathBuf>,
+        /// kmer size to sketch raw files if no precomputed sketch is given
+        #[arg(short = 'k', long = "kmer-size", default_value = "21")]
+        kmer_size: u8,
+        /// FracMinHash scale to sketch raw files if no precomputed sketch is given
+        #[arg(long)]
+        fscale: Option<u64>,
+        /// Hash algorithm to sketch raw files if no precomputed sketch is given
+        #[arg(long, default_value = "default")]
+        algorithm: HashAlgorithms,
+        /// Molecule type to sketch raw files if no precomputed sketch is given
+        #[arg(long, default_value = "dna")]
+        molecule: Molecule,
+        /// Kmer sampling to sketch raw files if no precomputed sketch is given
+        #[arg(long, default_value = "all")]
+        sampling: Sampling,
+        /// S-mer size of open and closed syncmers to sketch raw files
+        #[arg(long)]
+        smer_size: Option<u8>,
+        /// Number of consecutive kmers of each minimizer window to sketch raw files
+        #[arg(long)]
+        minimizer_window: Option<u8>,
+        /// Seed of the hash functions to sketch raw files if no precomputed sketch is given
+        #[arg(long, default_value = "42")]
+        seed: u64,
+    },
+    /// Find the minimum set of database sketches that covers the hashes of the input (metagenome)
+    /// Database sketches are selected greedily by their overlap with the remaining hashes
+    #[command(arg_required_else_help = true)]
+    Gather {
+        /// Input raw file or precomputed sketch (sourmash json, .sig.gz or lmdb .mdb)
+        #[arg(short, long)]
+        input: PathBuf,
+        /// Database sketch, 1 lmdb file
+        #[arg(short, long)]
+        database: PathBuf,
+        /// Output to file instead of stdout
+        #[arg(short, long)]
+        #[arg(value_parser = clap::value_parser!(std::path::PathBuf))]
+        output: Option<PathBuf>,
+        /// Minimum number of (unique) hashes shared with the remaining query hashes
+        #[arg(long, default_value = "3")]
+        min_overlap: usize,
+        /// Format of the results, csv and json contain named columns
+        #[arg(long, default_value = "tsv")]
+        output_format: ResultFormat,
+    },
+    /// Assign the hashes of the input to the lowest common ancestor (LCA)
+    /// of all matching database sketches, requires a database with lineages
+    #[command(arg_required_else_help = true)]
+    Classify {
+        /// Input raw file or precomputed sketch (sourmash json, .sig.gz or lmdb .mdb)
+        #[arg(short, long)]
+        input: PathBuf,
+        /// Database sketch, 1 lmdb file sketched with --lineages
+        #[arg(short, long)]
+        database: PathBuf,
+        /// Output to file instead of stdout
+        #[arg(short, long)]
+        #[arg(value_parser = clap::value_parser!(std::path::PathBuf))]
+        output: Option<PathBuf>,
+        /// Minimum fraction of the classified hashes required to assign a taxon
+        #[arg(long, default_value = "0.5")]
+        min_fraction: f64,
+        /// Report the hashes of all taxa per rank instead of one assignment per query
+        #[arg(long)]
+        per_rank: bool,
+        /// Format of the results, csv and json contain named columns
+        #[arg(long, default_value = "tsv")]
+        output_format: ResultFormat,
     },
 
     #[command(arg_required_else_help = true)]
@@ -0,0 +1,307 @@
+//! Clustering and dereplication of sketch collections based on the pairwise ANI
+use crate::cli::{ClusterMethod, MatrixMetric};
+use crate::compare::SimilarityMatrix;
+use crate::signature::Signature;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ClusterMember {
+    pub name: String,
+    pub cluster: usize,
+    pub representative: String,
+    pub is_representative: bool,
+    pub ani: f64, // ANI to the representative
+}
+
+impl Display for ClusterMember {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{:.4}",
+            self.name, self.cluster, self.representative, self.is_representative, self.ani
+        )
+    }
+}
+
+/// Disjoint sets of indices (union find with path halving)
+struct DisjointSets {
+    parents: Vec<usize>,
+}
+
+impl DisjointSets {
+    fn new(size: usize) -> Self {
+        DisjointSets {
+            parents: (0..size).collect(),
+        }
+    }
+
+    fn find(&mut self, mut idx: usize) -> usize {
+        while self.parents[idx] != idx {
+            self.parents[idx] = self.parents[self.parents[idx]];
+            idx = self.parents[idx];
+        }
+        idx
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a != b {
+            self.parents[a.max(b)] = a.min(b);
+        }
+    }
+}
+
+/// Clusters of (collapsed) signatures, each cluster has one representative
+pub struct Clusters {
+    pub members: Vec<ClusterMember>,
+    signatures: Vec<Signature>,
+    representatives: Vec<usize>,
+}
+
+impl Clusters {
+    /// Clusters all signatures with a pairwise ANI of at least `min_ani`
+    ///
+    /// Representatives are the largest sketches (most hashes) of each cluster,
+    /// the greedy method assigns signatures (largest first) to the most similar representative
+    pub fn new(
+        mut signatures: Vec<Signature>,
+        min_ani: f64,
+        method: &ClusterMethod,
+        threads: usize,
+    ) -> Result<Self> {
+        for signature in signatures.iter_mut() {
+            let sketch = signature.collapse();
+            signature.sketches = vec![sketch];
+        }
+        let sketches = signatures
+            .iter()
+            .map(|s| &s.sketches[0])
+            .collect::<Vec<_>>();
+        let matrix = SimilarityMatrix::from_sketches(&sketches, &MatrixMetric::Ani, threads)?;
+
+        // Largest sketches first, ties are resolved by the input order
+        let mut order = (0..sketches.len()).collect::<Vec<_>>();
+        order.sort_by(|a, b| sketches[*b].hashes.len().cmp(&sketches[*a].hashes.len()));
+
+        // Index of the representative for every signature
+        let assignments = match method {
+            ClusterMethod::Greedy => greedy(&matrix, &order, min_ani),
+            ClusterMethod::Single | ClusterMethod::Average => {
+                let mut sets = if *method == ClusterMethod::Single {
+                    single_linkage(&matrix, min_ani)
+                } else {
+                    average_linkage(&matrix, min_ani)
+                };
+                // The first (largest) member of each set is its representative
+                let mut representatives = vec![None; sketches.len()];
+                for idx in order.iter() {
+                    let root = sets.find(*idx);
+                    representatives[root].get_or_insert(*idx);
+                }
+                (0..sketches.len())
+                    .map(|idx| representatives[sets.find(idx)].unwrap_or(idx))
+                    .collect()
+            }
+        };
+
+        // Clusters are numbered by the size of their representative
+        let representatives = order
+            .iter()
+            .copied()
+            .filter(|idx| assignments[*idx] == *idx)
+            .collect::<Vec<_>>();
+        let mut members = Vec::with_capacity(sketches.len());
+        for (cluster, representative) in representatives.iter().enumerate() {
+            for idx in order
+                .iter()
+                .filter(|idx| assignments[**idx] == *representative)
+            {
+                members.push(ClusterMember {
+                    name: matrix.names[*idx].clone(),
+                    cluster,
+                    representative: matrix.names[*representative].clone(),
+                    is_representative: idx == representative,
+                    ani: matrix.get(*idx, *representative),
+                });
+            }
+        }
+        Ok(Clusters {
+            members,
+            signatures,
+            representatives,
+        })
+    }
+
+    pub fn num_clusters(&self) -> usize {
+        self.representatives.len()
+    }
+
+    /// The (collapsed) signatures of all representatives, largest first
+    pub fn representatives(&self) -> Vec<Signature> {
+        self.representatives
+            .iter()
+            .map(|idx| self.signatures[*idx].clone())
+            .collect()
+    }
+}
+
+/// Assigns each signature to the most similar representative or creates a new one
+fn greedy(matrix: &SimilarityMatrix, order: &[usize], min_ani: f64) -> Vec<usize> {
+    let mut assignments = vec![0; matrix.len()];
+    let mut representatives: Vec<usize> = Vec::new();
+    for idx in order.iter().copied() {
+        let best = representatives
+            .iter()
+            .copied()
+            .filter(|rep| matrix.get(idx, *rep) >= min_ani)
+            .max_by(|a, b| matrix.get(idx, *a).total_cmp(&matrix.get(idx, *b)));
+        assignments[idx] = match best {
+            Some(rep) => rep,
+            None => {
+                representatives.push(idx);
+                idx
+            }
+        };
+    }
+    assignments
+}
+
+fn single_linkage(matrix: &SimilarityMatrix, min_ani: f64) -> DisjointSets {
+    let mut sets = DisjointSets::new(matrix.len());
+    for row in 0..matrix.len() {
+        for col in row + 1..matrix.len() {
+            if matrix.get(row, col) >= min_ani {
+                sets.union(row, col);
+            }
+        }
+    }
+    sets
+}
+
+/// UPGMA with the nearest neighbor chain algorithm, average linkage is reducible
+/// which allows to cut the dendrogram by applying all merges above the threshold
+fn average_linkage(matrix: &SimilarityMatrix, min_ani: f64) -> DisjointSets {
+    let size = matrix.len();
+    let mut similarities = matrix.values.clone();
+    let mut cluster_sizes = vec![1.0; size];
+    let mut active = vec![true; size];
+    let mut num_active = size;
+    let mut chain: Vec<usize> = Vec::new();
+    let mut sets = DisjointSets::new(size);
+
+    while num_active > 1 {
+        if chain.is_empty() {
+            chain.extend(active.iter().position(|a| *a));
+        }
+        let current = chain[chain.len() - 1];
+        let previous = (chain.len() > 1).then(|| chain[chain.len() - 2]);
+        // Prefer the previous element of the chain on ties to guarantee termination
+        let mut nearest = previous;
+        for other in (0..size).filter(|other| active[*other] && *other != current) {
+            let similarity = similarities[current * size + other];
+            if nearest.is_none_or(|n| similarity > similarities[current * size + n]) {
+                nearest = Some(other);
+            }
+        }
+        let Some(nearest) = nearest else {
+            break;
+        };
+        if Some(nearest) != previous {
+            chain.push(nearest);
+            continue;
+        }
+
+        // Reciprocal nearest neighbors, merge `nearest` into `current`
+        chain.truncate(chain.len() - 2);
+        if similarities[current * size + nearest] >= min_ani {
+            sets.union(current, nearest);
+        }
+        let (size_a, size_b) = (cluster_sizes[current], cluster_sizes[nearest]);
+        for other in (0..size).filter(|other| active[*other]) {
+            let merged = (size_a * similarities[current * size + other]
+                + size_b * similarities[nearest * size + other])
+                / (size_a + size_b);
+            similarities[current * size + other] = merged;
+            similarities[other * size + current] = merged;
+        }
+        cluster_sizes[current] += size_b;
+        active[nearest] = false;
+        num_active -= 1;
+    }
+    sets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signature(name: &str, hashes: impl Iterator<Item = u64>) -> Signature {
+        let hashes = hashes.collect::<std::collections::BTreeSet<_>>();
+        Signature {
+            file_name: name.to_string(),
+            sketches: vec![crate::sketch::Sketch {
+                name: name.to_string(),
+                num_kmers: hashes.len(),
+                hashes,
+                kmer_size: 21,
+                abundances: None,
+                stats: None,
+                max_hash: None,
+            }],
+            algorithm: crate::cli::HashAlgorithms::Default,
+            kmer_size: 21,
+            max_hash: u64::MAX,
+            molecule: crate::cli::Molecule::Dna,
+            scaling: Default::default(),
+            sampling: Default::default(),
+            seed: crate::hash_functions::DEFAULT_SEED,
+        }
+    }
+
+    #[test]
+    fn test_cluster_methods() {
+        // ANI: a-b 0.976, b-c 0.957, a-c 0.0
+        let signatures = vec![
+            signature("a", 0..100),
+            signature("b", (0..60).chain(1000..1040)),
+            signature("c", (1000..1040).chain(2000..2060)),
+        ];
+        let clusters = |method| {
+            let clusters = Clusters::new(signatures.clone(), 0.95, &method, 2).unwrap();
+            clusters
+                .members
+                .iter()
+                .map(|m| (m.name.clone(), m.cluster))
+                .collect::<Vec<_>>()
+        };
+        let pairs = |pairs: &[(&str, usize)]| {
+            pairs
+                .iter()
+                .map(|(n, c)| (n.to_string(), *c))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            clusters(ClusterMethod::Single),
+            pairs(&[("a", 0), ("b", 0), ("c", 0)])
+        );
+        assert_eq!(
+            clusters(ClusterMethod::Average),
+            pairs(&[("a", 0), ("b", 0), ("c", 1)])
+        );
+        assert_eq!(
+            clusters(ClusterMethod::Greedy),
+            pairs(&[("a", 0), ("b", 0), ("c", 1)])
+        );
+
+        let clusters = Clusters::new(signatures, 0.95, &ClusterMethod::Average, 1).unwrap();
+        assert_eq!(clusters.num_clusters(), 2);
+        let representatives = clusters.representatives();
+        assert_eq!(representatives[0].file_name, "a");
+        assert_eq!(representatives[1].file_name, "c");
+        assert!(clusters.members[0].is_representative);
+        assert!(!clusters.members[1].is_representative);
+        assert!((clusters.members[1].ani - 0.6f64.powf(1.0 / 21.0)).abs() < 1e-9);
+    }
+}
@@ -1,10 +1,16 @@
-use crate::file_io::ShortSketchInfo;
-use crate::signature::Signature;
-use crate::sketch::Sketch;
+use crate::cli::HashAlgorithms;
+use crate::cli::MatrixMetric;
+use crate::cli::Molecule;
+use crate::file_io::{DatabaseMeta, ShortSketchInfo, ShortSketchInfoCodec, TsvHeader, META_KEY};
+use crate::hash_functions::DEFAULT_SEED;
+use crate::sampling::SamplingScheme;
+use crate::signature::{fscale_to_max_hash, Signature};
+use crate::sketch::{Sketch, Stats};
 use anyhow::anyhow;
 use anyhow::Result;
 use byteorder::BigEndian;
-use heed::types::SerdeBincode;
+use heed::types::SerdeJson;
+use heed::types::Str;
 use heed::types::U32;
 use heed::types::U64;
 use heed::DatabaseFlags;
@@ -12,10 +18,11 @@ use heed::EnvFlags;
 use indicatif::ParallelProgressIterator;
 use indicatif::ProgressBar;
 use indicatif::ProgressDrawTarget;
-use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+use rayon::prelude::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
 use serde::{Deserialize, Serialize};
-use std::cmp::max;
-use std::collections::HashMap;
+use statrs::distribution::{Binomial, DiscreteCDF};
+use std::cmp::{max, Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::RwLock;
@@ -27,39 +34,269 @@ use std::{
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct CompareResult {
-    pub from_name: String,
-    pub to_name: String,
+    #[serde(rename = "query")]
+    pub from_name: String, // Query sketch
+    #[serde(rename = "match")]
+    pub to_name: String, // Database sketch
     pub num_common: usize,
     pub num_kmers: usize,
-    pub reverse: bool,
+    #[serde(skip)]
+    pub reverse: bool, // The query is the smaller sketch
+    #[serde(rename = "containment")]
     pub estimated_containment: f64,
+    pub query_hashes: usize,     // Number of hashes in the query sketch
+    pub target_hashes: usize,    // Number of hashes in the target (database) sketch
+    pub query_containment: f64,  // Fraction of the query found in the target (in percent)
+    pub target_containment: f64, // Fraction of the target found in the query (in percent)
+    pub jaccard: f64,            // Jaccard index (0-1)
+    pub ani: f64,                // ANI estimated from the max containment (0-1)
+    pub mash_distance: f64,      // Mash distance estimated from the Jaccard index (0-1)
+    pub containment_lower: f64,  // Lower bound of the 95% CI of the query containment (in percent)
+    pub containment_upper: f64,  // Upper bound of the 95% CI of the query containment (in percent)
+    // Probability of at least num_common random matches, requires the database settings
+    pub p_value: Option<f64>,
+    // Only available if the query sketch tracks abundances
+    pub abundance_containment: Option<f64>,
+    pub mean_coverage: Option<f64>,
 }
 
 impl Display for CompareResult {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        if self.reverse {
-            write!(
-                f,
-                "{}\t{}\t{}\t{}\t{:.2}",
-                self.to_name,
-                self.from_name,
-                self.num_common,
-                self.num_kmers,
-                self.estimated_containment,
-            )?;
-            Ok(())
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{:.2}",
+            self.from_name,
+            self.to_name,
+            self.num_common,
+            self.num_kmers,
+            self.estimated_containment,
+        )?;
+        write!(
+            f,
+            "\t{:.2}\t{:.2}\t{:.4}\t{:.4}\t{:.4}",
+            self.query_containment,
+            self.target_containment,
+            self.jaccard,
+            self.ani,
+            self.mash_distance,
+        )?;
+        write!(
+            f,
+            "\t{:.2}\t{:.2}",
+            self.containment_lower, self.containment_upper
+        )?;
+        match self.p_value {
+            Some(p_value) => write!(f, "\t{:.3e}", p_value)?,
+            None => write!(f, "\tNA")?,
+        }
+        // Abundance columns are always written to keep the number of columns stable
+        for value in [self.abundance_containment, self.mean_coverage] {
+            match value {
+                Some(value) => write!(f, "\t{:.2}", value)?,
+                None => write!(f, "\tNA")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl TsvHeader for CompareResult {
+    fn tsv_header() -> &'static str {
+        "query\tmatch\tnum_common\tnum_kmers\tcontainment\tquery_containment\ttarget_containment\tjaccard\tani\tmash_distance\tcontainment_lower\tcontainment_upper\tp_value\tabundance_containment\tmean_coverage"
+    }
+}
+
+/// Two sided 95% quantile of the standard normal distribution
+const WILSON_Z: f64 = 1.959963984540054;
+
+impl CompareResult {
+    /// Calculates containment in both directions (with confidence interval), Jaccard index,
+    /// ANI and Mash distance from `num_common`, `query_hashes` and `target_hashes`
+    pub fn estimate_similarity(&mut self, kmer_size: u8) {
+        let num_common = self.num_common as f64;
+        let union = (self.query_hashes + self.target_hashes) as f64 - num_common;
+        let fraction = |size: usize| {
+            if size == 0 {
+                0.0
+            } else {
+                num_common / size as f64
+            }
+        };
+        self.query_containment = fraction(self.query_hashes) * 100.0;
+        self.target_containment = fraction(self.target_hashes) * 100.0;
+        self.jaccard = if union > 0.0 { num_common / union } else { 0.0 };
+
+        let kmer_size = kmer_size as f64;
+        // Containment ANI, see: https://doi.org/10.1101/2022.01.11.475870
+        let max_containment = self.query_containment.max(self.target_containment) / 100.0;
+        self.ani = max_containment.powf(1.0 / kmer_size);
+        // See: https://doi.org/10.1186/s13059-016-0997-x
+        let distance = -1.0 / kmer_size * (2.0 * self.jaccard / (1.0 + self.jaccard)).ln();
+        self.mash_distance = if self.jaccard == 0.0 {
+            1.0
+        } else if distance > 0.0 {
+            distance
         } else {
-            write!(
-                f,
-                "{}\t{}\t{}\t{}\t{:.2}",
-                self.from_name,
-                self.to_name,
-                self.num_common,
-                self.num_kmers,
-                self.estimated_containment,
-            )
+            0.0 // Identical sketches (avoids -0.0)
+        };
+
+        // Wilson score interval, the shared hashes are a binomial sample of the query
+        if self.query_hashes == 0 {
+            (self.containment_lower, self.containment_upper) = (0.0, 100.0);
+        } else {
+            let n = self.query_hashes as f64;
+            let p = num_common / n;
+            let z2 = WILSON_Z * WILSON_Z;
+            let center = (p + z2 / (2.0 * n)) / (1.0 + z2 / n);
+            let half_width =
+                WILSON_Z / (1.0 + z2 / n) * (p * (1.0 - p) / n + z2 / (4.0 * n * n)).sqrt();
+            self.containment_lower = (center - half_width).max(0.0) * 100.0;
+            self.containment_upper = (center + half_width).min(1.0) * 100.0;
         }
     }
+
+    /// P-value of `num_common` under a random match null model:
+    /// every query kmer matches one of the target kmers (target_hashes * fscale) by chance
+    /// with a probability that depends on the kmer size and alphabet
+    pub fn estimate_significance(
+        &mut self,
+        kmer_size: u8,
+        fscale: Option<u64>,
+        molecule: &Molecule,
+    ) {
+        let (alphabet, strands) = match molecule {
+            Molecule::Dna => (4.0f64, 2.0), // Canonical kmers match on both strands
+            Molecule::Protein => (20.0, 1.0),
+            Molecule::Dayhoff => (6.0, 1.0),
+            Molecule::Hp => (2.0, 1.0),
+        };
+        let kmer_match = (strands * alphabet.powi(-(kmer_size as i32))).min(1.0);
+        let target_kmers = self.target_hashes as f64 * fscale.unwrap_or(1) as f64;
+        let random_match = -(target_kmers * (-kmer_match).ln_1p()).exp_m1();
+        self.p_value = Some(if self.num_common == 0 {
+            1.0
+        } else {
+            match Binomial::new(random_match.clamp(0.0, 1.0), self.query_hashes as u64) {
+                Ok(binomial) => binomial.sf(self.num_common as u64 - 1),
+                Err(_) => 1.0,
+            }
+        });
+    }
+}
+
+/// Abundance weighted containment (in percent) and mean coverage of the shared hashes
+/// `common_abundance` is the summed query abundance of all shared hashes
+fn abundance_stats(
+    common_abundance: u64,
+    num_common: usize,
+    abundances: Option<&[u64]>,
+) -> (Option<f64>, Option<f64>) {
+    let Some(abundances) = abundances else {
+        return (None, None);
+    };
+    let total_abundance: u64 = abundances.iter().sum();
+    if total_abundance == 0 || num_common == 0 {
+        return (Some(0.0), Some(0.0));
+    }
+    (
+        Some(common_abundance as f64 / total_abundance as f64 * 100.0),
+        Some(common_abundance as f64 / num_common as f64),
+    )
+}
+
+/// Smaller of two (optional) max hashes, `None` is the full hash space
+fn common_max_hash(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Filters applied to the results of each query before they are collected
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ResultFilter {
+    pub top_n: Option<usize>, // Only keep the best n hits (by estimated containment)
+    pub max_pvalue: Option<f64>, // Results without p-value are always accepted
+    pub gc_lower: Option<f64>, // Minimum GC content (%) of the match
+    pub gc_upper: Option<f64>, // Maximum GC content (%) of the match
+}
+
+impl ResultFilter {
+    /// Matches without stats are rejected if a GC bound is set
+    fn accepts(&self, result: &CompareResult, target_stats: Option<&Stats>) -> bool {
+        if self.gc_lower.is_some() || self.gc_upper.is_some() {
+            let Some(gc) = target_stats.and_then(|stats| stats.gc_content()) else {
+                return false;
+            };
+            if self.gc_lower.is_some_and(|lower| gc < lower)
+                || self.gc_upper.is_some_and(|upper| gc > upper)
+            {
+                return false;
+            }
+        }
+        match (self.max_pvalue, result.p_value) {
+            (Some(max_pvalue), Some(p_value)) => p_value <= max_pvalue,
+            _ => true,
+        }
+    }
+}
+
+/// Orders results by estimated containment, number of shared hashes and (reversed) name
+struct RankedResult(CompareResult);
+
+impl Ord for RankedResult {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0
+            .estimated_containment
+            .total_cmp(&other.0.estimated_containment)
+            .then(self.0.num_common.cmp(&other.0.num_common))
+            .then(other.0.to_name.cmp(&self.0.to_name))
+    }
+}
+
+impl PartialOrd for RankedResult {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for RankedResult {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for RankedResult {}
+
+/// Bounded min-heap that keeps the best `limit` results of one query
+struct TopHits {
+    limit: Option<usize>,
+    heap: BinaryHeap<Reverse<RankedResult>>,
+}
+
+impl TopHits {
+    fn new(limit: Option<usize>) -> Self {
+        TopHits {
+            limit,
+            heap: BinaryHeap::new(),
+        }
+    }
+
+    fn push(&mut self, result: CompareResult) {
+        self.heap.push(Reverse(RankedResult(result)));
+        if self.limit.is_some_and(|limit| self.heap.len() > limit) {
+            self.heap.pop();
+        }
+    }
+
+    /// Best result first
+    fn into_sorted_vec(self) -> Vec<CompareResult> {
+        self.heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse(RankedResult(result))| result)
+            .collect()
+    }
 }
 
 pub struct MultiComp {
@@ -68,7 +305,10 @@ pub struct MultiComp {
     results: Vec<CompareResult>,
     threads: usize,
     kmer_size: u8,
+    fscale: Option<u64>,
+    molecule: Molecule,
     cutoff: f64,
+    filter: ResultFilter,
 }
 
 impl MultiComp {
@@ -82,6 +322,35 @@ impl MultiComp {
             .first()
             .ok_or_else(|| anyhow!("Empty from list"))?
             .kmer_size;
+        // Kmers selected by different schemes are not comparable
+        let sampling = to.first().map(|sig| sig.sampling).unwrap_or_default();
+        if let Some(sig) = from
+            .iter()
+            .chain(to.iter())
+            .find(|sig| sig.sampling != sampling)
+        {
+            return Err(anyhow!(
+                "Sampling scheme of {} does not match, expected: {:?}, got: {:?}",
+                sig.file_name,
+                sampling,
+                sig.sampling
+            ));
+        }
+        // Hashes of different seeds are independent
+        let seed = to.first().map_or(DEFAULT_SEED, |sig| sig.seed);
+        if let Some(sig) = from.iter().chain(to.iter()).find(|sig| sig.seed != seed) {
+            return Err(anyhow!(
+                "Hash seed of {} does not match, expected: {}, got: {}",
+                sig.file_name,
+                seed,
+                sig.seed
+            ));
+        }
+        // Settings of the database, used for the random match null model
+        let (fscale, molecule) = to
+            .first()
+            .map(|sig| (sig.fscale(), sig.molecule.clone()))
+            .unwrap_or_default();
 
         Ok(MultiComp {
             from: from.iter_mut().map(|e| e.collapse()).collect(),
@@ -89,10 +358,17 @@ impl MultiComp {
             results: Vec::new(),
             threads,
             kmer_size,
+            fscale,
+            molecule,
             cutoff,
+            filter: ResultFilter::default(),
         })
     }
 
+    pub fn set_filter(&mut self, filter: ResultFilter) {
+        self.filter = filter;
+    }
+
     pub fn compare(&mut self) -> Result<()> {
         let pool = rayon::ThreadPoolBuilder::new()
             .num_threads(self.threads)
@@ -102,6 +378,7 @@ impl MultiComp {
 
         pool.install(|| {
             self.from.par_iter().try_for_each(|origin| {
+                let top_hits = Mutex::new(TopHits::new(self.filter.top_n));
                 self.to.par_iter().try_for_each(|target| {
                     if target.kmer_size != self.kmer_size || origin.kmer_size != self.kmer_size {
                         return Err(anyhow!(
@@ -112,13 +389,22 @@ impl MultiComp {
                     }
                     let mut comparator = Comparator::new(origin, target);
                     comparator.compare()?;
-                    results
-                        .lock()
-                        .unwrap()
-                        .deref_mut()
-                        .push(comparator.finalize());
+                    let mut result = comparator.finalize();
+                    result.estimate_significance(self.kmer_size, self.fscale, &self.molecule);
+                    if result.num_common as f64 / result.num_kmers as f64 * 100.0 > self.cutoff
+                        && self.filter.accepts(&result, target.stats.as_ref())
+                    {
+                        top_hits.lock().unwrap().push(result);
+                    }
                     Ok::<(), anyhow::Error>(())
-                })
+                })?;
+                let top_hits = top_hits.into_inner().expect("poisoned lock");
+                results
+                    .lock()
+                    .unwrap()
+                    .deref_mut()
+                    .extend(top_hits.into_sorted_vec());
+                Ok::<(), anyhow::Error>(())
             })
         })?;
 
@@ -128,9 +414,97 @@ impl MultiComp {
 
     pub fn finalize(self) -> Vec<CompareResult> {
         self.results
-            .into_iter()
-            .filter(|e| e.num_common as f64 / e.num_kmers as f64 * 100.0 > self.cutoff)
-            .collect()
+    }
+}
+
+/// Symmetric all-vs-all similarity matrix of multiple signatures
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimilarityMatrix {
+    pub names: Vec<String>,
+    pub values: Vec<f64>, // Row-major, names.len() x names.len()
+}
+
+impl SimilarityMatrix {
+    /// Every signature is collapsed into one sketch, only the upper triangle is compared
+    pub fn new(
+        mut signatures: Vec<Signature>,
+        metric: &MatrixMetric,
+        threads: usize,
+    ) -> Result<Self> {
+        let sketches = signatures
+            .iter_mut()
+            .map(|s| s.collapse())
+            .collect::<Vec<_>>();
+        SimilarityMatrix::from_sketches(&sketches.iter().collect::<Vec<_>>(), metric, threads)
+    }
+
+    pub(crate) fn from_sketches(
+        sketches: &[&Sketch],
+        metric: &MatrixMetric,
+        threads: usize,
+    ) -> Result<Self> {
+        if let Some(first) = sketches.first() {
+            if let Some(other) = sketches.iter().find(|s| s.kmer_size != first.kmer_size) {
+                return Err(anyhow!(
+                    "Kmer sizes do not match, expected: {}, got: {}",
+                    first.kmer_size,
+                    other.kmer_size
+                ));
+            }
+        }
+        let size = sketches.len();
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()?;
+        let upper = pool.install(|| {
+            (0..size)
+                .into_par_iter()
+                .flat_map(|row| (row + 1..size).into_par_iter().map(move |col| (row, col)))
+                .map(|(row, col)| {
+                    let mut comparator = Comparator::new(sketches[row], sketches[col]);
+                    comparator.compare()?;
+                    let result = comparator.finalize();
+                    let value = match metric {
+                        MatrixMetric::Jaccard => result.jaccard,
+                        MatrixMetric::Containment => {
+                            result.query_containment.max(result.target_containment) / 100.0
+                        }
+                        MatrixMetric::Ani => result.ani,
+                    };
+                    Ok((row, col, value))
+                })
+                .collect::<Result<Vec<_>>>()
+        })?;
+
+        let mut values = vec![0.0; size * size];
+        for idx in 0..size {
+            values[idx * size + idx] = 1.0;
+        }
+        for (row, col, value) in upper {
+            values[row * size + col] = value;
+            values[col * size + row] = value;
+        }
+        Ok(SimilarityMatrix {
+            names: sketches.iter().map(|s| s.name.clone()).collect(),
+            values,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    pub fn get(&self, row: usize, col: usize) -> f64 {
+        self.values[row * self.len() + col]
+    }
+
+    pub fn row(&self, row: usize) -> &[f64] {
+        &self.values[row * self.len()..(row + 1) * self.len()]
     }
 }
 
@@ -139,7 +513,10 @@ pub struct Comparator<'a> {
     smaller: &'a Sketch,
     num_kmers: usize,
     num_common: usize,
-    num_skipped: usize,
+    num_skipped: usize, // Hashes outside of the common sampled hash space
+    larger_hashes: usize,
+    smaller_hashes: usize,
+    common_abundance: u64,
     reverse: bool,
 }
 
@@ -158,44 +535,79 @@ impl<'a> Comparator<'a> {
             num_kmers: 0,
             num_common: 0,
             num_skipped: 0,
+            larger_hashes: larger.hashes.len(),
+            smaller_hashes: smaller.hashes.len(),
+            common_abundance: 0,
             reverse,
         }
     }
 
-    // Stats handling:
-    // GC & Size for the original contig are stored in the Stats struct
-    // This comparison is always in relation to the query sketch
-    // If reverse is true, the query sketch is the larger sketch
+    /// Upper bound of the hash space sampled by both sketches,
+    /// capped / extended sketches (nmin, nmax, kscale) only contain the hashes below their max hash
+    fn max_hash(&self) -> Option<u64> {
+        common_max_hash(self.larger.max_hash, self.smaller.max_hash)
+    }
+
+    /// The sketch that was passed first (sketch_a)
+    fn query(&self) -> &'a Sketch {
+        if self.reverse {
+            self.smaller
+        } else {
+            self.larger
+        }
+    }
+
+    // Record stats (length, GC) are not part of the comparison,
+    // GC bounds are applied to the results (see `ResultFilter`)
+    // If reverse is true, the query sketch is the smaller sketch
     #[inline]
     pub fn compare(&mut self) -> Result<()> {
-        self.num_kmers = max(self.larger.num_kmers, self.smaller.num_kmers);
+        // Hashes above the common max hash are only sampled by one of the sketches
+        if let Some(max_hash) = self.max_hash() {
+            self.larger_hashes = self.larger.hashes.range(..max_hash).count();
+            self.smaller_hashes = self.smaller.hashes.range(..max_hash).count();
+        }
+        self.num_skipped = self.larger.hashes.len() + self.smaller.hashes.len()
+            - self.larger_hashes
+            - self.smaller_hashes;
+        self.num_kmers = if self.num_skipped == 0 {
+            max(self.larger.num_kmers, self.smaller.num_kmers)
+        } else {
+            max(self.larger_hashes, self.smaller_hashes)
+        };
+
+        let query_abundances = self.query().abundances.as_ref();
 
-        let mut larger = self.larger.hashes.iter();
-        let mut smaller = self.smaller.hashes.iter();
+        let mut larger = self
+            .larger
+            .hashes
+            .iter()
+            .take(self.larger_hashes)
+            .enumerate();
+        let mut smaller = self
+            .smaller
+            .hashes
+            .iter()
+            .take(self.smaller_hashes)
+            .enumerate();
 
         let mut larger_item = larger.next();
         let mut smaller_item = smaller.next();
 
-        loop {
-            match (larger_item, smaller_item) {
-                (Some(l), Some(s)) => {
-                    if l == s {
-                        self.num_common += 1;
-                        smaller_item = smaller.next();
-                        larger_item = larger.next();
-                    } else if l < s {
-                        smaller_item = smaller.next();
-                    } else {
-                        larger_item = larger.next();
-                    }
-                }
-                (Some(_), None) => {
-                    larger_item = larger.next();
+        // Once one side is exhausted no further matches are possible
+        while let (Some((l_idx, l)), Some((s_idx, s))) = (larger_item, smaller_item) {
+            if l == s {
+                self.num_common += 1;
+                if let Some(abundances) = query_abundances {
+                    let idx = if self.reverse { s_idx } else { l_idx };
+                    self.common_abundance += abundances.get(idx).copied().unwrap_or(1);
                 }
-                (None, Some(_)) => {
-                    smaller_item = smaller.next();
-                }
-                (None, None) => break,
+                smaller_item = smaller.next();
+                larger_item = larger.next();
+            } else if l < s {
+                larger_item = larger.next();
+            } else {
+                smaller_item = smaller.next();
             }
         }
 
@@ -215,15 +627,48 @@ impl<'a> Comparator<'a> {
         };
         let estimated_containment =
             self.num_common as f64 / self.num_kmers as f64 * fraction * 100.0;
-
-        CompareResult {
-            from_name: self.larger.name.clone(),
-            to_name: self.smaller.name.clone(),
+        let ((query, query_hashes), (target, target_hashes)) = if self.reverse {
+            (
+                (self.smaller, self.smaller_hashes),
+                (self.larger, self.larger_hashes),
+            )
+        } else {
+            (
+                (self.larger, self.larger_hashes),
+                (self.smaller, self.smaller_hashes),
+            )
+        };
+        // Only the abundances of hashes in the common hash space
+        let (abundance_containment, mean_coverage) = abundance_stats(
+            self.common_abundance,
+            self.num_common,
+            query
+                .abundances
+                .as_ref()
+                .map(|a| &a[..query_hashes.min(a.len())]),
+        );
+        let mut result = CompareResult {
+            from_name: query.name.clone(),
+            to_name: target.name.clone(),
             num_kmers: self.num_kmers,
             num_common: self.num_common,
             reverse: self.reverse,
             estimated_containment,
-        }
+            query_hashes,
+            target_hashes,
+            query_containment: 0.0,
+            target_containment: 0.0,
+            jaccard: 0.0,
+            ani: 0.0,
+            mash_distance: 0.0,
+            containment_lower: 0.0,
+            containment_upper: 0.0,
+            p_value: None,
+            abundance_containment,
+            mean_coverage,
+        };
+        result.estimate_similarity(query.kmer_size);
+        result
     }
 
     #[allow(dead_code)]
@@ -231,6 +676,9 @@ impl<'a> Comparator<'a> {
         self.num_kmers = 0;
         self.num_common = 0;
         self.num_skipped = 0;
+        self.larger_hashes = self.larger.hashes.len();
+        self.smaller_hashes = self.smaller.hashes.len();
+        self.common_abundance = 0;
     }
 }
 
@@ -242,7 +690,12 @@ pub struct LmdbComparator {
     pub infos: Arc<RwLock<HashMap<u32, ShortSketchInfo>>>,
     pub kmer_size: u8,
     pub fscale: Option<u64>,
+    pub algorithm: HashAlgorithms,
+    pub molecule: Molecule,
+    pub sampling: SamplingScheme,
+    pub seed: u64,
     pub silent: bool,
+    pub filter: ResultFilter,
 }
 
 impl LmdbComparator {
@@ -251,21 +704,26 @@ impl LmdbComparator {
             heed::EnvOpenOptions::new()
                 .flags(EnvFlags::READ_ONLY | EnvFlags::NO_LOCK | EnvFlags::NO_SUB_DIR)
                 .map_size(10 * 1024 * 1024 * 1024)
-                .max_dbs(2)
-                .open(lmdb_env)
-                .unwrap()
+                .max_dbs(4)
+                .open(lmdb_env)?
         };
 
         let txn = lmdb_env.read_txn()?;
 
+        let meta =
+            match lmdb_env.open_database::<Str, SerdeJson<DatabaseMeta>>(&txn, Some("meta"))? {
+                Some(meta_db) => meta_db.get(&txn, META_KEY)?,
+                None => None,
+            };
+
         let sigs_db = lmdb_env
-            .open_database::<U32<BigEndian>, SerdeBincode<ShortSketchInfo>>(&txn, Some("sigs"))?
+            .open_database::<U32<BigEndian>, ShortSketchInfoCodec>(&txn, Some("sigs"))?
             .ok_or_else(|| anyhow!("Database sigs not found"))?;
 
         let infos = RwLock::new(HashMap::new());
 
-        let mut kmer_size = None;
-        let mut fscale = None;
+        let mut kmer_size = meta.as_ref().map(|m| m.kmer_size);
+        let mut fscale = meta.as_ref().and_then(|m| m.fscale);
         for sig in sigs_db.iter(&txn)? {
             let (key, value) = sig?;
             if let Some(kmer_size) = kmer_size {
@@ -289,15 +747,30 @@ impl LmdbComparator {
 
         txn.commit()?;
 
+        let kmer_size = kmer_size.ok_or_else(|| anyhow!("Database is empty"))?;
+        match meta.as_ref() {
+            Some(meta) => meta.check_kmer_encoding()?,
+            None => DatabaseMeta::legacy(kmer_size, fscale).check_kmer_encoding()?,
+        }
+
         Ok(LmdbComparator {
             signatures: vec![],
             lmdb_env,
             threads,
             cutoff,
             infos: Arc::new(infos),
-            kmer_size: kmer_size.unwrap(),
+            kmer_size,
             fscale,
+            // Databases without header were sketched with the default algorithm
+            molecule: meta
+                .as_ref()
+                .map(|m| m.molecule.clone())
+                .unwrap_or_default(),
+            sampling: meta.as_ref().map(|m| m.sampling).unwrap_or_default(),
+            seed: meta.as_ref().map_or(DEFAULT_SEED, |m| m.seed),
+            algorithm: meta.map(|m| m.algorithm).unwrap_or(HashAlgorithms::Default),
             silent,
+            filter: ResultFilter::default(),
         })
     }
 
@@ -305,23 +778,79 @@ impl LmdbComparator {
         self.signatures = signatures;
     }
 
+    pub fn set_filter(&mut self, filter: ResultFilter) {
+        self.filter = filter;
+    }
+
+    /// Inverted index of the database: hash -> ids of all sketches containing the hash
+    pub(crate) fn hashes_db(
+        &self,
+        txn: &heed::RoTxn,
+    ) -> Result<heed::Database<U64<BigEndian>, U32<BigEndian>>> {
+        self.lmdb_env
+            .database_options()
+            .types::<U64<BigEndian>, U32<BigEndian>>()
+            .name("hashes")
+            .flags(DatabaseFlags::DUP_SORT)
+            .open(txn)?
+            .ok_or_else(|| anyhow!("Database hashes not found"))
+    }
+
+    /// Checks that all signatures were sketched with the sampling scheme and seed of the database
+    pub(crate) fn check_signatures(&self) -> Result<()> {
+        // Kmers selected by different schemes are not comparable
+        if let Some(sig) = self
+            .signatures
+            .iter()
+            .find(|sig| sig.sampling != self.sampling)
+        {
+            return Err(anyhow!(
+                "Sampling scheme of {} does not match the database, expected: {:?}, got: {:?}",
+                sig.file_name,
+                self.sampling,
+                sig.sampling
+            ));
+        }
+        if let Some(sig) = self.signatures.iter().find(|sig| sig.seed != self.seed) {
+            return Err(anyhow!(
+                "Hash seed of {} does not match the database, expected: {}, got: {}",
+                sig.file_name,
+                self.seed,
+                sig.seed
+            ));
+        }
+        Ok(())
+    }
+
     pub fn compare(&self) -> Result<Vec<CompareResult>> {
+        self.check_signatures()?;
         let pool = rayon::ThreadPoolBuilder::new()
             .num_threads(self.threads)
             .build()?;
 
         let results = Mutex::new(Vec::new());
 
-            let pb = ProgressBar::new(self.signatures.len() as u64);
-            pb.set_style(
-                indicatif::ProgressStyle::default_bar()
-                    .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}")?
-                    .progress_chars("##-"),
-            );
+        let pb = ProgressBar::new(self.signatures.len() as u64);
+        pb.set_style(
+            indicatif::ProgressStyle::default_bar()
+                .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}")?
+                .progress_chars("##-"),
+        );
         if self.silent {
             pb.set_draw_target(ProgressDrawTarget::hidden())
         }
         let infos = self.infos.clone();
+        // Sketches without own max hash contain all hashes below the max hash of the database
+        let database_max_hash =
+            Some(fscale_to_max_hash(self.fscale)).filter(|max_hash| *max_hash < u64::MAX);
+        // Matches below the smallest max hash are always within the sampled hash space
+        let min_max_hash = infos
+            .read()
+            .expect("poisoned lock")
+            .values()
+            .filter_map(|info| info.max_hash)
+            .min()
+            .unwrap_or(u64::MAX);
 
         pool.install(|| {
             self.signatures
@@ -331,60 +860,279 @@ impl LmdbComparator {
                     origin.sketches.par_iter().try_for_each(|target| {
                         let txn = self.lmdb_env.read_txn()?;
 
-                        let hashes = self
-                            .lmdb_env
-                            .database_options()
-                            .types::<U64<BigEndian>, U32<BigEndian>>()
-                            .name("hashes")
-                            .flags(DatabaseFlags::DUP_SORT)
-                            .open(&txn)?
-                            .ok_or_else(|| anyhow!("Database hashes not found"))?;
+                        let hashes = self.hashes_db(&txn)?;
                         let mut result_map = HashMap::new();
+                        let read_infos = infos.read().expect("poisoned lock");
 
-                        for hash in target.hashes.iter() {
+                        for (hash_idx, hash) in target.hashes.iter().enumerate() {
+                            if target.max_hash.is_some_and(|max_hash| *hash >= max_hash) {
+                                break;
+                            }
+                            let abundance = target
+                                .abundances
+                                .as_ref()
+                                .and_then(|a| a.get(hash_idx).copied())
+                                .unwrap_or(1);
                             if let Some(key) = hashes.get_duplicates(&txn, hash)? {
                                 for item in key {
                                     let (_, sketch) = item?;
-                                    let entry = result_map.entry(sketch).or_insert(0);
-                                    *entry += 1u64;
+                                    // The hash is above the sampled hash space of a capped sketch
+                                    if *hash >= min_max_hash
+                                        && read_infos
+                                            .get(&sketch)
+                                            .and_then(|info| info.max_hash)
+                                            .is_some_and(|max_hash| *hash >= max_hash)
+                                    {
+                                        continue;
+                                    }
+                                    let entry = result_map.entry(sketch).or_insert((0, 0));
+                                    entry.0 += 1u64;
+                                    entry.1 += abundance;
                                 }
                             };
                         }
 
-                        let mut final_results = vec![];
-                        for (idx, num_common) in result_map {
-                            let read_infos = infos.read().expect("poisoned lock");
+                        // Only the best hits are kept, independent of the number of matches
+                        let mut final_results = TopHits::new(self.filter.top_n);
+                        for (idx, (num_common, common_abundance)) in result_map {
                             let infos = read_infos.get(&idx).expect("Key not found");
-                            let num_kmers = if target.hashes.len() < infos.num_hashes {
-                                target.hashes.len()
-                            } else {
-                                infos.num_hashes
+                            // Both sketches are compared within the common sampled hash space
+                            let info_max_hash = infos.max_hash.or(database_max_hash);
+                            let max_hash = common_max_hash(target.max_hash, info_max_hash);
+                            let (query_hashes, target_hashes) = match max_hash {
+                                Some(max_hash) => (
+                                    target.hashes.range(..max_hash).count(),
+                                    // Hashes are uniformly distributed within the sampled space
+                                    match info_max_hash {
+                                        Some(info_max_hash) if max_hash < info_max_hash => {
+                                            (infos.num_hashes as f64 * max_hash as f64
+                                                / info_max_hash as f64)
+                                                .round()
+                                                as usize
+                                        }
+                                        _ => infos.num_hashes,
+                                    },
+                                ),
+                                None => (target.hashes.len(), infos.num_hashes),
                             };
+                            let num_kmers = query_hashes.min(target_hashes);
                             let estimated_containment =
                                 num_common as f64 / num_kmers as f64 * 100.0;
-                            final_results.push(CompareResult {
+                            let (abundance_containment, mean_coverage) = abundance_stats(
+                                common_abundance,
+                                num_common as usize,
+                                target
+                                    .abundances
+                                    .as_ref()
+                                    .map(|a| &a[..query_hashes.min(a.len())]),
+                            );
+                            let mut result = CompareResult {
                                 from_name: target.name.clone(),
                                 to_name: infos.file_name.clone(),
                                 num_kmers,
                                 num_common: num_common as usize,
                                 reverse: false,
                                 estimated_containment,
-                            })
+                                query_hashes,
+                                target_hashes,
+                                query_containment: 0.0,
+                                target_containment: 0.0,
+                                jaccard: 0.0,
+                                ani: 0.0,
+                                mash_distance: 0.0,
+                                containment_lower: 0.0,
+                                containment_upper: 0.0,
+                                p_value: None,
+                                abundance_containment,
+                                mean_coverage,
+                            };
+                            result.estimate_similarity(infos.kmer_size);
+                            result.estimate_significance(
+                                infos.kmer_size,
+                                self.fscale,
+                                &self.molecule,
+                            );
+                            if result.num_common as f64 / result.num_kmers as f64 * 100.0
+                                > self.cutoff
+                                && self.filter.accepts(&result, infos.stats.as_ref())
+                            {
+                                final_results.push(result);
+                            }
                         }
 
                         results
                             .lock()
                             .unwrap()
-                            .extend(final_results.into_iter().filter(|e| {
-                                e.num_common as f64 / e.num_kmers as f64 * 100.0 > self.cutoff
-                            }));
+                            .extend(final_results.into_sorted_vec());
+
+                        Ok::<(), anyhow::Error>(())
+                    })
+                })
+        })?;
+        Ok(results.into_inner().expect("poisoned lock"))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct GatherResult {
+    pub query_name: String,
+    pub match_name: String,
+    pub rank: usize,                      // Order in which the match was selected
+    pub unique_overlap: usize,            // Shared hashes not explained by previous matches
+    pub total_overlap: usize,             // All shared hashes with the query
+    pub query_hashes: usize,              // Number of hashes in the query sketch
+    pub match_hashes: usize,              // Number of hashes in the match sketch
+    pub f_unique_to_query: f64,           // unique_overlap / query_hashes (in percent)
+    pub f_match: f64,                     // total_overlap / match_hashes (in percent)
+    pub f_query_explained: f64,           // Cumulative fraction of the query explained (in percent)
+    pub estimated_abundance: Option<f64>, // Mean query abundance of the unique hashes
+}
 
+impl Display for GatherResult {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:.2}\t{:.2}\t{:.2}",
+            self.query_name,
+            self.match_name,
+            self.rank,
+            self.unique_overlap,
+            self.total_overlap,
+            self.query_hashes,
+            self.match_hashes,
+            self.f_unique_to_query,
+            self.f_match,
+            self.f_query_explained,
+        )?;
+        match self.estimated_abundance {
+            Some(estimated_abundance) => write!(f, "\t{:.2}", estimated_abundance),
+            None => write!(f, "\tNA"),
+        }
+    }
+}
+
+impl TsvHeader for GatherResult {
+    fn tsv_header() -> &'static str {
+        "query_name\tmatch_name\trank\tunique_overlap\ttotal_overlap\tquery_hashes\tmatch_hashes\tf_unique_to_query\tf_match\tf_query_explained\testimated_abundance"
+    }
+}
+
+/// Minimum metagenome cover (similar to sourmash gather):
+/// Iteratively selects the database sketch with the largest overlap with the remaining
+/// query hashes and removes these hashes from the query
+pub struct Gather<'a> {
+    comparator: &'a LmdbComparator,
+    min_overlap: usize,
+}
+
+impl<'a> Gather<'a> {
+    /// Uses the signatures and database of the comparator,
+    /// matches with less than `min_overlap` unique hashes are not reported
+    pub fn new(comparator: &'a LmdbComparator, min_overlap: usize) -> Self {
+        Gather {
+            comparator,
+            min_overlap: min_overlap.max(1),
+        }
+    }
+
+    pub fn gather(&self) -> Result<Vec<GatherResult>> {
+        self.comparator.check_signatures()?;
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.comparator.threads)
+            .build()?;
+
+        let results = Mutex::new(Vec::new());
+        pool.install(|| {
+            self.comparator
+                .signatures
+                .par_iter()
+                .try_for_each(|origin| {
+                    origin.sketches.par_iter().try_for_each(|query| {
+                        let query_results = self.gather_sketch(query)?;
+                        results.lock().unwrap().extend(query_results);
                         Ok::<(), anyhow::Error>(())
                     })
                 })
         })?;
         Ok(results.into_inner().expect("poisoned lock"))
     }
+
+    fn gather_sketch(&self, query: &Sketch) -> Result<Vec<GatherResult>> {
+        let txn = self.comparator.lmdb_env.read_txn()?;
+        let hashes = self.comparator.hashes_db(&txn)?;
+
+        let query_abundances = query.abundance_map();
+        // Database sketches for every query hash and the remaining overlap per sketch
+        let mut hash_to_sketches: HashMap<u64, Vec<u32>> = HashMap::new();
+        let mut total_overlaps: HashMap<u32, usize> = HashMap::new();
+        for hash in query.hashes.iter() {
+            if let Some(key) = hashes.get_duplicates(&txn, hash)? {
+                for item in key {
+                    let (_, sketch) = item?;
+                    hash_to_sketches.entry(*hash).or_default().push(sketch);
+                    *total_overlaps.entry(sketch).or_insert(0) += 1;
+                }
+            }
+        }
+        let mut remaining_overlaps = total_overlaps.clone();
+        let mut sketch_to_hashes: HashMap<u32, Vec<u64>> = HashMap::new();
+        for (hash, sketches) in hash_to_sketches.iter() {
+            for sketch in sketches {
+                sketch_to_hashes.entry(*sketch).or_default().push(*hash);
+            }
+        }
+
+        let infos = self.comparator.infos.read().expect("poisoned lock");
+        let query_hashes = query.hashes.len();
+        let mut explained = 0;
+        let mut results = Vec::new();
+        // Largest remaining overlap, ties are resolved by the lower database id
+        while let Some((&best, &unique_overlap)) = remaining_overlaps
+            .iter()
+            .max_by(|(a_idx, a), (b_idx, b)| a.cmp(b).then(b_idx.cmp(a_idx)))
+        {
+            if unique_overlap < self.min_overlap {
+                break;
+            }
+
+            // Remove the explained hashes from all other candidates
+            let mut abundance_sum = 0;
+            for hash in sketch_to_hashes.get(&best).into_iter().flatten() {
+                if let Some(sketches) = hash_to_sketches.remove(hash) {
+                    abundance_sum += query_abundances.get(hash).copied().unwrap_or(1);
+                    for sketch in sketches {
+                        if let Some(overlap) = remaining_overlaps.get_mut(&sketch) {
+                            *overlap -= 1;
+                        }
+                    }
+                }
+            }
+            remaining_overlaps.remove(&best);
+            explained += unique_overlap;
+
+            let info = infos
+                .get(&best)
+                .ok_or_else(|| anyhow!("Unknown database sketch {}", best))?;
+            let total_overlap = total_overlaps.get(&best).copied().unwrap_or_default();
+            results.push(GatherResult {
+                query_name: query.name.clone(),
+                match_name: info.file_name.clone(),
+                rank: results.len(),
+                unique_overlap,
+                total_overlap,
+                query_hashes,
+                match_hashes: info.num_hashes,
+                f_unique_to_query: unique_overlap as f64 / query_hashes as f64 * 100.0,
+                f_match: total_overlap as f64 / info.num_hashes.max(1) as f64 * 100.0,
+                f_query_explained: explained as f64 / query_hashes as f64 * 100.0,
+                estimated_abundance: query
+                    .abundances
+                    .as_ref()
+                    .map(|_| abundance_sum as f64 / unique_overlap as f64),
+            });
+        }
+        Ok(results)
+    }
 }
 
 #[cfg(test)]
@@ -402,6 +1150,9 @@ mod tests {
             hashes: bheap1,
             num_kmers: 3,
             kmer_size: 21,
+            abundances: None,
+            stats: None,
+            max_hash: None,
         };
         let mut bheap2 = BTreeSet::default();
         bheap2.extend([1, 2, 4]);
@@ -410,6 +1161,9 @@ mod tests {
             hashes: bheap2,
             num_kmers: 3,
             kmer_size: 21,
+            abundances: None,
+            stats: None,
+            max_hash: None,
         };
 
         let mut comp = super::Comparator::new(&sketch_a, &sketch_b);
@@ -426,7 +1180,289 @@ mod tests {
             num_common: 2,
             reverse: false,
             estimated_containment: 66.66666666666666,
+            query_hashes: 3,
+            target_hashes: 3,
+            query_containment: 66.66666666666666,
+            target_containment: 66.66666666666666,
+            jaccard: 0.5,
+            ani: result.ani,
+            mash_distance: result.mash_distance,
+            containment_lower: result.containment_lower,
+            containment_upper: result.containment_upper,
+            p_value: None,
+            abundance_containment: None,
+            mean_coverage: None,
         };
         assert_eq!(result, constructed_result);
     }
+
+    #[test]
+    fn test_similarity_estimates() {
+        let mut result = CompareResult {
+            from_name: "a".to_string(),
+            to_name: "b".to_string(),
+            num_kmers: 100,
+            num_common: 50,
+            reverse: false,
+            estimated_containment: 50.0,
+            query_hashes: 100,
+            target_hashes: 50,
+            query_containment: 0.0,
+            target_containment: 0.0,
+            jaccard: 0.0,
+            ani: 0.0,
+            mash_distance: 0.0,
+            containment_lower: 0.0,
+            containment_upper: 0.0,
+            p_value: None,
+            abundance_containment: None,
+            mean_coverage: None,
+        };
+        result.estimate_similarity(21);
+        assert_eq!(result.query_containment, 50.0);
+        assert_eq!(result.target_containment, 100.0);
+        assert_eq!(result.jaccard, 0.5);
+        assert_eq!(result.ani, 1.0);
+        assert!(result.containment_lower < 50.0 && result.containment_lower > 39.0);
+        assert!(result.containment_upper > 50.0 && result.containment_upper < 61.0);
+
+        // 50 shared hashes are very unlikely to be random matches
+        result.estimate_significance(21, Some(1000), &crate::cli::Molecule::Dna);
+        assert!(result.p_value.unwrap() < 1e-100);
+        // For kmer size 3 almost every kmer matches randomly
+        result.estimate_significance(3, Some(1000), &crate::cli::Molecule::Dna);
+        assert!(result.p_value.unwrap() > 0.99);
+        let expected_distance = -1.0 / 21.0 * (2.0f64 / 3.0).ln();
+        assert!((result.mash_distance - expected_distance).abs() < 1e-12);
+
+        // Nothing in common
+        result.num_common = 0;
+        result.estimate_similarity(21);
+        assert_eq!(result.jaccard, 0.0);
+        assert_eq!(result.ani, 0.0);
+        assert_eq!(result.mash_distance, 1.0);
+        assert!(result.containment_lower < 1e-9);
+        result.estimate_significance(21, Some(1000), &crate::cli::Molecule::Dna);
+        assert_eq!(result.p_value, Some(1.0));
+    }
+
+    #[test]
+    fn test_comp_interleaved() {
+        let sketch_a = crate::sketch::Sketch {
+            name: "a".to_string(),
+            hashes: BTreeSet::from([1, 5, 9]),
+            num_kmers: 3,
+            kmer_size: 21,
+            abundances: None,
+            stats: None,
+            max_hash: None,
+        };
+        let sketch_b = crate::sketch::Sketch {
+            name: "b".to_string(),
+            hashes: BTreeSet::from([2, 5, 9]),
+            num_kmers: 3,
+            kmer_size: 21,
+            abundances: None,
+            stats: None,
+            max_hash: None,
+        };
+        let mut comp = super::Comparator::new(&sketch_a, &sketch_b);
+        comp.compare().unwrap();
+        assert_eq!(comp.finalize().num_common, 2);
+    }
+
+    #[test]
+    fn test_comp_with_abundance() {
+        let sketch_a = crate::sketch::Sketch {
+            name: "a".to_string(),
+            hashes: BTreeSet::from([1, 2, 3]),
+            num_kmers: 3,
+            kmer_size: 21,
+            abundances: Some(vec![4, 2, 2]),
+            stats: None,
+            max_hash: None,
+        };
+        let sketch_b = crate::sketch::Sketch {
+            name: "b".to_string(),
+            hashes: BTreeSet::from([1, 2, 4]),
+            num_kmers: 3,
+            kmer_size: 21,
+            abundances: None,
+            stats: None,
+            max_hash: None,
+        };
+        let mut comp = super::Comparator::new(&sketch_a, &sketch_b);
+        comp.compare().unwrap();
+        let result = comp.finalize();
+        assert_eq!(result.num_common, 2);
+        assert_eq!(result.abundance_containment, Some(75.0));
+        assert_eq!(result.mean_coverage, Some(3.0));
+    }
+
+    #[test]
+    fn test_comp_capped_sketches() {
+        let sketch = |name: &str, hashes: std::ops::Range<u64>, max_hash| crate::sketch::Sketch {
+            name: name.to_string(),
+            num_kmers: hashes.clone().count(),
+            hashes: hashes.collect(),
+            kmer_size: 21,
+            abundances: None,
+            stats: None,
+            max_hash,
+        };
+        // b only contains the hashes below 10, all hashes above are unknown
+        let sketch_a = sketch("a", 0..100, None);
+        let sketch_b = sketch("b", 0..10, Some(10));
+
+        let mut comp = super::Comparator::new(&sketch_a, &sketch_b);
+        comp.compare().unwrap();
+        let result = comp.finalize();
+        assert_eq!(result.num_common, 10);
+        assert_eq!(result.num_kmers, 10);
+        assert_eq!(result.query_hashes, 10);
+        assert_eq!(result.target_hashes, 10);
+        assert_eq!(result.estimated_containment, 100.0);
+        assert_eq!(result.jaccard, 1.0);
+
+        // Without the max hash only 10% of a would be contained in b
+        let sketch_b = sketch("b", 0..10, None);
+        let mut comp = super::Comparator::new(&sketch_a, &sketch_b);
+        comp.compare().unwrap();
+        let result = comp.finalize();
+        assert_eq!(result.query_hashes, 100);
+        assert_eq!(result.jaccard, 0.1);
+    }
+
+    #[test]
+    fn test_similarity_matrix() {
+        let signature = |name: &str, hashes: &[u64]| crate::signature::Signature {
+            file_name: name.to_string(),
+            sketches: vec![crate::sketch::Sketch {
+                name: name.to_string(),
+                hashes: hashes.iter().copied().collect(),
+                num_kmers: hashes.len(),
+                kmer_size: 21,
+                abundances: None,
+                stats: None,
+                max_hash: None,
+            }],
+            algorithm: crate::cli::HashAlgorithms::Default,
+            kmer_size: 21,
+            max_hash: u64::MAX,
+            molecule: crate::cli::Molecule::Dna,
+            scaling: Default::default(),
+            sampling: Default::default(),
+            seed: crate::hash_functions::DEFAULT_SEED,
+        };
+        let signatures = vec![
+            signature("a", &[1, 2, 3, 4]),
+            signature("b", &[1, 2]),
+            signature("c", &[5, 6]),
+        ];
+
+        let matrix =
+            super::SimilarityMatrix::new(signatures.clone(), &crate::cli::MatrixMetric::Jaccard, 2)
+                .unwrap();
+        assert_eq!(matrix.names, vec!["a", "b", "c"]);
+        assert_eq!(matrix.row(0), &[1.0, 0.5, 0.0]);
+        assert_eq!(matrix.row(1), &[0.5, 1.0, 0.0]);
+        assert_eq!(matrix.get(2, 2), 1.0);
+
+        // b is fully contained in a
+        let matrix =
+            super::SimilarityMatrix::new(signatures, &crate::cli::MatrixMetric::Containment, 1)
+                .unwrap();
+        assert_eq!(matrix.get(0, 1), 1.0);
+        assert_eq!(matrix.get(1, 0), 1.0);
+        assert_eq!(matrix.get(0, 2), 0.0);
+    }
+
+    #[test]
+    fn test_result_names_reverse() {
+        let sketch = |name: &str, hashes: std::ops::Range<u64>| crate::sketch::Sketch {
+            name: name.to_string(),
+            num_kmers: hashes.clone().count(),
+            hashes: hashes.collect(),
+            kmer_size: 21,
+            abundances: None,
+            stats: None,
+            max_hash: None,
+        };
+        let query = sketch("query", 0..10);
+        let target = sketch("target", 0..100);
+        // The query is the smaller sketch
+        let mut comp = super::Comparator::new(&query, &target);
+        comp.compare().unwrap();
+        let result = comp.finalize();
+        assert!(result.reverse);
+        assert_eq!(result.from_name, "query");
+        assert_eq!(result.to_name, "target");
+        assert_eq!(result.query_containment, 100.0);
+        assert!(result.to_string().starts_with("query\ttarget\t"));
+
+        let json = serde_json::to_value(&result).unwrap();
+        assert_eq!(json["query"], "query");
+        assert_eq!(json["match"], "target");
+        assert!(json.get("reverse").is_none());
+    }
+
+    #[test]
+    fn test_top_hits() {
+        let result = |name: &str, containment: f64, num_common: usize| CompareResult {
+            from_name: "query".to_string(),
+            to_name: name.to_string(),
+            num_common,
+            num_kmers: 100,
+            reverse: false,
+            estimated_containment: containment,
+            query_hashes: 100,
+            target_hashes: 100,
+            query_containment: containment,
+            target_containment: containment,
+            jaccard: 0.0,
+            ani: 0.0,
+            mash_distance: 0.0,
+            containment_lower: 0.0,
+            containment_upper: 0.0,
+            p_value: Some(containment / 100.0),
+            abundance_containment: None,
+            mean_coverage: None,
+        };
+        let mut top_hits = super::TopHits::new(Some(2));
+        for (name, containment) in [("a", 10.0), ("b", 50.0), ("c", 30.0), ("d", 50.0)] {
+            top_hits.push(result(name, containment, containment as usize));
+        }
+        let names = top_hits
+            .into_sorted_vec()
+            .into_iter()
+            .map(|r| r.to_name)
+            .collect::<Vec<_>>();
+        // Ties are resolved by the name
+        assert_eq!(names, vec!["b", "d"]);
+
+        let mut top_hits = super::TopHits::new(None);
+        for (name, containment) in [("a", 10.0), ("b", 50.0), ("c", 30.0)] {
+            top_hits.push(result(name, containment, containment as usize));
+        }
+        assert_eq!(top_hits.into_sorted_vec().len(), 3);
+
+        let filter = super::ResultFilter {
+            max_pvalue: Some(0.2),
+            ..Default::default()
+        };
+        assert!(filter.accepts(&result("a", 10.0, 10), None));
+        assert!(!filter.accepts(&result("b", 50.0, 50), None));
+
+        // GC bounds require stats of the match
+        let filter = super::ResultFilter {
+            gc_lower: Some(40.0),
+            gc_upper: Some(60.0),
+            ..Default::default()
+        };
+        let stats = |seq: &[u8]| Some(crate::sketch::Stats::from_sequence(seq));
+        assert!(filter.accepts(&result("a", 10.0, 10), stats(b"ACGTNNNN").as_ref()));
+        assert!(!filter.accepts(&result("a", 10.0, 10), stats(b"ACGCGC").as_ref()));
+        assert!(!filter.accepts(&result("a", 10.0, 10), stats(b"NNNN").as_ref()));
+        assert!(!filter.accepts(&result("a", 10.0, 10), None));
+    }
 }
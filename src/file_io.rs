@@ -1,17 +1,28 @@
 use crate::cli::Commands;
 use crate::cli::HashAlgorithms;
+use crate::cli::MatrixFormat;
+use crate::cli::Molecule;
 use crate::cli::OutputFormats;
-use crate::compare::CompareResult;
-use crate::hash_functions::Function;
+use crate::cli::ResultFormat;
+use crate::compare::SimilarityMatrix;
+use crate::hash_functions::{default_seed, DEFAULT_SEED};
+use crate::heed::HeedHandler;
+use crate::sampling::SamplingScheme;
+use crate::signature::parse_hash_function;
+use crate::signature::Scaling;
 use crate::signature::Signature;
-use crate::sketch::Sketch;
+use crate::sketch::Stats;
 use crate::sketcher;
+use crate::taxonomy::Lineages;
 use anyhow::anyhow;
 use anyhow::Result;
 use byteorder::BigEndian;
 use heed::types::SerdeBincode;
+use heed::types::SerdeJson;
+use heed::types::Str;
 use heed::types::U32;
 use heed::types::U64;
+use heed::BoxedError;
 use heed::DatabaseFlags;
 use heed::EnvFlags;
 use heed::PutFlags;
@@ -19,12 +30,20 @@ use indicatif::MultiProgress;
 use indicatif::ParallelProgressIterator;
 use indicatif::ProgressBar;
 use needletail::parse_fastx_file;
+use needletail::parse_fastx_reader;
+use needletail::FastxReader;
+use rayon::iter::ParallelBridge;
 use rayon::prelude::IntoParallelRefIterator;
 use rayon::prelude::ParallelIterator;
 use serde::Deserialize;
 use serde::Serialize;
 use sourmash::signature::Signature as SourmashSignature;
+use sourmash::signature::SigsTrait;
+use sourmash::sketch::Sketch as SourmashSketch;
+use std::borrow::Cow;
 use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fmt::Display;
 use std::fs;
 use std::fs::remove_file;
 use std::io;
@@ -32,7 +51,10 @@ use std::io::Write;
 use std::path;
 use std::sync::mpsc;
 use std::sync::mpsc::Receiver;
+use std::sync::Arc;
 use std::thread;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 use std::{
     ffi::OsStr,
     fs::File,
@@ -40,6 +62,11 @@ use std::{
     path::PathBuf,
 };
 
+/// Column names of results written as tsv
+pub trait TsvHeader {
+    fn tsv_header() -> &'static str;
+}
+
 pub struct FileHandler {}
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -48,6 +75,343 @@ pub struct ShortSketchInfo {
     pub num_hashes: usize,
     pub kmer_size: u8,
     pub fscale: Option<u64>,
+    pub lineage: Option<String>, // `;` separated taxa, see `taxonomy::RANKS`
+    pub stats: Option<Stats>,    // Only available if sketched with --stats
+    pub max_hash: Option<u64>,   // Sampled hash space of the sketch, see `Sketch::max_hash`
+}
+
+/// Bincode codec for `ShortSketchInfo` that also reads the layouts of older databases
+/// Fields were only appended over time, missing trailing fields are decoded as `None`
+pub struct ShortSketchInfoCodec;
+
+impl heed::BytesEncode<'_> for ShortSketchInfoCodec {
+    type EItem = ShortSketchInfo;
+
+    fn bytes_encode(item: &Self::EItem) -> Result<Cow<'_, [u8]>, BoxedError> {
+        Ok(Cow::Owned(bincode::serialize(item)?))
+    }
+}
+
+impl heed::BytesDecode<'_> for ShortSketchInfoCodec {
+    type DItem = ShortSketchInfo;
+
+    fn bytes_decode(mut bytes: &[u8]) -> Result<Self::DItem, BoxedError> {
+        let reader = &mut bytes;
+        Ok(ShortSketchInfo {
+            file_name: bincode::deserialize_from(&mut *reader)?,
+            num_hashes: bincode::deserialize_from(&mut *reader)?,
+            kmer_size: bincode::deserialize_from(&mut *reader)?,
+            fscale: bincode::deserialize_from(&mut *reader)?,
+            lineage: deserialize_optional(reader)?,
+            stats: deserialize_optional(reader)?,
+            max_hash: deserialize_optional(reader)?,
+        })
+    }
+}
+
+/// Decodes an optional (bincode) field that is missing at the end of older entries
+fn deserialize_optional<T: serde::de::DeserializeOwned>(
+    reader: &mut &[u8],
+) -> Result<Option<T>, bincode::Error> {
+    if reader.is_empty() {
+        Ok(None)
+    } else {
+        bincode::deserialize_from(reader)
+    }
+}
+
+/// Sourmash signature with the record statistics, scaling and sampling of its sketches
+/// as additional fields, unknown fields are ignored by sourmash
+#[derive(Serialize, Deserialize)]
+struct SourmashRecord {
+    #[serde(flatten)]
+    signature: SourmashSignature,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    jam_stats: Vec<Option<Stats>>, // Same order as the sketches
+    #[serde(default, skip_serializing_if = "Scaling::is_uniform")]
+    jam_scaling: Scaling,
+    #[serde(default, skip_serializing_if = "SamplingScheme::is_all")]
+    jam_sampling: SamplingScheme,
+}
+
+impl From<Signature> for SourmashRecord {
+    fn from(signature: Signature) -> Self {
+        let jam_scaling = signature.scaling;
+        let jam_sampling = signature.sampling;
+        let mut jam_stats = signature
+            .sketches
+            .iter()
+            .map(|sketch| sketch.stats)
+            .collect::<Vec<_>>();
+        if jam_stats.iter().all(Option::is_none) {
+            jam_stats.clear();
+        }
+        SourmashRecord {
+            signature: signature.into(),
+            jam_stats,
+            jam_scaling,
+            jam_sampling,
+        }
+    }
+}
+
+impl TryFrom<SourmashRecord> for Signature {
+    type Error = anyhow::Error;
+
+    fn try_from(record: SourmashRecord) -> Result<Self> {
+        let mut signature = Signature::try_from(record.signature)?;
+        for (sketch, stats) in signature.sketches.iter_mut().zip(record.jam_stats) {
+            sketch.stats = stats;
+        }
+        // Capped sketches are complete up to their largest hash
+        if !record.jam_scaling.is_uniform() {
+            for sketch in signature.sketches.iter_mut() {
+                if let Some(last) = sketch.hashes.last() {
+                    sketch.max_hash = Some(last.saturating_add(1));
+                }
+            }
+        }
+        signature.scaling = record.jam_scaling;
+        signature.sampling = record.jam_sampling;
+        Ok(signature)
+    }
+}
+
+/// Current version of the `DatabaseMeta` header
+/// Version 2: Murmur3 hashes the kmer bytes for all kmer sizes (sourmash compatible)
+/// Version 3: DNA kmers with 32 <= k < 64 are hashed 2-bit encoded (except murmur3)
+pub const META_VERSION: u32 = 3;
+/// Key of the header in the `meta` database
+pub const META_KEY: &str = "header";
+/// Number of bases per chunk if a single input is sketched by multiple threads
+const PARALLEL_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Owned records (id, sequence) that are sketched by one worker
+type RecordChunk = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// Database level settings, stored as json in the `meta` database of lmdb files
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DatabaseMeta {
+    pub version: u32,
+    pub algorithm: HashAlgorithms,
+    pub kmer_size: u8,
+    pub fscale: Option<u64>,
+    pub nmax: Option<u64>,
+    #[serde(default)]
+    pub nmin: Option<u64>,
+    #[serde(default)]
+    pub kscale: Option<u64>,
+    pub singleton: bool,
+    #[serde(default)]
+    pub track_abundance: bool,
+    #[serde(default)]
+    pub molecule: Molecule,
+    // Window size and step (in sequence characters) of positional sketches
+    #[serde(default)]
+    pub window: Option<u64>,
+    #[serde(default)]
+    pub step: Option<u64>,
+    #[serde(default)]
+    pub stats: bool, // Length, GC and N content per sketch
+    #[serde(default)]
+    pub sampling: SamplingScheme,
+    #[serde(default = "default_seed")]
+    pub seed: u64, // Seed of all hash functions
+    pub created: u64, // Unix timestamp in seconds
+    pub jam_version: String,
+}
+
+impl DatabaseMeta {
+    pub fn new(
+        algorithm: HashAlgorithms,
+        kmer_size: u8,
+        fscale: Option<u64>,
+        nmax: Option<u64>,
+        singleton: bool,
+    ) -> Self {
+        DatabaseMeta {
+            version: META_VERSION,
+            algorithm,
+            kmer_size,
+            fscale,
+            nmax,
+            nmin: None,
+            kscale: None,
+            singleton,
+            track_abundance: false,
+            molecule: Molecule::Dna,
+            window: None,
+            step: None,
+            stats: false,
+            sampling: SamplingScheme::All,
+            seed: DEFAULT_SEED,
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            jam_version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+
+    /// Settings of databases without header (sketched by version 1 with the default algorithm)
+    pub fn legacy(kmer_size: u8, fscale: Option<u64>) -> Self {
+        DatabaseMeta {
+            version: 1,
+            ..DatabaseMeta::new(HashAlgorithms::Default, kmer_size, fscale, None, false)
+        }
+    }
+
+    /// Derives the common settings of a set of (sourmash) signatures
+    /// Fails if the signatures were created with different settings
+    pub fn from_signatures(signatures: &[Signature]) -> Result<Self> {
+        let mut meta: Option<DatabaseMeta> = None;
+        for sig in signatures {
+            let sig_meta = DatabaseMeta {
+                molecule: sig.molecule.clone(),
+                sampling: sig.sampling,
+                seed: sig.seed,
+                ..DatabaseMeta::new(
+                    sig.algorithm.clone(),
+                    sig.kmer_size,
+                    sig.fscale(),
+                    None,
+                    false,
+                )
+            };
+            match &meta {
+                Some(meta) => meta
+                    .check_compatible(&sig_meta)
+                    .map_err(|e| anyhow!("Signature {} is incompatible: {}", sig.file_name, e))?,
+                None => meta = Some(sig_meta),
+            }
+        }
+        let meta = meta.ok_or_else(|| anyhow!("No database signatures found"))?;
+        meta.check_algorithm()?;
+        Ok(meta)
+    }
+
+    /// Custom hash algorithms must be registered before sketching
+    pub fn check_algorithm(&self) -> Result<()> {
+        self.algorithm.hasher(&self.molecule).map(|_| ())
+    }
+
+    /// Databases with an outdated kmer encoding can not be compared with new sketches
+    pub fn check_kmer_encoding(&self) -> Result<()> {
+        // Older versions hashed murmur3 DNA kmers with k < 32 2-bit encoded
+        if self.version < 2
+            && self.molecule == Molecule::Dna
+            && self.algorithm == HashAlgorithms::Murmur3
+            && self.kmer_size < 32
+        {
+            return Err(anyhow!(
+                "Kmers of size {} were hashed 2-bit encoded by jam {}, re-sketch the database",
+                self.kmer_size,
+                self.jam_version
+            ));
+        }
+        // Older versions hashed DNA kmers with 32 <= k < 64 as bytes
+        if self.version < 3
+            && self.molecule == Molecule::Dna
+            && (32..64).contains(&self.kmer_size)
+            && self.algorithm != HashAlgorithms::Murmur3
+        {
+            return Err(anyhow!(
+                "Kmers of size {} were hashed as bytes by jam {}, re-sketch the database",
+                self.kmer_size,
+                self.jam_version
+            ));
+        }
+        Ok(())
+    }
+
+    /// Sketches can only be combined / compared if these settings match
+    pub fn check_compatible(&self, other: &DatabaseMeta) -> Result<()> {
+        self.check_kmer_encoding()?;
+        other.check_kmer_encoding()?;
+        if self.molecule != other.molecule {
+            return Err(anyhow!(
+                "Molecule does not match, expected: {:?}, got: {:?}",
+                self.molecule,
+                other.molecule
+            ));
+        }
+        if self.algorithm != other.algorithm {
+            return Err(anyhow!(
+                "Hash algorithm does not match, expected: {:?}, got: {:?}",
+                self.algorithm,
+                other.algorithm
+            ));
+        }
+        if self.kmer_size != other.kmer_size {
+            return Err(anyhow!(
+                "Kmer size does not match, expected: {}, got: {}",
+                self.kmer_size,
+                other.kmer_size
+            ));
+        }
+        if self.fscale != other.fscale {
+            return Err(anyhow!(
+                "Fscale does not match, expected: {:?}, got: {:?}",
+                self.fscale,
+                other.fscale
+            ));
+        }
+        if self.sampling != other.sampling {
+            return Err(anyhow!(
+                "Sampling scheme does not match, expected: {:?}, got: {:?}",
+                self.sampling,
+                other.sampling
+            ));
+        }
+        if self.seed != other.seed {
+            return Err(anyhow!(
+                "Hash seed does not match, expected: {}, got: {}",
+                self.seed,
+                other.seed
+            ));
+        }
+        Ok(())
+    }
+
+    /// Appended sketches must also match the settings that are only stored in the header
+    pub fn check_append(&self, other: &DatabaseMeta) -> Result<()> {
+        self.check_compatible(other)?;
+        if self.nmin != other.nmin || self.nmax != other.nmax || self.kscale != other.kscale {
+            return Err(anyhow!(
+                "Nmin / nmax / kscale do not match, expected: {:?} / {:?} / {:?}, got: {:?} / {:?} / {:?}",
+                self.nmin,
+                self.nmax,
+                self.kscale,
+                other.nmin,
+                other.nmax,
+                other.kscale
+            ));
+        }
+        if self.track_abundance != other.track_abundance {
+            return Err(anyhow!(
+                "Abundance tracking does not match, expected: {}, got: {}",
+                self.track_abundance,
+                other.track_abundance
+            ));
+        }
+        if self.stats != other.stats {
+            return Err(anyhow!(
+                "Stats do not match, expected: {}, got: {}",
+                self.stats,
+                other.stats
+            ));
+        }
+        if self.window != other.window || self.step != other.step {
+            return Err(anyhow!(
+                "Window size / step does not match, expected: {:?} / {:?}, got: {:?} / {:?}",
+                self.window,
+                self.step,
+                other.window,
+                other.step
+            ));
+        }
+        Ok(())
+    }
 }
 
 impl FileHandler {
@@ -56,29 +420,143 @@ impl FileHandler {
             Commands::Sketch {
                 input,
                 output,
+                name,
                 kmer_size,
                 fscale,
                 nmax,
+                nmin,
+                kscale,
                 algorithm,
                 format,
                 singleton,
+                molecule,
+                input_is_protein,
+                track_abundance,
+                append,
+                lineages,
+                taxdump,
+                window,
+                step,
+                stats,
+                sampling,
+                smer_size,
+                minimizer_window,
+                seed,
             } => {
+                if append && !matches!(format, OutputFormats::Lmdb) {
+                    return Err(anyhow!(
+                        "Append is only supported for the lmdb output format"
+                    ));
+                }
+                if window.is_some() && !matches!(format, OutputFormats::Lmdb) {
+                    return Err(anyhow!(
+                        "Windows are only supported for the lmdb output format"
+                    ));
+                }
+                if window == Some(0) || step == Some(0) {
+                    return Err(anyhow!("Window size and step must be greater than 0"));
+                }
+                let sampling = SamplingScheme::new(&sampling, smer_size, minimizer_window)?;
+                if kscale == Some(0) {
+                    return Err(anyhow!("Kscale must be greater than 0"));
+                }
+                if let (Some(nmin), Some(nmax)) = (nmin, nmax) {
+                    if nmin > nmax {
+                        return Err(anyhow!(
+                            "Nmin ({}) must not be larger than nmax ({})",
+                            nmin,
+                            nmax
+                        ));
+                    }
+                }
+                let lineages = match lineages {
+                    Some(lineages) if !matches!(format, OutputFormats::Lmdb) => {
+                        return Err(anyhow!(
+                            "Lineages {:?} are only stored in the lmdb output format",
+                            lineages
+                        ));
+                    }
+                    Some(lineages) => Some(Arc::new(Lineages::from_file(
+                        &lineages,
+                        taxdump.as_deref(),
+                    )?)),
+                    None => None,
+                };
                 let files = FileHandler::test_and_collect_files(input, true)?;
+                if name.is_some() && files.len() != 1 {
+                    return Err(anyhow!(
+                        "A signature name can only be used with a single input, got {} inputs",
+                        files.len()
+                    ));
+                }
                 let pool = rayon::ThreadPoolBuilder::new()
                     .num_threads(threads.unwrap_or_default())
                     .build()?;
 
-                let function = Function::from_alg(algorithm.clone(), kmer_size);
-
-                let (send, recv) = mpsc::sync_channel(10);
+                let mut kmer_sizes = kmer_size;
+                kmer_sizes.sort_unstable();
+                kmer_sizes.dedup();
+                let metas = kmer_sizes
+                    .iter()
+                    .map(|kmer_size| DatabaseMeta {
+                        track_abundance,
+                        molecule: molecule.clone(),
+                        window,
+                        step,
+                        stats,
+                        nmin,
+                        kscale,
+                        sampling,
+                        seed,
+                        ..DatabaseMeta::new(algorithm.clone(), *kmer_size, fscale, nmax, singleton)
+                    })
+                    .collect::<Vec<_>>();
+                let Some(settings) = metas.first().cloned() else {
+                    return Err(anyhow!("At least one kmer size is required"));
+                };
+                for meta in metas.iter() {
+                    meta.check_algorithm()?;
+                    meta.sampling.check_kmer_size(meta.kmer_size)?;
+                }
 
                 let multi_bar = MultiProgress::new();
-                let multi_bar_clone = multi_bar.clone();
-
                 let is_stdout = output.is_none();
-                let handler = thread::spawn(move || {
-                    FileHandler::write_output(fscale, output, format, recv, multi_bar_clone)
-                });
+                // Threads that are not used for separate files sketch records of the same file
+                let file_threads = pool.current_num_threads() / files.len().max(1);
+
+                // Lmdb databases hold a single kmer size, multiple kmer sizes
+                // are written to one database per kmer size in subfolders of the output
+                let mut senders = Vec::new();
+                let mut handlers = Vec::new();
+                let outputs = match (&format, &output) {
+                    (OutputFormats::Lmdb, Some(output)) if metas.len() > 1 => metas
+                        .into_iter()
+                        .map(|meta| {
+                            let path = output.join(format!("k{}", meta.kmer_size));
+                            fs::create_dir_all(&path)?;
+                            Ok((meta, Some(path)))
+                        })
+                        .collect::<Result<Vec<_>>>()?,
+                    _ => vec![(settings.clone(), output)],
+                };
+                for (meta, output) in outputs {
+                    let (send, recv) = mpsc::sync_channel(10);
+                    let multi_bar_clone = multi_bar.clone();
+                    let format = format.clone();
+                    let lineages = lineages.clone();
+                    senders.push(send);
+                    handlers.push(thread::spawn(move || {
+                        FileHandler::write_output(
+                            meta,
+                            output,
+                            format,
+                            append,
+                            lineages.as_deref(),
+                            recv,
+                            multi_bar_clone,
+                        )
+                    }));
+                }
 
                 let pb = ProgressBar::new(files.len() as u64);
                 let pb = multi_bar.add(pb);
@@ -93,31 +571,34 @@ impl FileHandler {
                         .progress_with(pb)
                         .try_for_each(|file_path| {
                             pb_clone.set_message(format!("{:?}", file_path.clone()));
-                            match FileHandler::sketch_file(
+                            let sigs = FileHandler::sketch_file_kmers(
                                 file_path,
-                                kmer_size,
-                                fscale,
-                                nmax,
-                                singleton,
-                                function.clone(),
-                                algorithm.clone(),
+                                &settings,
+                                &kmer_sizes,
+                                name.as_deref(),
+                                input_is_protein,
+                                file_threads,
                                 is_stdout,
-                            ) {
-                                Ok(sig) => {
-                                    send.send(sig).map_err(|_| anyhow!("Error while sending"))
-                                }
-                                Err(_) => {
-                                    Err(anyhow!("Error while sketching file {:?}", file_path))
-                                }
+                            )
+                            .map_err(|_| anyhow!("Error while sketching file {:?}", file_path))?;
+                            // Signatures are in the same order as the senders (or all go to one)
+                            for (idx, sig) in sigs.into_iter().enumerate() {
+                                senders[idx.min(senders.len() - 1)]
+                                    .send(sig)
+                                    .map_err(|_| anyhow!("Error while sending"))?;
                             }
+                            Ok::<(), anyhow::Error>(())
                         })
                 });
 
-                drop(send);
+                drop(senders);
 
-                Ok(handler
-                    .join()
-                    .map_err(|_| anyhow!("Unable to join threads"))??)
+                for handler in handlers {
+                    handler
+                        .join()
+                        .map_err(|_| anyhow!("Unable to join threads"))??;
+                }
+                Ok(())
             }
             _ => Err(anyhow!("Wrong command")),
         }
@@ -125,53 +606,193 @@ impl FileHandler {
 
     pub fn sketch_file(
         input: &PathBuf,
-        kmer_length: u8,
-        fscale: Option<u64>,
-        nmax: Option<u64>,
-        singleton: bool,
-        function: Function,
-        algorithm: HashAlgorithms,
-        _stdout: bool,
+        settings: &DatabaseMeta,
+        input_is_protein: bool,
+        stdout: bool,
     ) -> Result<Signature> {
+        FileHandler::sketch_file_kmers(
+            input,
+            settings,
+            &[settings.kmer_size],
+            None,
+            input_is_protein,
+            1,
+            stdout,
+        )?
+        .pop()
+        .ok_or_else(|| anyhow!("No signature created for {:?}", input))
+    }
+
+    /// Sketches all kmer sizes in a single pass over the input, one signature per kmer size
+    /// The input is read from stdin if it is "-", the name defaults to the input path
+    /// With more than one thread the records of the input are sketched in parallel
+    pub fn sketch_file_kmers(
+        input: &PathBuf,
+        settings: &DatabaseMeta,
+        kmer_sizes: &[u8],
+        name: Option<&str>,
+        input_is_protein: bool,
+        threads: usize,
+        _stdout: bool,
+    ) -> Result<Vec<Signature>> {
         //let start = std::time::Instant::now();
-        let max_hash = if let Some(fscale) = fscale {
-            (u64::MAX as f64 / fscale as f64) as u64
-        } else {
-            u64::MAX
-        };
-        let mut sketcher = sketcher::Sketcher::new(
-            kmer_length,
-            input
+        let name = match name {
+            Some(name) => name.to_string(),
+            None => input
                 .to_str()
                 .ok_or_else(|| anyhow!("Unknown path"))?
                 .to_string(),
-            singleton,
-            max_hash,
-            nmax,
-            function,
-            algorithm,
-        );
-        let mut reader = parse_fastx_file(input)?;
-        //let mut counter = 0;
-        while let Some(record) = reader.next() {
-            sketcher.process(&record?);
-        }
-        //let elapsed = start.elapsed().as_millis();
-        // if !stdout {
-        //     println!(
-        //         "Processed {:?} with {} records, in {:?} seconds",
-        //         input,
-        //         counter,
-        //         elapsed as f64 / 1000.0,
-        //     );
-        // }
+        };
+        let hasher = settings.algorithm.hasher(&settings.molecule)?;
+        let new_sketcher = || {
+            sketcher::Sketcher::new(
+                name.to_string(),
+                settings,
+                hasher.clone(),
+                kmer_sizes,
+                input_is_protein,
+            )
+        };
+        let mut reader = if input.as_path() == path::Path::new("-") {
+            parse_fastx_reader(io::stdin())?
+        } else {
+            parse_fastx_file(input)?
+        };
+        if threads <= 1 {
+            let mut sketcher = new_sketcher();
+            while let Some(record) = reader.next() {
+                sketcher.process(&record?);
+            }
+            return Ok(sketcher.finish());
+        }
+
+        // Chunks of records are sketched by multiple workers and merged afterwards
+        let chunks = std::iter::from_fn(move || FileHandler::read_chunk(&mut reader).transpose())
+            .enumerate();
+        let (mut sketcher, mut completed) = chunks
+            .par_bridge()
+            .try_fold(
+                || (new_sketcher(), Vec::new()),
+                |(mut sketcher, mut completed), (idx, chunk)| {
+                    for (id, seq) in chunk? {
+                        sketcher.process_sequence(&id, &seq);
+                    }
+                    // Singleton sketches are restored in the order of the records
+                    completed.push((idx, sketcher.take_completed()));
+                    Ok::<_, anyhow::Error>((sketcher, completed))
+                },
+            )
+            .try_reduce(
+                || (new_sketcher(), Vec::new()),
+                |(mut sketcher, mut completed), (other, other_completed)| {
+                    sketcher.merge(other);
+                    completed.extend(other_completed);
+                    Ok((sketcher, completed))
+                },
+            )?;
+        completed.sort_by_key(|(idx, _)| *idx);
+        for (_, sketches) in completed {
+            sketcher.extend_completed(sketches);
+        }
         Ok(sketcher.finish())
     }
 
+    /// Reads owned records until PARALLEL_CHUNK_SIZE bases are reached, None at the end of the input
+    fn read_chunk(reader: &mut Box<dyn FastxReader>) -> Result<Option<RecordChunk>> {
+        let mut chunk = Vec::new();
+        let mut size = 0;
+        while size < PARALLEL_CHUNK_SIZE {
+            let Some(record) = reader.next() else {
+                break;
+            };
+            let record = record?;
+            let seq = record.seq().into_owned();
+            size += seq.len();
+            chunk.push((record.id().to_vec(), seq));
+        }
+        Ok((!chunk.is_empty()).then_some(chunk))
+    }
+
+    /// Loads precomputed sketches (lmdb / sourmash) or sketches a raw sequence file
+    /// Raw files are sketched and loaded sketches validated against the given settings
+    pub fn load_or_sketch_file(input: &PathBuf, settings: &DatabaseMeta) -> Result<Vec<Signature>> {
+        let signatures = if test_lmdb(input) {
+            HeedHandler::new_ro(input.clone())?.load_signatures()?
+        } else if test_sketch(input) {
+            FileHandler::read_signatures(input)?
+        } else {
+            // Only the hash, sampling and window settings are relevant for queries
+            let settings = DatabaseMeta {
+                molecule: settings.molecule.clone(),
+                sampling: settings.sampling,
+                seed: settings.seed,
+                window: settings.window,
+                step: settings.step,
+                ..DatabaseMeta::new(
+                    settings.algorithm.clone(),
+                    settings.kmer_size,
+                    settings.fscale,
+                    None,
+                    false,
+                )
+            };
+            let signature = FileHandler::sketch_file(input, &settings, false, false)?;
+            // Each window is compared as a separate query
+            if settings.window.is_some() {
+                return Ok(signature.split());
+            }
+            return Ok(vec![signature]);
+        };
+        settings
+            .check_compatible(&DatabaseMeta::from_signatures(&signatures)?)
+            .map_err(|e| anyhow!("Input {:?} is incompatible with the database: {}", input, e))?;
+        Ok(signatures)
+    }
+
+    /// Loads precomputed sketches and sketches raw files in the order of the inputs
+    /// Raw files use the settings of the precomputed sketches or `default` if there are none
+    pub fn load_or_sketch_files(
+        inputs: &[PathBuf],
+        default: &DatabaseMeta,
+    ) -> Result<Vec<Signature>> {
+        let mut loaded = Vec::with_capacity(inputs.len());
+        for input in inputs {
+            loaded.push(if test_lmdb(input) {
+                Some(HeedHandler::new_ro(input.clone())?.load_signatures()?)
+            } else if test_sketch(input) {
+                Some(FileHandler::read_signatures(input)?)
+            } else {
+                None
+            });
+        }
+        let precomputed = loaded
+            .iter()
+            .flatten()
+            .flatten()
+            .cloned()
+            .collect::<Vec<_>>();
+        let settings = if precomputed.is_empty() {
+            default.clone()
+        } else {
+            DatabaseMeta::from_signatures(&precomputed)?
+        };
+
+        let mut signatures = Vec::new();
+        for (input, loaded) in inputs.iter().zip(loaded) {
+            match loaded {
+                Some(loaded) => signatures.extend(loaded),
+                None => signatures.extend(FileHandler::load_or_sketch_file(input, &settings)?),
+            }
+        }
+        Ok(signatures)
+    }
+
     pub fn write_output(
-        fscale: Option<u64>,
+        meta: DatabaseMeta,
         output: Option<PathBuf>,
         output_format: OutputFormats,
+        append: bool,
+        lineages: Option<&Lineages>,
         signature_recv: Receiver<Signature>,
         multibar: MultiProgress,
     ) -> Result<()> {
@@ -186,11 +807,11 @@ impl FileHandler {
                 output.write_all(b"[\n")?;
                 let mut first = true;
                 while let Ok(sig) = signature_recv.recv() {
-                    let sourmash_sig: SourmashSignature = sig.into();
+                    let sourmash_sig: SourmashRecord = sig.into();
                     if !first {
                         output.write_all(b",\n")?;
-                        first = false;
                     }
+                    first = false;
                     serde_json::to_writer(&mut output, &sourmash_sig)?;
                 }
                 output.write_all(b"]")?;
@@ -209,18 +830,16 @@ impl FileHandler {
                     ));
                 }
 
-                let heed_env = unsafe {
-                    heed::EnvOpenOptions::new()
-                        .map_size(10 * 1024 * 1024 * 1024 * 1024)
-                        .max_dbs(2)
-                        .flags(EnvFlags::WRITE_MAP | EnvFlags::MAP_ASYNC)
-                        .open(output.clone())?
+                let heed_env = if append {
+                    FileHandler::open_lmdb_append(&output, &meta)?
+                } else {
+                    FileHandler::create_lmdb_env(&output)?
                 };
                 {
                     let mut write_txn = heed_env.write_txn()?;
 
                     let sigs_db = heed_env
-                        .create_database::<U32<BigEndian>, SerdeBincode<ShortSketchInfo>>(
+                        .create_database::<U32<BigEndian>, ShortSketchInfoCodec>(
                             &mut write_txn,
                             Some("sigs"),
                         )?;
@@ -230,8 +849,35 @@ impl FileHandler {
                         .name("hashes")
                         .flags(DatabaseFlags::DUP_SORT)
                         .create(&mut write_txn)?;
+                    let meta_db = heed_env.create_database::<Str, SerdeJson<DatabaseMeta>>(
+                        &mut write_txn,
+                        Some("meta"),
+                    )?;
+                    let abundances_db = heed_env
+                        .create_database::<U32<BigEndian>, SerdeBincode<Vec<u64>>>(
+                            &mut write_txn,
+                            Some("abundances"),
+                        )?;
+                    // Appending keeps the original header
+                    if meta_db.get(&write_txn, META_KEY)?.is_none() {
+                        // Existing sketches of header-less databases have no abundances / stats
+                        let is_empty = sigs_db.is_empty(&write_txn)?;
+                        let meta = DatabaseMeta {
+                            track_abundance: meta.track_abundance && is_empty,
+                            stats: meta.stats && is_empty,
+                            ..meta.clone()
+                        };
+                        meta_db.put(&mut write_txn, META_KEY, &meta)?;
+                    }
 
-                    let mut counter: u32 = 0;
+                    // Continue after the highest existing key, new keys are always larger
+                    // which keeps the duplicates in the hashes table sorted
+                    let mut counter: u32 = match sigs_db.last(&write_txn)? {
+                        Some((last_idx, _)) => last_idx
+                            .checked_add(1)
+                            .ok_or_else(|| anyhow!("Database is full"))?,
+                        None => 0,
+                    };
                     let mut hashes = BTreeMap::new();
                     while let Ok(sig) = signature_recv.recv() {
                         for sketch in sig.sketches {
@@ -239,12 +885,20 @@ impl FileHandler {
                                 &mut write_txn,
                                 &counter,
                                 &ShortSketchInfo {
+                                    lineage: lineages
+                                        .and_then(|lineages| lineages.get(&sketch.name))
+                                        .cloned(),
                                     file_name: sketch.name,
                                     num_hashes: sketch.num_kmers,
                                     kmer_size: sig.kmer_size,
-                                    fscale,
+                                    fscale: meta.fscale,
+                                    stats: sketch.stats,
+                                    max_hash: sketch.max_hash,
                                 },
                             )?;
+                            if let Some(abundances) = &sketch.abundances {
+                                abundances_db.put(&mut write_txn, &counter, abundances)?;
+                            }
                             for hash in sketch.hashes {
                                 hashes.entry(hash).or_insert_with(Vec::new).push(counter);
                             }
@@ -275,65 +929,369 @@ impl FileHandler {
                     write_txn.commit()?;
                 }
 
-                heed_env.prepare_for_closing().wait();
+                FileHandler::compact_lmdb(heed_env, &output)?;
+            }
+        }
 
-                let heed_env = unsafe {
-                    heed::EnvOpenOptions::new()
-                        .map_size(10 * 1024 * 1024 * 1024 * 1024)
-                        .max_dbs(2)
-                        .open(output.clone())?
-                };
+        Ok(())
+    }
 
-                let canonical_path = fs::canonicalize(format!("{}/", output.to_string_lossy()))?;
-                println!(
-                    "Compacting database to {:?}/compact.mdb",
-                    canonical_path.to_string_lossy()
-                );
-                heed_env
-                    .copy_to_file(
-                        format!("{}/compact.mdb", canonical_path.to_string_lossy()),
-                        heed::CompactionOption::Enabled,
-                    )
-                    .map_err(|e| {
-                        println!("Error in copy file: {e}");
-                        e
-                    })?;
+    fn create_lmdb_env(output: &path::Path) -> Result<heed::Env> {
+        Ok(unsafe {
+            heed::EnvOpenOptions::new()
+                .map_size(10 * 1024 * 1024 * 1024 * 1024)
+                .max_dbs(4)
+                .flags(EnvFlags::WRITE_MAP | EnvFlags::MAP_ASYNC)
+                .open(output)?
+        })
+    }
 
-                remove_file(format!("{}/data.mdb", output.to_string_lossy())).map_err(|e| {
-                    println!("Error deleting data.mdb: {e}");
-                    e
-                })?;
-                remove_file(format!("{}/lock.mdb", output.to_string_lossy())).map_err(|e| {
-                    println!("Error deleting lock.mdb: {e}");
-                    e
-                })?;
+    /// Reopens the compacted database in the output folder for writing
+    /// The database is moved back to `data.mdb` and compacted again afterwards
+    fn open_lmdb_append(output: &path::Path, meta: &DatabaseMeta) -> Result<heed::Env> {
+        let compact = output.join("compact.mdb");
+        if !compact.is_file() {
+            return Err(anyhow!("No existing database found at {:?}", compact));
+        }
+        {
+            let handler = HeedHandler::new_ro(compact.clone())?;
+            match handler.get_meta()? {
+                Some(existing) => existing.check_append(meta)?,
+                // Databases without header, only kmer size and fscale can be validated
+                None => {
+                    let rtxn = handler.heed_env.read_txn()?;
+                    if let Some((_, info)) = handler.signatures.last(&rtxn)? {
+                        if info.kmer_size != meta.kmer_size {
+                            return Err(anyhow!(
+                                "Kmer size does not match existing database, expected: {}, got: {}",
+                                info.kmer_size,
+                                meta.kmer_size
+                            ));
+                        }
+                        if info.fscale != meta.fscale {
+                            return Err(anyhow!(
+                                "Fscale does not match existing database, expected: {:?}, got: {:?}",
+                                info.fscale,
+                                meta.fscale
+                            ));
+                        }
+                    }
+                    rtxn.commit()?;
+                }
             }
+            handler.heed_env.prepare_for_closing().wait();
         }
+        fs::rename(&compact, output.join("data.mdb"))?;
+        FileHandler::create_lmdb_env(output)
+    }
 
+    /// Copies the database into a compacted `compact.mdb` and removes the working files
+    fn compact_lmdb(heed_env: heed::Env, output: &path::Path) -> Result<()> {
+        heed_env.prepare_for_closing().wait();
+
+        let heed_env = unsafe {
+            heed::EnvOpenOptions::new()
+                .map_size(10 * 1024 * 1024 * 1024 * 1024)
+                .max_dbs(4)
+                .open(output)?
+        };
+
+        let canonical_path = fs::canonicalize(format!("{}/", output.to_string_lossy()))?;
+        println!(
+            "Compacting database to {:?}/compact.mdb",
+            canonical_path.to_string_lossy()
+        );
+        heed_env
+            .copy_to_file(
+                format!("{}/compact.mdb", canonical_path.to_string_lossy()),
+                heed::CompactionOption::Enabled,
+            )
+            .map_err(|e| {
+                println!("Error in copy file: {e}");
+                e
+            })?;
+
+        remove_file(format!("{}/data.mdb", output.to_string_lossy())).map_err(|e| {
+            println!("Error deleting data.mdb: {e}");
+            e
+        })?;
+        remove_file(format!("{}/lock.mdb", output.to_string_lossy())).map_err(|e| {
+            println!("Error deleting lock.mdb: {e}");
+            e
+        })?;
         Ok(())
     }
 
-    pub fn read_signatures(input: &PathBuf) -> Result<Vec<Signature>> {
-        Ok(
-            sourmash::signature::Signature::from_path(path::Path::new(input))?
-                .into_iter()
-                .map(Signature::from)
-                .collect(),
-        )
+    pub fn read_signatures(input: &path::Path) -> Result<Vec<Signature>> {
+        FileHandler::read_sourmash_records(input)?
+            .into_iter()
+            .map(|record| {
+                Signature::try_from(record)
+                    .map_err(|e| anyhow!("Invalid signature in {:?}: {}", input, e))
+            })
+            .collect()
     }
 
+    /// Reads a (gzip compressed) sourmash json file including the record statistics
+    fn read_sourmash_records(input: &path::Path) -> Result<Vec<SourmashRecord>> {
+        let mut reader = BufReader::new(File::open(input)?);
+        let reader: Box<dyn io::Read> = if reader.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+            Box::new(flate2::bufread::MultiGzDecoder::new(reader))
+        } else {
+            Box::new(reader)
+        };
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    /// Merges multiple lmdb databases or sourmash json files into one output
+    /// All inputs must share the same kmer size, fscale and hash algorithm
     pub fn concat(inputs: Vec<PathBuf>, output: PathBuf) -> Result<()> {
-        let o_file = std::fs::File::create(output)?;
-        let mut bufwriter = std::io::BufWriter::new(o_file);
+        if inputs.is_empty() {
+            return Err(anyhow!("No input sketches provided"));
+        }
+        let num_lmdb = inputs.iter().filter(|input| test_lmdb(input)).count();
+        if num_lmdb == inputs.len() {
+            FileHandler::concat_lmdb(inputs, output)
+        } else if num_lmdb == 0 {
+            FileHandler::concat_sourmash(inputs, output)
+        } else {
+            Err(anyhow!(
+                "Unable to merge lmdb databases and sourmash signatures into one output"
+            ))
+        }
+    }
+
+    fn concat_lmdb(inputs: Vec<PathBuf>, output: PathBuf) -> Result<()> {
+        if !output.is_dir() {
+            return Err(anyhow!(
+                "Output folder {:?} does not exist or is no directory",
+                output
+            ));
+        }
+
+        let heed_env = FileHandler::create_lmdb_env(&output)?;
+        {
+            let mut write_txn = heed_env.write_txn()?;
+
+            let sigs_db = heed_env.create_database::<U32<BigEndian>, ShortSketchInfoCodec>(
+                &mut write_txn,
+                Some("sigs"),
+            )?;
+            let hashes_db = heed_env
+                .database_options()
+                .types::<U64<BigEndian>, U32<BigEndian>>()
+                .name("hashes")
+                .flags(DatabaseFlags::DUP_SORT)
+                .create(&mut write_txn)?;
+            let meta_db = heed_env
+                .create_database::<Str, SerdeJson<DatabaseMeta>>(&mut write_txn, Some("meta"))?;
+            let abundances_db = heed_env
+                .create_database::<U32<BigEndian>, SerdeBincode<Vec<u64>>>(
+                    &mut write_txn,
+                    Some("abundances"),
+                )?;
+
+            let mut counter: u32 = 0;
+            let mut kmer_size = None;
+            let mut fscale = None;
+            let mut meta: Option<DatabaseMeta> = None;
+            let mut hashes = BTreeMap::new();
+            for (idx, input) in inputs.into_iter().enumerate() {
+                let handler = HeedHandler::new_ro(input.clone())?;
+                let input_meta = handler.get_meta()?;
+                // The hash algorithm of databases without header is unknown
+                if idx > 0 && meta.is_some() != input_meta.is_some() {
+                    return Err(anyhow!(
+                        "Unable to merge {:?}, databases with and without header can not be merged",
+                        input
+                    ));
+                }
+                match (&mut meta, input_meta) {
+                    (Some(meta), Some(input_meta)) => {
+                        meta.check_compatible(&input_meta)
+                            .map_err(|e| anyhow!("Unable to merge {:?}: {}", input, e))?;
+                        meta.track_abundance &= input_meta.track_abundance;
+                    }
+                    (None, Some(input_meta)) => meta = Some(input_meta),
+                    _ => {}
+                }
+                let rtxn = handler.heed_env.read_txn()?;
 
+                // Old sketch id -> new sketch id in the merged database
+                let mut renumbered = HashMap::new();
+                for sig in handler.signatures.iter(&rtxn)? {
+                    let (old_idx, info) = sig?;
+                    match kmer_size {
+                        Some(kmer_size) if kmer_size != info.kmer_size => {
+                            return Err(anyhow!(
+                                "Kmer size of {:?} does not match, expected: {}, got: {}",
+                                input,
+                                kmer_size,
+                                info.kmer_size
+                            ));
+                        }
+                        _ => kmer_size = Some(info.kmer_size),
+                    }
+                    match fscale {
+                        Some(fscale) if fscale != info.fscale => {
+                            return Err(anyhow!(
+                                "Fscale of {:?} does not match, expected: {:?}, got: {:?}",
+                                input,
+                                fscale,
+                                info.fscale
+                            ));
+                        }
+                        _ => fscale = Some(info.fscale),
+                    }
+                    sigs_db.put(&mut write_txn, &counter, &info)?;
+                    if let Some(abundances) = handler.get_abundances(&rtxn, old_idx)? {
+                        abundances_db.put(&mut write_txn, &counter, &abundances)?;
+                    }
+                    renumbered.insert(old_idx, counter);
+                    counter = counter
+                        .checked_add(1)
+                        .ok_or_else(|| anyhow!("Too many signatures to merge"))?;
+                }
+
+                for hash in handler.hashes.iter(&rtxn)? {
+                    let (hash, old_idx) = hash?;
+                    let new_idx = renumbered.get(&old_idx).ok_or_else(|| {
+                        anyhow!(
+                            "Hash references unknown signature {} in {:?}",
+                            old_idx,
+                            input
+                        )
+                    })?;
+                    hashes.entry(hash).or_insert_with(Vec::new).push(*new_idx);
+                }
+            }
+
+            if let Some(meta) = meta {
+                meta_db.put(&mut write_txn, META_KEY, &meta)?;
+            }
+
+            // Inputs are processed in order, duplicates are therefore already sorted
+            for (hash, sigs) in hashes {
+                for sig in sigs {
+                    hashes_db.put_with_flags(&mut write_txn, PutFlags::APPEND_DUP, &hash, &sig)?;
+                }
+            }
+            write_txn.commit()?;
+        }
+
+        FileHandler::compact_lmdb(heed_env, &output)
+    }
+
+    fn concat_sourmash(inputs: Vec<PathBuf>, output: PathBuf) -> Result<()> {
+        let mut signatures = Vec::new();
+        let mut kmer_size = None;
+        let mut max_hash = None;
+        let mut algorithm = None;
+        let mut molecule = None;
+        let mut sampling = None;
+        let mut seed = None;
         for input in inputs {
-            let mut reader = BufReader::new(std::fs::File::open(input)?);
-            while let Ok(result) =
-                bincode::deserialize_from::<&mut BufReader<File>, Sketch>(&mut reader)
-            {
-                bincode::serialize_into(&mut bufwriter, &result)?;
+            for record in FileHandler::read_sourmash_records(&input)? {
+                match &sampling {
+                    Some(sampling) if sampling != &record.jam_sampling => {
+                        return Err(anyhow!(
+                            "Sampling scheme of {:?} does not match, expected: {:?}, got: {:?}",
+                            input,
+                            sampling,
+                            record.jam_sampling
+                        ));
+                    }
+                    _ => sampling = Some(record.jam_sampling),
+                }
+                let sig = &record.signature;
+                let sig_algorithm = parse_hash_function(&sig.hash_function()).ok_or_else(|| {
+                    anyhow!(
+                        "Unknown hash function {} in {:?}",
+                        sig.hash_function(),
+                        input
+                    )
+                })?;
+                match &algorithm {
+                    Some(algorithm) if algorithm != &sig_algorithm => {
+                        return Err(anyhow!(
+                            "Hash algorithm of {:?} does not match, expected: {:?}, got: {:?}",
+                            input,
+                            algorithm,
+                            sig_algorithm
+                        ));
+                    }
+                    _ => algorithm = Some(sig_algorithm),
+                }
+
+                for sketch in sig.sketches() {
+                    let (ksize, sketch_max_hash, sketch_molecule, sketch_seed) = match sketch {
+                        SourmashSketch::MinHash(mash) => (
+                            mash.ksize() as u8,
+                            mash.max_hash(),
+                            Molecule::from(mash.hash_function()),
+                            mash.seed(),
+                        ),
+                        SourmashSketch::LargeMinHash(mash) => (
+                            mash.ksize() as u8,
+                            mash.max_hash(),
+                            Molecule::from(mash.hash_function()),
+                            mash.seed(),
+                        ),
+                        SourmashSketch::HyperLogLog(_) => {
+                            return Err(anyhow!("HyperLogLog sketches are not supported"));
+                        }
+                    };
+                    match &molecule {
+                        Some(molecule) if molecule != &sketch_molecule => {
+                            return Err(anyhow!(
+                                "Molecule of {:?} does not match, expected: {:?}, got: {:?}",
+                                input,
+                                molecule,
+                                sketch_molecule
+                            ));
+                        }
+                        _ => molecule = Some(sketch_molecule),
+                    }
+                    match seed {
+                        Some(seed) if seed != sketch_seed => {
+                            return Err(anyhow!(
+                                "Hash seed of {:?} does not match, expected: {}, got: {}",
+                                input,
+                                seed,
+                                sketch_seed
+                            ));
+                        }
+                        _ => seed = Some(sketch_seed),
+                    }
+                    match kmer_size {
+                        Some(kmer_size) if kmer_size != ksize => {
+                            return Err(anyhow!(
+                                "Kmer size of {:?} does not match, expected: {}, got: {}",
+                                input,
+                                kmer_size,
+                                ksize
+                            ));
+                        }
+                        _ => kmer_size = Some(ksize),
+                    }
+                    match max_hash {
+                        Some(max_hash) if max_hash != sketch_max_hash => {
+                            return Err(anyhow!(
+                                "Fscale (max hash) of {:?} does not match, expected: {}, got: {}",
+                                input,
+                                max_hash,
+                                sketch_max_hash
+                            ));
+                        }
+                        _ => max_hash = Some(sketch_max_hash),
+                    }
+                }
+                signatures.push(record);
             }
         }
+
+        let writer = std::io::BufWriter::new(File::create(output)?);
+        serde_json::to_writer(writer, &signatures)?;
         Ok(())
     }
 
@@ -341,6 +1299,11 @@ impl FileHandler {
         let mut resulting_paths = Vec::new();
         let mut found_list: Option<PathBuf> = None;
         for path in input {
+            // Streams can not be checked for extensions and are always sequence inputs
+            if test_stream(&path) {
+                resulting_paths.push(path);
+                continue;
+            }
             if !path.exists() {
                 return Err(anyhow::anyhow!("File {:?} does not exist", path));
             }
@@ -403,7 +1366,106 @@ impl FileHandler {
         Ok(resulting_paths)
     }
 
-    pub fn write_result(result: &Vec<CompareResult>, output: PathBuf) -> Result<()> {
+    /// Writes signatures into a new lmdb database in the output folder
+    pub fn write_lmdb(signatures: Vec<Signature>, output: PathBuf) -> Result<()> {
+        let meta = DatabaseMeta {
+            track_abundance: signatures
+                .iter()
+                .flat_map(|s| s.sketches.iter())
+                .all(|s| s.abundances.is_some()),
+            ..DatabaseMeta::from_signatures(&signatures)?
+        };
+        let (send, recv) = mpsc::channel();
+        for signature in signatures {
+            send.send(signature)?;
+        }
+        drop(send);
+        FileHandler::write_output(
+            meta,
+            Some(output),
+            OutputFormats::Lmdb,
+            false,
+            None,
+            recv,
+            MultiProgress::with_draw_target(indicatif::ProgressDrawTarget::hidden()),
+        )
+    }
+
+    /// Writes the matrix to a file or stdout, npy requires an output file
+    /// the labels of npy matrices are written to `<output>.labels.txt`
+    pub fn write_matrix(
+        matrix: &SimilarityMatrix,
+        format: &MatrixFormat,
+        output: Option<PathBuf>,
+    ) -> Result<()> {
+        let mut writer: Box<dyn Write> = match &output {
+            Some(o) => Box::new(std::io::BufWriter::new(File::create(o)?)),
+            None if *format == MatrixFormat::Npy => {
+                return Err(anyhow!("Output format npy requires an output file"));
+            }
+            None => Box::new(std::io::BufWriter::new(io::stdout())),
+        };
+        match format {
+            MatrixFormat::Tsv => write_matrix_tsv(matrix, &mut writer)?,
+            MatrixFormat::Phylip => write_matrix_phylip(matrix, &mut writer)?,
+            MatrixFormat::Npy => {
+                write_matrix_npy(matrix, &mut writer)?;
+                if let Some(output) = output {
+                    let mut labels = output.into_os_string();
+                    labels.push(".labels.txt");
+                    let mut labels = std::io::BufWriter::new(File::create(labels)?);
+                    for name in matrix.names.iter() {
+                        writeln!(labels, "{}", name)?;
+                    }
+                }
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Writes results to a file or stdout, tsv uses the `Display` implementation
+    /// all other formats the serialized (named) fields
+    pub fn write_results<T: Display + Serialize + TsvHeader>(
+        result: &[T],
+        format: &ResultFormat,
+        output: Option<PathBuf>,
+    ) -> Result<()> {
+        let mut writer: Box<dyn Write> = match output {
+            Some(o) => Box::new(std::io::BufWriter::new(File::create(o)?)),
+            None => Box::new(std::io::BufWriter::new(io::stdout())),
+        };
+        match format {
+            ResultFormat::Tsv => {
+                writeln!(writer, "{}", T::tsv_header())?;
+                for r in result {
+                    writeln!(writer, "{}", r)?;
+                }
+            }
+            ResultFormat::Csv => {
+                let mut csv_writer = csv::Writer::from_writer(writer);
+                for r in result {
+                    csv_writer.serialize(r)?;
+                }
+                csv_writer.flush()?;
+                return Ok(());
+            }
+            ResultFormat::Jsonl => {
+                for r in result {
+                    serde_json::to_writer(&mut writer, r)?;
+                    writeln!(writer)?;
+                }
+            }
+            ResultFormat::Json => {
+                serde_json::to_writer_pretty(&mut writer, result)?;
+                writeln!(writer)?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn write_result<T: Display>(result: &[T], output: PathBuf) -> Result<()> {
         let o_file = std::fs::File::create(output)?;
         let mut bufwriter = std::io::BufWriter::new(o_file);
         for r in result {
@@ -413,22 +1475,317 @@ impl FileHandler {
     }
 }
 
+fn write_matrix_tsv(matrix: &SimilarityMatrix, writer: &mut dyn Write) -> Result<()> {
+    writeln!(writer, "\t{}", matrix.names.join("\t"))?;
+    for (idx, name) in matrix.names.iter().enumerate() {
+        write!(writer, "{}", name)?;
+        for value in matrix.row(idx) {
+            write!(writer, "\t{:.6}", value)?;
+        }
+        writeln!(writer)?;
+    }
+    Ok(())
+}
+
+/// Relaxed (square) PHYLIP distance matrix, names are padded to 10 characters
+fn write_matrix_phylip(matrix: &SimilarityMatrix, writer: &mut dyn Write) -> Result<()> {
+    writeln!(writer, "{}", matrix.len())?;
+    for (idx, name) in matrix.names.iter().enumerate() {
+        let name = name.split_whitespace().collect::<Vec<_>>().join("_");
+        write!(writer, "{:<10}", name)?;
+        for value in matrix.row(idx) {
+            write!(writer, " {:.6}", 1.0 - value)?;
+        }
+        writeln!(writer)?;
+    }
+    Ok(())
+}
+
+/// Numpy `.npy` format version 1.0 with a little endian float64 (N, N) array
+fn write_matrix_npy(matrix: &SimilarityMatrix, writer: &mut dyn Write) -> Result<()> {
+    let mut header = format!(
+        "{{'descr': '<f8', 'fortran_order': False, 'shape': ({}, {}), }}",
+        matrix.len(),
+        matrix.len()
+    );
+    // Magic (6) + version (2) + header length (2) + header must be aligned to 64 bytes
+    let padding = 64 - (10 + header.len() + 1) % 64;
+    header.push_str(&" ".repeat(padding % 64));
+    header.push('\n');
+    writer.write_all(b"\x93NUMPY\x01\x00")?;
+    writer.write_all(&(header.len() as u16).to_le_bytes())?;
+    writer.write_all(header.as_bytes())?;
+    for value in matrix.values.iter() {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Lmdb databases are single (compacted) files with the `.mdb` extension
+pub fn test_lmdb(path: &path::Path) -> bool {
+    path.is_file() && path.extension() == Some(OsStr::new("mdb"))
+}
+
+/// Sourmash signatures are json files, optionally compressed
+pub fn test_sketch(path: &path::Path) -> bool {
+    let name = path.to_string_lossy();
+    [".sig", ".json", ".sig.gz", ".json.gz"]
+        .iter()
+        .any(|ext| name.ends_with(ext))
+}
+
+/// Stdin ("-"), named pipes and process substitutions (/dev/fd/*) are read as streams
+pub fn test_stream(path: &path::Path) -> bool {
+    if path == path::Path::new("-") {
+        return true;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        if let Ok(metadata) = fs::metadata(path) {
+            let file_type = metadata.file_type();
+            return file_type.is_fifo() || file_type.is_char_device();
+        }
+    }
+    path.starts_with("/dev/fd")
+}
+
 pub fn test_extension(ext: &OsStr) -> bool {
-    !(ext != "fasta" && ext != "fa" && ext != "fastq" && ext != "fq" && ext != "gz")
+    !(ext != "fasta" && ext != "fa" && ext != "faa" && ext != "fastq" && ext != "fq" && ext != "gz")
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use heed::{BytesDecode, BytesEncode};
+
+    fn compare_result() -> crate::compare::CompareResult {
+        crate::compare::CompareResult {
+            from_name: "query".to_string(),
+            to_name: "target".to_string(),
+            num_common: 1,
+            num_kmers: 2,
+            reverse: true,
+            estimated_containment: 50.0,
+            query_hashes: 2,
+            target_hashes: 4,
+            query_containment: 50.0,
+            target_containment: 25.0,
+            jaccard: 0.2,
+            ani: 0.9,
+            mash_distance: 0.1,
+            containment_lower: 1.0,
+            containment_upper: 99.0,
+            p_value: None,
+            abundance_containment: None,
+            mean_coverage: None,
+        }
+    }
+
+    /// Writes the results to a temporary file and returns the written content
+    fn write_results_to_string<T: Display + Serialize + TsvHeader>(
+        result: &[T],
+        format: &ResultFormat,
+        name: &str,
+    ) -> String {
+        let output = std::env::temp_dir().join(name);
+        FileHandler::write_results(result, format, Some(output.clone())).unwrap();
+        let written = fs::read_to_string(&output).unwrap();
+        fs::remove_file(output).unwrap();
+        written
+    }
+
+    #[test]
+    fn test_write_results_csv() {
+        let written =
+            write_results_to_string(&[compare_result()], &ResultFormat::Csv, "jam_results.csv");
+        let mut lines = written.lines();
+        assert!(lines
+            .next()
+            .unwrap()
+            .starts_with("query,match,num_common,num_kmers,containment,"));
+        assert!(lines.next().unwrap().starts_with("query,target,1,2,50.0,"));
+        assert_eq!(lines.next(), None);
+    }
+
+    #[test]
+    fn test_write_results_tsv() {
+        let written =
+            write_results_to_string(&[compare_result()], &ResultFormat::Tsv, "jam_results.tsv");
+        let mut lines = written.lines();
+        let header = lines.next().unwrap();
+        assert!(header.starts_with("query\tmatch\tnum_common\t"));
+        let row = lines.next().unwrap();
+        assert_eq!(header.split('\t').count(), row.split('\t').count());
+        assert!(row.ends_with("\tNA\tNA\tNA"));
+        assert_eq!(lines.next(), None);
+    }
+
+    #[test]
+    fn test_write_gather_results() {
+        let result = crate::compare::GatherResult {
+            query_name: "query".to_string(),
+            match_name: "target".to_string(),
+            rank: 0,
+            unique_overlap: 1,
+            total_overlap: 2,
+            query_hashes: 4,
+            match_hashes: 2,
+            f_unique_to_query: 25.0,
+            f_match: 100.0,
+            f_query_explained: 25.0,
+            estimated_abundance: None,
+        };
+        let tsv = write_results_to_string(
+            std::slice::from_ref(&result),
+            &ResultFormat::Tsv,
+            "jam_gather.tsv",
+        );
+        let mut lines = tsv.lines();
+        let header = lines.next().unwrap();
+        let row = lines.next().unwrap();
+        assert_eq!(header.split('\t').count(), row.split('\t').count());
+        assert!(row.ends_with("\tNA"));
+
+        // The csv header matches the tsv header
+        let csv = write_results_to_string(&[result], &ResultFormat::Csv, "jam_gather.csv");
+        assert_eq!(csv.lines().next().unwrap(), header.replace('\t', ","));
+    }
+
+    #[test]
+    fn test_write_matrix() {
+        let matrix = SimilarityMatrix {
+            names: vec!["a".to_string(), "b c".to_string()],
+            values: vec![1.0, 0.25, 0.25, 1.0],
+        };
+        let mut tsv = Vec::new();
+        write_matrix_tsv(&matrix, &mut tsv).unwrap();
+        assert_eq!(
+            String::from_utf8(tsv).unwrap(),
+            "\ta\tb c\na\t1.000000\t0.250000\nb c\t0.250000\t1.000000\n"
+        );
+
+        let mut phylip = Vec::new();
+        write_matrix_phylip(&matrix, &mut phylip).unwrap();
+        assert_eq!(
+            String::from_utf8(phylip).unwrap(),
+            "2\na          0.000000 0.750000\nb_c        0.750000 0.000000\n"
+        );
+
+        let mut npy = Vec::new();
+        write_matrix_npy(&matrix, &mut npy).unwrap();
+        let header_len = u16::from_le_bytes([npy[8], npy[9]]) as usize;
+        assert_eq!(&npy[..6], b"\x93NUMPY");
+        assert_eq!((10 + header_len) % 64, 0);
+        assert!(String::from_utf8_lossy(&npy[10..10 + header_len]).contains("'shape': (2, 2)"));
+        assert_eq!(npy.len(), 10 + header_len + 4 * 8);
+        assert_eq!(
+            &npy[10 + header_len + 8..10 + header_len + 16],
+            &0.25f64.to_le_bytes()
+        );
+    }
+
+    #[test]
+    fn test_short_sketch_info_codec() {
+        // Entries written before lineages were introduced
+        let legacy =
+            bincode::serialize(&("a.fa".to_string(), 10usize, 21u8, Some(1000u64))).unwrap();
+        let info = ShortSketchInfoCodec::bytes_decode(&legacy).unwrap();
+        assert_eq!(info.file_name, "a.fa");
+        assert_eq!(info.num_hashes, 10);
+        assert_eq!(info.fscale, Some(1000));
+        assert_eq!(info.lineage, None);
+
+        let info = ShortSketchInfo {
+            lineage: Some("Bacteria;Pseudomonadota".to_string()),
+            ..info
+        };
+        // Entries written before record statistics / max hashes were introduced
+        let lineage = bincode::serialize(&(
+            "a.fa".to_string(),
+            10usize,
+            21u8,
+            Some(1000u64),
+            info.lineage.clone(),
+        ))
+        .unwrap();
+        let decoded = ShortSketchInfoCodec::bytes_decode(&lineage).unwrap();
+        assert_eq!(decoded.lineage, info.lineage);
+        assert_eq!(decoded.stats, None);
+
+        let info = ShortSketchInfo {
+            stats: Some(crate::sketch::Stats::from_sequence(b"ACGTNN")),
+            ..info
+        };
+        let encoded = ShortSketchInfoCodec::bytes_encode(&info).unwrap();
+        let decoded = ShortSketchInfoCodec::bytes_decode(&encoded).unwrap();
+        assert_eq!(decoded.lineage, info.lineage);
+        assert_eq!(decoded.stats, info.stats);
+        assert_eq!(decoded.kmer_size, 21);
+    }
 
     #[test]
     fn test_test_extension() {
         assert!(test_extension(OsStr::new("fasta")));
         assert!(test_extension(OsStr::new("fa")));
+        assert!(test_extension(OsStr::new("faa")));
         assert!(test_extension(OsStr::new("fastq")));
         assert!(test_extension(OsStr::new("fq")));
         assert!(test_extension(OsStr::new("gz")));
         assert!(!test_extension(OsStr::new("txt")));
         assert!(!test_extension(OsStr::new("list")));
     }
+
+    #[test]
+    fn test_test_stream() {
+        assert!(test_stream(path::Path::new("-")));
+        assert!(!test_stream(path::Path::new("tests/testfiles/short.fa")));
+        assert!(!test_stream(path::Path::new("tests/testfiles")));
+    }
+
+    #[test]
+    fn test_test_sketch() {
+        assert!(test_sketch(path::Path::new("genome.sig")));
+        assert!(test_sketch(path::Path::new("genome.sig.gz")));
+        assert!(test_sketch(path::Path::new("genome.json")));
+        assert!(!test_sketch(path::Path::new("genome.fa.gz")));
+        assert!(!test_sketch(path::Path::new("genome.fasta")));
+    }
+
+    #[test]
+    fn test_kmer_encoding() {
+        // Murmur3 hashed small kmers 2-bit encoded before version 2
+        let old = DatabaseMeta {
+            version: 1,
+            ..DatabaseMeta::new(HashAlgorithms::Murmur3, 21, None, None, false)
+        };
+        assert!(old.check_kmer_encoding().is_err());
+        assert!(
+            DatabaseMeta::new(HashAlgorithms::Murmur3, 21, None, None, false)
+                .check_kmer_encoding()
+                .is_ok()
+        );
+        let old = DatabaseMeta {
+            algorithm: HashAlgorithms::Default,
+            ..old
+        };
+        assert!(old.check_kmer_encoding().is_ok());
+
+        // Kmers with 32 <= k < 64 were hashed as bytes before version 3
+        let old = DatabaseMeta {
+            version: 2,
+            ..DatabaseMeta::new(HashAlgorithms::Default, 33, None, None, false)
+        };
+        assert!(old.check_kmer_encoding().is_err());
+        assert!(
+            DatabaseMeta::new(HashAlgorithms::Default, 33, None, None, false)
+                .check_kmer_encoding()
+                .is_ok()
+        );
+        // Databases without header are checked as version 1
+        assert!(DatabaseMeta::legacy(33, None)
+            .check_kmer_encoding()
+            .is_err());
+        assert!(DatabaseMeta::legacy(21, None).check_kmer_encoding().is_ok());
+    }
 }
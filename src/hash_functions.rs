@@ -1,79 +1,279 @@
 //! A list of hash functions to compare
 //!
 //! Constants chosen by testing different digits of pi;
-use crate::cli::HashAlgorithms;
+use crate::cli::{HashAlgorithms, Molecule};
+use anyhow::{anyhow, Result};
+use std::sync::{Arc, RwLock};
 const KEY1: u64 = 0xe121_19c4_114f_22a7; // = 0x4528_21e6_38d0_1377 ^ 0xa409_3822_299f_31d0;
 const KEY2: u32 = 0x60e5; //(0xbe54_66cf_34e9_0c6c ^ 0x082e_fa98_ec4e_6c89) & 63;
 
+/// Default seed of all hash functions, identical to the sourmash default
+pub const DEFAULT_SEED: u64 = 42;
+
+/// Serde default of the seed for sketches created before seeds were stored
+pub fn default_seed() -> u64 {
+    DEFAULT_SEED
+}
+
+// Xxhash and ahash are seeded relative to the default seed, the default seed keeps their original output
+
 // Standard xxhash function for all sizes
 #[inline]
-pub fn xxhash3(kmer: &[u8]) -> u64 {
-    xxhash_rust::xxh3::xxh3_64(kmer)
+pub fn xxhash3(kmer: &[u8], seed: u64) -> u64 {
+    xxhash_rust::xxh3::xxh3_64_with_seed(kmer, seed ^ DEFAULT_SEED)
 }
 
 // Standard xxhash function for all sizes
 #[inline]
-pub fn xxhash3_u64(kmer: u64) -> u64 {
-    xxhash_rust::xxh3::xxh3_64(&kmer.to_be_bytes())
+pub fn xxhash3_u64(kmer: u64, seed: u64) -> u64 {
+    xxhash3(&kmer.to_be_bytes(), seed)
+}
+
+// Standard xxhash function for 2-bit encoded kmers < 64
+#[inline]
+pub fn xxhash3_u128(kmer: u128, seed: u64) -> u64 {
+    xxhash3(&kmer.to_be_bytes(), seed)
+}
+
+const MULTIPLE: u64 = 6364136223846793005;
+
+// Seed of ahash mixed with the murmur3 finalizer, zero for the default seed
+#[inline]
+fn ahash_key(seed: u64) -> u64 {
+    let mut key = seed ^ DEFAULT_SEED;
+    key ^= key >> 33;
+    key = key.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    key ^= key >> 33;
+    key = key.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    key ^ (key >> 33)
 }
 
 // Specialized hash function for kmers < 32
 // Simplified version of ahash-fallback from the ahash crate
+// The seed changes the input, the (odd) multiplier and the rotation, a seed that only
+// changed the input would just relabel the kmers
 #[inline]
-pub fn ahash(kmer: u64) -> u64 {
-    let temp = (kmer ^ KEY1) as u128 * 6364136223846793005_u128;
+pub fn ahash(kmer: u64, seed: u64) -> u64 {
+    let key = ahash_key(seed);
+    let temp = (kmer ^ KEY1 ^ key) as u128 * (MULTIPLE ^ (key & !1)) as u128;
     let temp2 = ((temp & 0xffff_ffff_ffff_ffff) as u64) ^ ((temp >> 64) as u64); // XOR the lower 64 bits with the upper 64 bits.
-    temp2.rotate_left(KEY2)
+    temp2.rotate_left(KEY2 ^ (key >> 58) as u32)
 }
 
-// Faster version of murmur3 with equivalent output
+// Specialized hash function for kmers < 64, both halves are folded into one ahash
 #[inline]
-pub fn murmur3(kmer: &[u8]) -> u64 {
-    fastmurmur3::murmur3_x64_128(kmer, 42) as u64
+pub fn ahash_u128(kmer: u128, seed: u64) -> u64 {
+    ahash(ahash(kmer as u64, seed) ^ (kmer >> 64) as u64, seed)
 }
 
+// Ahash for byte encoded kmers, folds 8 byte chunks
 #[inline]
-pub fn murmur3_u64(kmer: u64) -> u64 {
-    fastmurmur3::murmur3_x64_128(&kmer.to_be_bytes(), 42) as u64
+pub fn ahash_bytes(kmer: &[u8], seed: u64) -> u64 {
+    kmer.chunks(8).fold(kmer.len() as u64, |hash, chunk| {
+        let mut bytes = [0; 8];
+        bytes[..chunk.len()].copy_from_slice(chunk);
+        ahash(hash ^ u64::from_be_bytes(bytes), seed)
+    })
 }
 
-/// Stores a function pointer to a hash function
-#[derive(Clone)]
-pub enum Function<'a> {
-    Large(&'a (dyn Fn(&[u8]) -> u64 + Send + Sync)),
-    Small(&'a (dyn Fn(u64) -> u64 + Send + Sync)),
+// Faster version of murmur3 with equivalent output, same seed as sourmash
+#[inline]
+pub fn murmur3(kmer: &[u8], seed: u64) -> u64 {
+    fastmurmur3::murmur3_x64_128(kmer, seed) as u64
 }
 
-impl Function<'_> {
-    pub fn get_large(&self) -> Option<&dyn Fn(&[u8]) -> u64> {
-        match self {
-            Function::Large(f) => Some(f),
-            _ => None,
+#[inline]
+pub fn murmur3_u64(kmer: u64, seed: u64) -> u64 {
+    murmur3(&kmer.to_be_bytes(), seed)
+}
+
+#[inline]
+pub fn murmur3_u128(kmer: u128, seed: u64) -> u64 {
+    murmur3(&kmer.to_be_bytes(), seed)
+}
+
+/// Encoding of the DNA kmers passed to a `KmerHasher`, amino acid kmers are always bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KmerEncoding {
+    Small, // 2-bit encoded u64 (k < 32)
+    Wide,  // 2-bit encoded u128 (32 <= k < 64)
+    Large, // Bytes of the canonical kmer
+}
+
+impl KmerEncoding {
+    /// Smallest 2-bit encoding of the kmer size, bytes if the kmer does not fit into 128 bits
+    pub fn bits(kmer_size: u8) -> Self {
+        match kmer_size {
+            0..32 => KmerEncoding::Small,
+            32..64 => KmerEncoding::Wide,
+            _ => KmerEncoding::Large,
         }
     }
-    pub fn get_small(&self) -> Option<&dyn Fn(u64) -> u64> {
+}
+
+/// Hash function for kmers, custom implementations are added with `register_hasher`
+/// and selected with `HashAlgorithms::Custom(name)`
+pub trait KmerHasher: Send + Sync {
+    /// Unique name, stored with the signatures
+    fn name(&self) -> &str;
+
+    /// Encoding of DNA kmers with the given size, bytes by default
+    fn encoding(&self, _kmer_size: u8) -> KmerEncoding {
+        KmerEncoding::Large
+    }
+
+    /// Hash of a 2-bit encoded kmer (k < 32)
+    fn hash_small(&self, kmer: u64, seed: u64) -> u64 {
+        self.hash_large(&kmer.to_be_bytes(), seed)
+    }
+
+    /// Hash of a 2-bit encoded kmer (32 <= k < 64)
+    fn hash_wide(&self, kmer: u128, seed: u64) -> u64 {
+        self.hash_large(&kmer.to_be_bytes(), seed)
+    }
+
+    /// Hash of a byte encoded kmer, independent sketches use different seeds
+    fn hash_large(&self, kmer: &[u8], seed: u64) -> u64;
+}
+
+/// Ahash for 2-bit encoded DNA kmers, xxhash for larger kmers and murmur3 for amino acids
+pub struct DefaultHasher;
+
+impl KmerHasher for DefaultHasher {
+    fn name(&self) -> &str {
+        "Default"
+    }
+    fn encoding(&self, kmer_size: u8) -> KmerEncoding {
+        KmerEncoding::bits(kmer_size)
+    }
+    fn hash_small(&self, kmer: u64, seed: u64) -> u64 {
+        ahash(kmer, seed)
+    }
+    fn hash_wide(&self, kmer: u128, seed: u64) -> u64 {
+        ahash_u128(kmer, seed)
+    }
+    fn hash_large(&self, kmer: &[u8], seed: u64) -> u64 {
+        xxhash3(kmer, seed)
+    }
+}
+
+pub struct AhashHasher;
+
+impl KmerHasher for AhashHasher {
+    fn name(&self) -> &str {
+        "Ahash"
+    }
+    fn encoding(&self, kmer_size: u8) -> KmerEncoding {
+        KmerEncoding::bits(kmer_size)
+    }
+    fn hash_small(&self, kmer: u64, seed: u64) -> u64 {
+        ahash(kmer, seed)
+    }
+    fn hash_wide(&self, kmer: u128, seed: u64) -> u64 {
+        ahash_u128(kmer, seed)
+    }
+    fn hash_large(&self, kmer: &[u8], seed: u64) -> u64 {
+        ahash_bytes(kmer, seed)
+    }
+}
+
+pub struct XxhashHasher;
+
+impl KmerHasher for XxhashHasher {
+    fn name(&self) -> &str {
+        "Xxhash"
+    }
+    fn encoding(&self, kmer_size: u8) -> KmerEncoding {
+        KmerEncoding::bits(kmer_size)
+    }
+    fn hash_small(&self, kmer: u64, seed: u64) -> u64 {
+        xxhash3_u64(kmer, seed)
+    }
+    fn hash_wide(&self, kmer: u128, seed: u64) -> u64 {
+        xxhash3_u128(kmer, seed)
+    }
+    fn hash_large(&self, kmer: &[u8], seed: u64) -> u64 {
+        xxhash3(kmer, seed)
+    }
+}
+
+/// Sourmash hashes the canonical kmer bytes, 2-bit encoded kmers would not match
+pub struct Murmur3Hasher;
+
+impl KmerHasher for Murmur3Hasher {
+    fn name(&self) -> &str {
+        "Murmur3"
+    }
+    fn hash_large(&self, kmer: &[u8], seed: u64) -> u64 {
+        murmur3(kmer, seed)
+    }
+}
+
+/// Hashers added by library users, the built-in algorithms are not part of the registry
+static REGISTRY: RwLock<Vec<Arc<dyn KmerHasher>>> = RwLock::new(Vec::new());
+
+/// Registers a custom hasher, names must be unique and differ from the built-in algorithms
+pub fn register_hasher(hasher: Arc<dyn KmerHasher>) -> Result<()> {
+    let name = hasher.name().to_string();
+    if BUILTIN_NAMES.contains(&name.as_str()) || registered_hasher(&name).is_some() {
+        return Err(anyhow!("Hash algorithm {} is already registered", name));
+    }
+    REGISTRY.write().expect("poisoned lock").push(hasher);
+    Ok(())
+}
+
+/// Custom hasher registered with the given name
+pub fn registered_hasher(name: &str) -> Option<Arc<dyn KmerHasher>> {
+    REGISTRY
+        .read()
+        .expect("poisoned lock")
+        .iter()
+        .find(|hasher| hasher.name() == name)
+        .cloned()
+}
+
+/// Names of the built-in algorithms, identical to the variant names of `HashAlgorithms`
+const BUILTIN_NAMES: [&str; 4] = ["Default", "Ahash", "Xxhash", "Murmur3"];
+
+impl HashAlgorithms {
+    /// Name of the algorithm, stored as `hash_function` in sourmash signatures
+    pub fn name(&self) -> &str {
         match self {
-            Function::Small(f) => Some(f),
-            _ => None,
+            HashAlgorithms::Default => "Default",
+            HashAlgorithms::Ahash => "Ahash",
+            HashAlgorithms::Xxhash => "Xxhash",
+            HashAlgorithms::Murmur3 => "Murmur3",
+            HashAlgorithms::Custom(name) => name,
         }
     }
 
-    pub fn from_alg(algo: HashAlgorithms, kmer_size: u8) -> Self {
-        if kmer_size < 32 {
-            match algo {
-                HashAlgorithms::Ahash => Function::Small(&ahash),
-                HashAlgorithms::Murmur3 => Function::Small(&murmur3_u64),
-                HashAlgorithms::Xxhash => Function::Small(&xxhash3_u64),
-                HashAlgorithms::Default => Function::Small(&ahash),
-            }
-        } else {
-            match algo {
-                HashAlgorithms::Murmur3 => Function::Large(&murmur3),
-                HashAlgorithms::Xxhash | HashAlgorithms::Default => Function::Large(&xxhash3),
-                _ => panic!("Hash function not supported for kmer size > 32"),
-            }
+    /// Inverse of `name`, custom algorithms must be registered
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "Default" => Some(HashAlgorithms::Default),
+            "Ahash" => Some(HashAlgorithms::Ahash),
+            "Xxhash" => Some(HashAlgorithms::Xxhash),
+            "Murmur3" => Some(HashAlgorithms::Murmur3),
+            _ => registered_hasher(name).map(|_| HashAlgorithms::Custom(name.to_string())),
         }
     }
+
+    /// The hasher of the algorithm, amino acid kmers are hashed with murmur3 by default (sourmash compatible)
+    pub fn hasher(&self, molecule: &Molecule) -> Result<Arc<dyn KmerHasher>> {
+        Ok(match self {
+            HashAlgorithms::Default if *molecule != Molecule::Dna => Arc::new(Murmur3Hasher),
+            HashAlgorithms::Default => Arc::new(DefaultHasher),
+            HashAlgorithms::Ahash => Arc::new(AhashHasher),
+            HashAlgorithms::Xxhash => Arc::new(XxhashHasher),
+            HashAlgorithms::Murmur3 => Arc::new(Murmur3Hasher),
+            HashAlgorithms::Custom(name) => registered_hasher(name).ok_or_else(|| {
+                anyhow!(
+                    "Unknown hash algorithm {}, custom hashers must be registered",
+                    name
+                )
+            })?,
+        })
+    }
 }
 
 #[cfg(test)]
@@ -82,56 +282,185 @@ mod tests {
 
     #[test]
     fn test_xxhash3() {
-        assert_eq!(xxhash3(b"AAAAAAAAAAA"), 0x92994E9987384EE2);
+        assert_eq!(xxhash3(b"AAAAAAAAAAA", DEFAULT_SEED), 0x92994E9987384EE2);
     }
 
     #[test]
     fn test_ahash() {
-        assert_eq!(ahash(0xAAAAAAAAAAAAAAA), 6369629604220809163);
+        assert_eq!(ahash(0xAAAAAAAAAAAAAAA, DEFAULT_SEED), 6369629604220809163);
     }
 
     #[test]
     fn test_murmur3() {
-        assert_eq!(murmur3(b"AAAAAAAAAAA"), 7773142420371383521);
+        assert_eq!(murmur3(b"AAAAAAAAAAA", DEFAULT_SEED), 7773142420371383521);
     }
 
     #[test]
     fn test_xxhash3_u64() {
-        assert_eq!(xxhash3_u64(0xAAAAAAAAAAAAAAA), 5855080426738543665);
+        assert_eq!(
+            xxhash3_u64(0xAAAAAAAAAAAAAAA, DEFAULT_SEED),
+            5855080426738543665
+        );
     }
 
     #[test]
     fn test_murmur3_u64() {
-        assert_eq!(murmur3_u64(0xAAAAAAAAAAAAAAA), 442865051503200633);
+        assert_eq!(
+            murmur3_u64(0xAAAAAAAAAAAAAAA, DEFAULT_SEED),
+            442865051503200633
+        );
+    }
+
+    #[test]
+    fn test_u128() {
+        assert_eq!(
+            xxhash3_u128(0xAAAAAAAAAAAAAAA, DEFAULT_SEED),
+            12330961484905389358
+        );
+        assert_eq!(
+            murmur3_u128(0xAAAAAAAAAAAAAAA, DEFAULT_SEED),
+            14872811094495216901
+        );
+        assert_eq!(
+            ahash_u128(0xAAAAAAAAAAAAAAA, DEFAULT_SEED),
+            10703720056021316698
+        );
+        assert_ne!(
+            ahash_u128(0xAAAAAAAAAAAAAAA, DEFAULT_SEED),
+            ahash_u128(0xAAAAAAAAAAAAAAA << 64, DEFAULT_SEED)
+        );
+    }
+
+    #[test]
+    fn test_seed() {
+        // Every function produces independent hashes for other seeds
+        assert_ne!(
+            xxhash3(b"AAAAAAAAAAA", 0),
+            xxhash3(b"AAAAAAAAAAA", DEFAULT_SEED)
+        );
+        assert_ne!(
+            ahash(0xAAAAAAAAAAAAAAA, 0),
+            ahash(0xAAAAAAAAAAAAAAA, DEFAULT_SEED)
+        );
+        assert_ne!(
+            murmur3(b"AAAAAAAAAAA", 0),
+            murmur3(b"AAAAAAAAAAA", DEFAULT_SEED)
+        );
+        assert_ne!(
+            ahash_bytes(b"AAAAAAAAAAA", 7),
+            ahash_bytes(b"AAAAAAAAAAA", DEFAULT_SEED)
+        );
+    }
+
+    #[test]
+    fn test_seed_independent() {
+        // Kmers of the bottom-k sketch of all 8-mers
+        let bottom_k = |hash: &dyn Fn(u64) -> u64| {
+            let mut kmers = (0..1u64 << 16).collect::<Vec<_>>();
+            kmers.sort_by_key(|kmer| hash(*kmer));
+            kmers.truncate(64);
+            kmers.sort();
+            kmers
+        };
+        let hash_functions: [&dyn Fn(u64, u64) -> u64; 3] = [
+            &ahash,
+            &|kmer, seed| ahash_u128(kmer as u128, seed),
+            &|kmer, seed| ahash_bytes(&kmer.to_be_bytes(), seed),
+        ];
+        for hash in hash_functions {
+            let default = bottom_k(&|kmer| hash(kmer, DEFAULT_SEED));
+            let seeded = bottom_k(&|kmer| hash(kmer, 7));
+            // No XOR of the kmers maps one sketch onto the other
+            for kmer in seeded.iter() {
+                let mut relabeled = default
+                    .iter()
+                    .map(|k| k ^ default[0] ^ kmer)
+                    .collect::<Vec<_>>();
+                relabeled.sort();
+                assert_ne!(relabeled, seeded);
+            }
+        }
     }
 
     #[test]
     fn function_test() {
-        let f = Function::from_alg(HashAlgorithms::Ahash, 21);
+        let hasher = |algorithm: HashAlgorithms| algorithm.hasher(&Molecule::Dna).unwrap();
+        let f = hasher(HashAlgorithms::Ahash);
+        assert_eq!(f.encoding(21), KmerEncoding::Small);
         assert_eq!(
-            f.get_small().unwrap()(0xAAAAAAAAAAAAAAA),
+            f.hash_small(0xAAAAAAAAAAAAAAA, DEFAULT_SEED),
             6369629604220809163
         );
-        let f = Function::from_alg(HashAlgorithms::Murmur3, 21);
+        let f = hasher(HashAlgorithms::Murmur3);
+        assert_eq!(f.encoding(21), KmerEncoding::Large);
         assert_eq!(
-            f.get_small().unwrap()(0xAAAAAAAAAAAAAAA),
-            442865051503200633
+            f.hash_large(b"AAAAAAAAAAA", DEFAULT_SEED),
+            7773142420371383521
         );
-        let f = Function::from_alg(HashAlgorithms::Xxhash, 21);
+        let f = hasher(HashAlgorithms::Xxhash);
         assert_eq!(
-            f.get_small().unwrap()(0xAAAAAAAAAAAAAAA),
+            f.hash_small(0xAAAAAAAAAAAAAAA, DEFAULT_SEED),
             5855080426738543665
         );
-        let f = Function::from_alg(HashAlgorithms::Default, 21);
+        let f = hasher(HashAlgorithms::Default);
         assert_eq!(
-            f.get_small().unwrap()(0xAAAAAAAAAAAAAAA),
+            f.hash_small(0xAAAAAAAAAAAAAAA, DEFAULT_SEED),
             6369629604220809163
         );
-        let f = Function::from_alg(HashAlgorithms::Murmur3, 32);
-        assert_eq!(f.get_large().unwrap()(b"AAAAAAAAAAA"), 7773142420371383521);
-        let f = Function::from_alg(HashAlgorithms::Xxhash, 32);
-        assert_eq!(f.get_large().unwrap()(b"AAAAAAAAAAA"), 10563560822279786210);
-        let f = Function::from_alg(HashAlgorithms::Default, 32);
-        assert_eq!(f.get_large().unwrap()(b"AAAAAAAAAAA"), 10563560822279786210);
+        assert_eq!(
+            hasher(HashAlgorithms::Murmur3).encoding(32),
+            KmerEncoding::Large
+        );
+        let f = hasher(HashAlgorithms::Xxhash);
+        assert_eq!(f.encoding(32), KmerEncoding::Wide);
+        assert_eq!(
+            f.hash_wide(0xAAAAAAAAAAAAAAA, DEFAULT_SEED),
+            xxhash3_u128(0xAAAAAAAAAAAAAAA, DEFAULT_SEED)
+        );
+        let f = hasher(HashAlgorithms::Default);
+        assert_eq!(f.encoding(63), KmerEncoding::Wide);
+        assert_eq!(
+            f.hash_wide(0xAAAAAAAAAAAAAAA, DEFAULT_SEED),
+            ahash_u128(0xAAAAAAAAAAAAAAA, DEFAULT_SEED)
+        );
+        assert_eq!(f.encoding(64), KmerEncoding::Large);
+        assert_eq!(
+            f.hash_large(b"AAAAAAAAAAA", DEFAULT_SEED),
+            10563560822279786210
+        );
+        assert_eq!(
+            hasher(HashAlgorithms::Xxhash).hash_large(b"AAAAAAAAAAA", DEFAULT_SEED),
+            10563560822279786210
+        );
+        let f = HashAlgorithms::Default.hasher(&Molecule::Protein).unwrap();
+        assert_eq!(
+            f.hash_large(b"AAAAAAAAAAA", DEFAULT_SEED),
+            7773142420371383521
+        );
+    }
+
+    #[test]
+    fn test_custom_hasher() {
+        struct Identity;
+        impl KmerHasher for Identity {
+            fn name(&self) -> &str {
+                "identity"
+            }
+            fn hash_large(&self, kmer: &[u8], _seed: u64) -> u64 {
+                kmer.len() as u64
+            }
+        }
+        let algorithm = HashAlgorithms::Custom("identity".to_string());
+        assert!(algorithm.hasher(&Molecule::Dna).is_err());
+        assert_eq!(HashAlgorithms::from_name("identity"), None);
+
+        register_hasher(Arc::new(Identity)).unwrap();
+        assert!(register_hasher(Arc::new(Identity)).is_err());
+        assert!(register_hasher(Arc::new(DefaultHasher)).is_err());
+        let hasher = algorithm.hasher(&Molecule::Dna).unwrap();
+        assert_eq!(hasher.encoding(21), KmerEncoding::Large);
+        // 2-bit encoded kmers are hashed as bytes by default
+        assert_eq!(hasher.hash_small(1, DEFAULT_SEED), 8);
+        assert_eq!(HashAlgorithms::from_name("identity"), Some(algorithm));
     }
 }
@@ -1,17 +1,28 @@
-use std::path::PathBuf;
+use std::{collections::BTreeMap, path::PathBuf};
 
 use byteorder::BigEndian;
 use heed::{
-    types::{SerdeBincode, U32, U64},
+    types::{SerdeBincode, SerdeJson, Str, U32, U64},
     DatabaseFlags, EnvFlags,
 };
 
-use crate::file_io::ShortSketchInfo;
+use crate::{
+    cli::{HashAlgorithms, Molecule},
+    file_io::{DatabaseMeta, ShortSketchInfoCodec, META_KEY},
+    hash_functions::DEFAULT_SEED,
+    sampling::SamplingScheme,
+    signature::{fscale_to_max_hash, Scaling, Signature},
+    sketch::Sketch,
+};
 
 pub struct HeedHandler {
-    heed_env: heed::Env,
-    signatures: heed::Database<U32<BigEndian>, SerdeBincode<ShortSketchInfo>>,
-    hashes: heed::Database<U64<BigEndian>, U32<BigEndian>>,
+    pub(crate) heed_env: heed::Env,
+    pub(crate) signatures: heed::Database<U32<BigEndian>, ShortSketchInfoCodec>,
+    pub(crate) hashes: heed::Database<U64<BigEndian>, U32<BigEndian>>,
+    // Databases created before the header was introduced have no meta database
+    pub(crate) meta: Option<heed::Database<Str, SerdeJson<DatabaseMeta>>>,
+    // Per sketch abundances in the order of the sorted hashes
+    pub(crate) abundances: Option<heed::Database<U32<BigEndian>, SerdeBincode<Vec<u64>>>>,
 }
 
 impl HeedHandler {
@@ -20,7 +31,7 @@ impl HeedHandler {
             unsafe {
                 heed::EnvOpenOptions::new()
                     .map_size(10 * 1024 * 1024 * 1024 * 1024)
-                    .max_dbs(2)
+                    .max_dbs(4)
                     .flags(EnvFlags::READ_ONLY)
                     .open(path.clone())?
             }
@@ -28,7 +39,7 @@ impl HeedHandler {
             unsafe {
                 heed::EnvOpenOptions::new()
                     .map_size(10 * 1024 * 1024 * 1024 * 1024)
-                    .max_dbs(2)
+                    .max_dbs(4)
                     .flags(EnvFlags::READ_ONLY | EnvFlags::NO_SUB_DIR)
                     .open(path.clone())?
             }
@@ -37,7 +48,7 @@ impl HeedHandler {
         let rtxn = heed_env.read_txn()?;
 
         let sigs_db = heed_env
-            .open_database::<U32<BigEndian>, SerdeBincode<ShortSketchInfo>>(&rtxn, Some("sigs"))?
+            .open_database::<U32<BigEndian>, ShortSketchInfoCodec>(&rtxn, Some("sigs"))?
             .ok_or_else(|| anyhow::anyhow!("Unable to open signatures database"))?;
         let hashes = heed_env
             .database_options()
@@ -46,15 +57,132 @@ impl HeedHandler {
             .flags(DatabaseFlags::DUP_SORT)
             .open(&rtxn)?
             .ok_or_else(|| anyhow::anyhow!("Unable to open signatures database"))?;
+        let meta = heed_env.open_database::<Str, SerdeJson<DatabaseMeta>>(&rtxn, Some("meta"))?;
+        let abundances = heed_env
+            .open_database::<U32<BigEndian>, SerdeBincode<Vec<u64>>>(&rtxn, Some("abundances"))?;
         rtxn.commit()?;
         Ok(HeedHandler {
             heed_env,
             signatures: sigs_db,
             hashes,
+            meta,
+            abundances,
         })
     }
 
+    pub(crate) fn get_abundances(
+        &self,
+        rtxn: &heed::RoTxn,
+        idx: u32,
+    ) -> anyhow::Result<Option<Vec<u64>>> {
+        match self.abundances {
+            Some(abundances) => Ok(abundances.get(rtxn, &idx)?),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_meta(&self) -> anyhow::Result<Option<DatabaseMeta>> {
+        let Some(meta) = self.meta else {
+            return Ok(None);
+        };
+        let rtxn = self.heed_env.read_txn()?;
+        Ok(meta.get(&rtxn, META_KEY)?)
+    }
+
+    /// Reconstructs one signature per stored sketch from the inverted hashes table
+    pub fn load_signatures(&self) -> anyhow::Result<Vec<Signature>> {
+        let meta = self.get_meta()?;
+        let rtxn = self.heed_env.read_txn()?;
+
+        let mut sketches = BTreeMap::new();
+        let mut fscale = None;
+        for sig in self.signatures.iter(&rtxn)? {
+            let (idx, info) = sig?;
+            fscale = info.fscale;
+            let mut sketch = Sketch::new(info.file_name, info.num_hashes, info.kmer_size);
+            sketch.stats = info.stats;
+            sketch.max_hash = info.max_hash;
+            sketches.insert(idx, sketch);
+        }
+        match meta.as_ref() {
+            Some(meta) => meta.check_kmer_encoding()?,
+            None => {
+                if let Some(sketch) = sketches.values().next() {
+                    DatabaseMeta::legacy(sketch.kmer_size, fscale).check_kmer_encoding()?;
+                }
+            }
+        }
+        for hash in self.hashes.iter(&rtxn)? {
+            let (hash, idx) = hash?;
+            sketches
+                .get_mut(&idx)
+                .ok_or_else(|| anyhow::anyhow!("Hash references unknown signature {}", idx))?
+                .hashes
+                .insert(hash);
+        }
+        for (idx, sketch) in sketches.iter_mut() {
+            sketch.abundances = self.get_abundances(&rtxn, *idx)?;
+        }
+
+        // Databases without header were sketched with the default algorithm
+        let (algorithm, fscale, molecule, sampling, scaling, seed) = match meta {
+            Some(meta) => (
+                meta.algorithm,
+                meta.fscale,
+                meta.molecule,
+                meta.sampling,
+                Scaling {
+                    nmin: meta.nmin,
+                    nmax: meta.nmax,
+                    kscale: meta.kscale,
+                },
+                meta.seed,
+            ),
+            None => (
+                HashAlgorithms::Default,
+                fscale,
+                Molecule::Dna,
+                SamplingScheme::All,
+                Scaling::default(),
+                DEFAULT_SEED,
+            ),
+        };
+        let max_hash = fscale_to_max_hash(fscale);
+        Ok(sketches
+            .into_values()
+            .map(|mut sketch| {
+                // Sketches of older databases contain all hashes below the max hash
+                if sketch.max_hash.is_none() && max_hash < u64::MAX {
+                    sketch.max_hash = Some(max_hash);
+                }
+                Signature {
+                    file_name: sketch.name.clone(),
+                    algorithm: algorithm.clone(),
+                    kmer_size: sketch.kmer_size,
+                    max_hash,
+                    molecule: molecule.clone(),
+                    sketches: vec![sketch],
+                    scaling,
+                    sampling,
+                    seed,
+                }
+            })
+            .collect())
+    }
+
     pub fn summarize_stats(&self) -> anyhow::Result<()> {
+        if let Some(meta) = self.get_meta()? {
+            println!("Algorithm: {:?}", meta.algorithm);
+            println!("Molecule: {:?}", meta.molecule);
+            println!("Kmer size: {}", meta.kmer_size);
+            println!("Fscale: {:?}", meta.fscale);
+            println!("Sampling: {:?}", meta.sampling);
+            println!("Seed: {}", meta.seed);
+            println!("Nmax: {:?}", meta.nmax);
+            println!("Singleton: {}", meta.singleton);
+            println!("Created: {}", meta.created);
+            println!("Jam version: {}", meta.jam_version);
+        }
         let rtxn = self.heed_env.read_txn()?;
         let num_of_sigs = self.signatures.len(&rtxn)?;
         println!("Number of signatures: {}", num_of_sigs);
@@ -65,11 +193,31 @@ impl HeedHandler {
 
     pub fn detail_sigs(&self) -> anyhow::Result<()> {
         let rtxn = self.heed_env.read_txn()?;
-        for (_, value) in self.signatures.iter(&rtxn)?.enumerate() {
+        for value in self.signatures.iter(&rtxn)? {
             let (_, value) = value?;
+            // Length, GC content (%) and N count are empty without stats
+            let stats = value
+                .stats
+                .map(|stats| {
+                    format!(
+                        "{},{},{}",
+                        stats.length,
+                        stats
+                            .gc_content()
+                            .map(|gc| format!("{:.2}", gc))
+                            .unwrap_or_default(),
+                        stats.n_count
+                    )
+                })
+                .unwrap_or_else(|| ",,".to_string());
             println!(
-                "{},{:?},{},{}",
-                value.file_name, value.fscale, value.kmer_size, value.num_hashes
+                "{},{:?},{},{},{},{}",
+                value.file_name,
+                value.fscale,
+                value.kmer_size,
+                value.num_hashes,
+                value.lineage.unwrap_or_default(),
+                stats
             );
         }
         Ok(())
@@ -1,11 +1,14 @@
 pub mod cli;
+pub mod cluster;
 pub mod compare;
 pub mod file_io;
 pub mod hash_functions;
 mod hasher;
 pub mod heed;
 //mod heed_codec;
+pub mod sampling;
 pub mod signature;
 mod sketch;
 pub mod sketcher;
+pub mod taxonomy;
 mod varintencoding;
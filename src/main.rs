@@ -4,8 +4,10 @@ use clap::{error::ErrorKind, CommandFactory, Parser};
 use indicatif::ProgressIterator;
 use jam_rs::{
     cli::{Cli, Commands},
-    hash_functions::ahash,
+    compare::LmdbComparator,
+    file_io::DatabaseMeta,
     heed::HeedHandler,
+    sampling::SamplingScheme,
 };
 
 fn main() {
@@ -21,21 +23,42 @@ fn main() {
                 }
             }
         }
-        // Commands::Merge { inputs, output } => {
-        //     match jam_rs::file_io::FileHandler::concat(inputs, output) {
-        //         Ok(_) => {}
-        //         Err(e) => {
-        //             Cli::command().error(ErrorKind::ArgumentConflict, e).exit();
-        //         }
-        //     }
-        // }
+        Commands::Merge { inputs, output } => {
+            match jam_rs::file_io::FileHandler::concat(inputs, output) {
+                Ok(_) => {}
+                Err(e) => {
+                    Cli::command().error(ErrorKind::ArgumentConflict, e).exit();
+                }
+            }
+        }
         Commands::Dist {
             input,
             database,
             output,
             cutoff,
+            max_pvalue,
+            output_format,
+            top_n,
+            best_hit,
+            window,
+            step,
+            gc_lower,
+            gc_upper,
         } => {
             let mut cmd = Cli::command();
+            if window == Some(0) || step == Some(0) {
+                cmd.error(
+                    ErrorKind::ArgumentConflict,
+                    "Window size and step must be greater than 0",
+                )
+                .exit();
+            }
+            let filter = jam_rs::compare::ResultFilter {
+                top_n: if best_hit { Some(1) } else { top_n },
+                max_pvalue,
+                gc_lower,
+                gc_upper,
+            };
 
             let input_files =
                 jam_rs::file_io::FileHandler::test_and_collect_files(vec![input], false);
@@ -49,42 +72,50 @@ fn main() {
             if database.len() == 1 {
                 let mut lmdb = false;
                 if let Some(first) = database.first() {
-                    if first.is_file() {
-                        if first.extension() == Some("mdb".as_ref()) {
-                            lmdb = true;
-                        }
+                    if jam_rs::file_io::test_lmdb(first) {
+                        lmdb = true;
                     }
                     if lmdb {
-                        let mut lmdb_comparator = jam_rs::compare::LmdbComparator::new(
+                        let mut lmdb_comparator = match jam_rs::compare::LmdbComparator::new(
                             first.clone(),
                             args.threads.unwrap_or(1),
                             cutoff,
-                            args.silent
-                        )
-                        .unwrap();
+                            args.silent,
+                        ) {
+                            Ok(c) => c,
+                            Err(e) => {
+                                cmd.error(ErrorKind::ArgumentConflict, e).exit();
+                            }
+                        };
 
                         let mut input_sketch = Vec::new();
 
-                        let iterator:Box<dyn Iterator<Item = PathBuf>> = if args.silent {
+                        let iterator: Box<dyn Iterator<Item = PathBuf>> = if args.silent {
                             Box::new(fs_input.into_iter())
                         } else {
                             Box::new(fs_input.into_iter().progress())
                         };
 
-                        for db_path in iterator {
-                            // TODO: Remove hardcoded kmer sizes / settings / parse from db
-                            match jam_rs::file_io::FileHandler::sketch_file(
-                                &db_path,
+                        let settings = DatabaseMeta {
+                            molecule: lmdb_comparator.molecule.clone(),
+                            sampling: lmdb_comparator.sampling,
+                            seed: lmdb_comparator.seed,
+                            window,
+                            step,
+                            ..DatabaseMeta::new(
+                                lmdb_comparator.algorithm.clone(),
                                 lmdb_comparator.kmer_size,
                                 lmdb_comparator.fscale,
                                 None,
                                 false,
-                                jam_rs::hash_functions::Function::Small(&ahash),
-                                jam_rs::cli::HashAlgorithms::Ahash,
-                                false,
+                            )
+                        };
+                        for db_path in iterator {
+                            match jam_rs::file_io::FileHandler::load_or_sketch_file(
+                                &db_path, &settings,
                             ) {
                                 Ok(r) => {
-                                    input_sketch.push(r);
+                                    input_sketch.extend(r);
                                 }
                                 Err(e) => {
                                     cmd.error(ErrorKind::ArgumentConflict, e).exit();
@@ -93,6 +124,7 @@ fn main() {
                         }
 
                         lmdb_comparator.set_signatures(input_sketch);
+                        lmdb_comparator.set_filter(filter);
 
                         let mut result = match lmdb_comparator.compare() {
                             Ok(r) => r,
@@ -101,50 +133,22 @@ fn main() {
                             }
                         };
 
-                        result.sort_by(|a, b| b.estimated_containment.total_cmp(&a.estimated_containment));
+                        result.sort_by(|a, b| {
+                            b.estimated_containment.total_cmp(&a.estimated_containment)
+                        });
 
-                        match output {
-                            Some(o) => {
-                                if let Err(e) =
-                                    jam_rs::file_io::FileHandler::write_result(&result, o)
-                                {
-                                    cmd.error(ErrorKind::ArgumentConflict, e).exit();
-                                }
-                            }
-                            None => {
-                                for result in result {
-                                    println!("{}", result);
-                                }
-                            }
+                        if let Err(e) = jam_rs::file_io::FileHandler::write_results(
+                            &result,
+                            &output_format,
+                            output,
+                        ) {
+                            cmd.error(ErrorKind::ArgumentConflict, e).exit();
                         }
                         return;
                     }
                 }
             };
 
-            let mut input_sketch = Vec::new();
-            eprintln!("Reading input sketches");
-            for db_path in fs_input {
-                // TODO: Remove hardcoded kmer sizes / settings / parse from db
-                match jam_rs::file_io::FileHandler::sketch_file(
-                    &db_path,
-                    21,
-                    None,
-                    None,
-                    false,
-                    jam_rs::hash_functions::Function::Small(&ahash),
-                    jam_rs::cli::HashAlgorithms::Ahash,
-                    false,
-                ) {
-                    Ok(r) => {
-                        input_sketch.push(r);
-                    }
-                    Err(e) => {
-                        cmd.error(ErrorKind::ArgumentConflict, e).exit();
-                    }
-                }
-            }
-
             let database_files =
                 jam_rs::file_io::FileHandler::test_and_collect_files(database, false);
             let fs = match database_files {
@@ -166,6 +170,31 @@ fn main() {
                 }
             }
 
+            // Inputs must be sketched with the same settings as the database
+            let settings = match DatabaseMeta::from_signatures(&db_sketches) {
+                Ok(settings) => DatabaseMeta {
+                    window,
+                    step,
+                    ..settings
+                },
+                Err(e) => {
+                    cmd.error(ErrorKind::ArgumentConflict, e).exit();
+                }
+            };
+
+            let mut input_sketch = Vec::new();
+            eprintln!("Reading input sketches");
+            for db_path in fs_input {
+                match jam_rs::file_io::FileHandler::load_or_sketch_file(&db_path, &settings) {
+                    Ok(r) => {
+                        input_sketch.extend(r);
+                    }
+                    Err(e) => {
+                        cmd.error(ErrorKind::ArgumentConflict, e).exit();
+                    }
+                }
+            }
+
             match jam_rs::compare::MultiComp::new(
                 input_sketch,
                 db_sketches,
@@ -173,21 +202,15 @@ fn main() {
                 cutoff,
             ) {
                 Ok(mut mc) => {
+                    mc.set_filter(filter);
                     if let Err(e) = mc.compare() {
                         cmd.error(ErrorKind::ArgumentConflict, e).exit();
                     }
                     let result = mc.finalize();
-                    match output {
-                        Some(o) => {
-                            if let Err(e) = jam_rs::file_io::FileHandler::write_result(&result, o) {
-                                cmd.error(ErrorKind::ArgumentConflict, e).exit();
-                            }
-                        }
-                        None => {
-                            for result in result {
-                                println!("{}", result);
-                            }
-                        }
+                    if let Err(e) =
+                        jam_rs::file_io::FileHandler::write_results(&result, &output_format, output)
+                    {
+                        cmd.error(ErrorKind::ArgumentConflict, e).exit();
                     }
                 }
                 Err(e) => {
@@ -195,6 +218,218 @@ fn main() {
                 }
             }
         }
+        Commands::Compare {
+            input,
+            output,
+            metric,
+            format,
+            kmer_size,
+            fscale,
+            algorithm,
+            molecule,
+            sampling,
+            smer_size,
+            minimizer_window,
+            seed,
+        } => {
+            let mut cmd = Cli::command();
+
+            let files = match jam_rs::file_io::FileHandler::test_and_collect_files(input, false) {
+                Ok(f) => f,
+                Err(e) => {
+                    cmd.error(ErrorKind::ArgumentConflict, e).exit();
+                }
+            };
+            let sampling = match SamplingScheme::new(&sampling, smer_size, minimizer_window) {
+                Ok(s) => s,
+                Err(e) => {
+                    cmd.error(ErrorKind::ArgumentConflict, e).exit();
+                }
+            };
+            if let Err(e) = sampling.check_kmer_size(kmer_size) {
+                cmd.error(ErrorKind::ArgumentConflict, e).exit();
+            }
+            let default = DatabaseMeta {
+                molecule,
+                sampling,
+                seed,
+                ..DatabaseMeta::new(algorithm, kmer_size, fscale, None, false)
+            };
+            let signatures =
+                match jam_rs::file_io::FileHandler::load_or_sketch_files(&files, &default) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        cmd.error(ErrorKind::ArgumentConflict, e).exit();
+                    }
+                };
+            let matrix = match jam_rs::compare::SimilarityMatrix::new(
+                signatures,
+                &metric,
+                args.threads.unwrap_or(1),
+            ) {
+                Ok(m) => m,
+                Err(e) => {
+                    cmd.error(ErrorKind::ArgumentConflict, e).exit();
+                }
+            };
+            if let Err(e) = jam_rs::file_io::FileHandler::write_matrix(&matrix, &format, output) {
+                cmd.error(ErrorKind::ArgumentConflict, e).exit();
+            }
+        }
+        Commands::Cluster {
+            input,
+            output,
+            ani,
+            method,
+            dereplicated,
+            kmer_size,
+            fscale,
+            algorithm,
+            molecule,
+            sampling,
+            smer_size,
+            minimizer_window,
+            seed,
+        } => {
+            let mut cmd = Cli::command();
+
+            let files = match jam_rs::file_io::FileHandler::test_and_collect_files(input, false) {
+                Ok(f) => f,
+                Err(e) => {
+                    cmd.error(ErrorKind::ArgumentConflict, e).exit();
+                }
+            };
+            let sampling = match SamplingScheme::new(&sampling, smer_size, minimizer_window) {
+                Ok(s) => s,
+                Err(e) => {
+                    cmd.error(ErrorKind::ArgumentConflict, e).exit();
+                }
+            };
+            if let Err(e) = sampling.check_kmer_size(kmer_size) {
+                cmd.error(ErrorKind::ArgumentConflict, e).exit();
+            }
+            let default = DatabaseMeta {
+                molecule,
+                sampling,
+                seed,
+                ..DatabaseMeta::new(algorithm, kmer_size, fscale, None, false)
+            };
+            let signatures =
+                match jam_rs::file_io::FileHandler::load_or_sketch_files(&files, &default) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        cmd.error(ErrorKind::ArgumentConflict, e).exit();
+                    }
+                };
+            let clusters = match jam_rs::cluster::Clusters::new(
+                signatures,
+                ani,
+                &method,
+                args.threads.unwrap_or(1),
+            ) {
+                Ok(c) => c,
+                Err(e) => {
+                    cmd.error(ErrorKind::ArgumentConflict, e).exit();
+                }
+            };
+            if !args.silent {
+                eprintln!(
+                    "{} clusters for {} inputs",
+                    clusters.num_clusters(),
+                    clusters.members.len()
+                );
+            }
+
+            if let Some(dereplicated) = dereplicated {
+                if let Err(e) = jam_rs::file_io::FileHandler::write_lmdb(
+                    clusters.representatives(),
+                    dereplicated,
+                ) {
+                    cmd.error(ErrorKind::ArgumentConflict, e).exit();
+                }
+            }
+            match output {
+                Some(o) => {
+                    if let Err(e) = jam_rs::file_io::FileHandler::write_result(&clusters.members, o)
+                    {
+                        cmd.error(ErrorKind::ArgumentConflict, e).exit();
+                    }
+                }
+                None => {
+                    for member in clusters.members {
+                        println!("{}", member);
+                    }
+                }
+            }
+        }
+        Commands::Gather {
+            input,
+            database,
+            output,
+            min_overlap,
+            output_format,
+        } => {
+            let mut cmd = Cli::command();
+
+            let lmdb_comparator =
+                match open_lmdb_comparator(input, database, args.threads, args.silent) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        cmd.error(ErrorKind::ArgumentConflict, e).exit();
+                    }
+                };
+
+            let mut result =
+                match jam_rs::compare::Gather::new(&lmdb_comparator, min_overlap).gather() {
+                    Ok(r) => r,
+                    Err(e) => {
+                        cmd.error(ErrorKind::ArgumentConflict, e).exit();
+                    }
+                };
+            result.sort_by(|a, b| a.query_name.cmp(&b.query_name).then(a.rank.cmp(&b.rank)));
+
+            if let Err(e) =
+                jam_rs::file_io::FileHandler::write_results(&result, &output_format, output)
+            {
+                cmd.error(ErrorKind::ArgumentConflict, e).exit();
+            }
+        }
+        Commands::Classify {
+            input,
+            database,
+            output,
+            min_fraction,
+            per_rank,
+            output_format,
+        } => {
+            let mut cmd = Cli::command();
+
+            let lmdb_comparator =
+                match open_lmdb_comparator(input, database, args.threads, args.silent) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        cmd.error(ErrorKind::ArgumentConflict, e).exit();
+                    }
+                };
+            let classifier = jam_rs::taxonomy::Classifier::new(&lmdb_comparator, min_fraction);
+            let result = if per_rank {
+                classifier.summarize()
+            } else {
+                classifier.classify()
+            };
+            let result = match result {
+                Ok(r) => r,
+                Err(e) => {
+                    cmd.error(ErrorKind::ArgumentConflict, e).exit();
+                }
+            };
+
+            if let Err(e) =
+                jam_rs::file_io::FileHandler::write_results(&result, &output_format, output)
+            {
+                cmd.error(ErrorKind::ArgumentConflict, e).exit();
+            }
+        }
         Commands::Stats { input, short } => {
             let mut cmd = Cli::command();
 
@@ -223,3 +458,41 @@ fn main() {
         }
     }
 }
+
+/// Opens a single lmdb database and loads (or sketches) the input with the database settings
+fn open_lmdb_comparator(
+    input: PathBuf,
+    database: PathBuf,
+    threads: Option<usize>,
+    silent: bool,
+) -> anyhow::Result<LmdbComparator> {
+    let fs_input = jam_rs::file_io::FileHandler::test_and_collect_files(vec![input], false)?;
+    if !jam_rs::file_io::test_lmdb(&database) {
+        return Err(anyhow::anyhow!(
+            "Database {:?} is no lmdb database",
+            database
+        ));
+    }
+    let mut lmdb_comparator = LmdbComparator::new(database, threads.unwrap_or(1), 0.0, silent)?;
+
+    let settings = DatabaseMeta {
+        molecule: lmdb_comparator.molecule.clone(),
+        sampling: lmdb_comparator.sampling,
+        seed: lmdb_comparator.seed,
+        ..DatabaseMeta::new(
+            lmdb_comparator.algorithm.clone(),
+            lmdb_comparator.kmer_size,
+            lmdb_comparator.fscale,
+            None,
+            false,
+        )
+    };
+    let mut input_sketch = Vec::new();
+    for db_path in fs_input {
+        input_sketch.extend(jam_rs::file_io::FileHandler::load_or_sketch_file(
+            &db_path, &settings,
+        )?);
+    }
+    lmdb_comparator.set_signatures(input_sketch);
+    Ok(lmdb_comparator)
+}
@@ -0,0 +1,254 @@
+//! Kmer selection schemes applied before the hashes are scaled
+//!
+//! Syncmers select kmers by their content (the position of their smallest s-mer),
+//! minimizers the kmer with the smallest hash of `window` consecutive kmers.
+//! Both are robust to small shifts of the sequence and spread the samples more evenly.
+use crate::cli::Sampling;
+use crate::hash_functions::{xxhash3, xxhash3_u128, xxhash3_u64};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", tag = "scheme")]
+pub enum SamplingScheme {
+    #[default]
+    All, // Every kmer is hashed
+    OpenSyncmer {
+        smer_size: u8,
+    }, // Smallest s-mer at the middle position (k - s) / 2
+    ClosedSyncmer {
+        smer_size: u8,
+    }, // Smallest s-mer at the first or last position
+    Minimizer {
+        window: u8,
+    }, // Smallest hash of `window` consecutive kmers
+}
+
+impl SamplingScheme {
+    pub fn new(sampling: &Sampling, smer_size: Option<u8>, window: Option<u8>) -> Result<Self> {
+        let scheme = match sampling {
+            Sampling::All => SamplingScheme::All,
+            Sampling::OpenSyncmer | Sampling::ClosedSyncmer => {
+                let smer_size =
+                    smer_size.ok_or_else(|| anyhow!("Syncmers require a s-mer size"))?;
+                if smer_size == 0 {
+                    return Err(anyhow!("S-mer size must be greater than 0"));
+                }
+                if *sampling == Sampling::OpenSyncmer {
+                    SamplingScheme::OpenSyncmer { smer_size }
+                } else {
+                    SamplingScheme::ClosedSyncmer { smer_size }
+                }
+            }
+            Sampling::Minimizer => {
+                let window =
+                    window.ok_or_else(|| anyhow!("Minimizers require a minimizer window"))?;
+                if window == 0 {
+                    return Err(anyhow!("Minimizer window must be greater than 0"));
+                }
+                SamplingScheme::Minimizer { window }
+            }
+        };
+        Ok(scheme)
+    }
+
+    pub fn is_all(&self) -> bool {
+        *self == SamplingScheme::All
+    }
+
+    /// S-mers must be smaller than the kmers they are selected from
+    pub fn check_kmer_size(&self, kmer_size: u8) -> Result<()> {
+        match self {
+            SamplingScheme::OpenSyncmer { smer_size }
+            | SamplingScheme::ClosedSyncmer { smer_size }
+                if *smer_size >= kmer_size =>
+            {
+                Err(anyhow!(
+                    "S-mer size ({}) must be smaller than the kmer size ({})",
+                    smer_size,
+                    kmer_size
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A kmer as 2-bit encoded integer (first base in the highest bits) or as bytes
+#[derive(Debug, Clone, Copy)]
+pub enum Kmer<'a> {
+    Bits(u64),
+    WideBits(u128), // 32 <= k < 64
+    Bytes(&'a [u8]),
+}
+
+/// Selects kmers of one kmer size, kmers must be passed in sequence order
+#[derive(Debug)]
+pub struct Sampler {
+    scheme: SamplingScheme,
+    kmer_size: usize,
+    seed: u64, // Seed of the s-mer hashes
+    // Minimizer candidates (position, hash) of the current window, increasing hashes
+    candidates: VecDeque<(usize, u64)>,
+    // Number of consecutive kmers up to the current position
+    run_length: usize,
+    next_position: usize,
+    last_selected: Option<usize>,
+}
+
+impl Sampler {
+    pub fn new(scheme: SamplingScheme, kmer_size: u8, seed: u64) -> Self {
+        Sampler {
+            scheme,
+            kmer_size: kmer_size as usize,
+            seed,
+            candidates: VecDeque::new(),
+            run_length: 0,
+            next_position: 0,
+            last_selected: None,
+        }
+    }
+
+    /// Starts a new sequence
+    pub fn reset(&mut self) {
+        self.candidates.clear();
+        self.run_length = 0;
+        self.next_position = 0;
+        self.last_selected = None;
+    }
+
+    /// Returns the hash if the kmer at `position` is selected
+    #[inline]
+    pub fn select(&mut self, position: usize, kmer: Kmer, hash: u64) -> Option<u64> {
+        match self.scheme {
+            SamplingScheme::All => Some(hash),
+            SamplingScheme::OpenSyncmer { smer_size } => {
+                let smallest = self.smallest_smer(kmer, smer_size as usize);
+                (smallest == (self.kmer_size - smer_size as usize) / 2).then_some(hash)
+            }
+            SamplingScheme::ClosedSyncmer { smer_size } => {
+                let smallest = self.smallest_smer(kmer, smer_size as usize);
+                (smallest == 0 || smallest == self.kmer_size - smer_size as usize).then_some(hash)
+            }
+            SamplingScheme::Minimizer { window } => self.minimizer(position, hash, window as usize),
+        }
+    }
+
+    /// Position of the (first) smallest s-mer of the kmer
+    fn smallest_smer(&self, kmer: Kmer, smer_size: usize) -> usize {
+        let num_smers = self.kmer_size - smer_size + 1;
+        let hashes = (0..num_smers).map(|idx| match kmer {
+            Kmer::Bits(bits) => {
+                let shift = 2 * (num_smers - 1 - idx);
+                xxhash3_u64(
+                    (bits >> shift) & (u64::MAX >> (64 - 2 * smer_size)),
+                    self.seed,
+                )
+            }
+            Kmer::WideBits(bits) => {
+                let shift = 2 * (num_smers - 1 - idx);
+                xxhash3_u128(
+                    (bits >> shift) & (u128::MAX >> (128 - 2 * smer_size)),
+                    self.seed,
+                )
+            }
+            Kmer::Bytes(bytes) => xxhash3(&bytes[idx..idx + smer_size], self.seed),
+        });
+        let mut smallest = (0, u64::MAX);
+        for (idx, hash) in hashes.enumerate() {
+            if hash < smallest.1 {
+                smallest = (idx, hash);
+            }
+        }
+        smallest.0
+    }
+
+    /// Selects each minimizer once, windows are only complete within runs of consecutive kmers
+    fn minimizer(&mut self, position: usize, hash: u64, window: usize) -> Option<u64> {
+        // Kmers with ambiguous bases are skipped and interrupt the run
+        if position != self.next_position {
+            self.candidates.clear();
+            self.run_length = 0;
+        }
+        self.next_position = position + 1;
+        self.run_length += 1;
+
+        // Ties are resolved by the leftmost kmer
+        while self.candidates.back().is_some_and(|(_, h)| *h > hash) {
+            self.candidates.pop_back();
+        }
+        self.candidates.push_back((position, hash));
+        while self
+            .candidates
+            .front()
+            .is_some_and(|(p, _)| p + window <= position)
+        {
+            self.candidates.pop_front();
+        }
+
+        if self.run_length < window {
+            return None;
+        }
+        let (min_position, min_hash) = *self.candidates.front()?;
+        if self.last_selected == Some(min_position) {
+            return None;
+        }
+        self.last_selected = Some(min_position);
+        Some(min_hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash_functions::DEFAULT_SEED;
+
+    #[test]
+    fn test_minimizer() {
+        let mut sampler = Sampler::new(SamplingScheme::Minimizer { window: 3 }, 21, DEFAULT_SEED);
+        let hashes = [5, 3, 4, 8, 9, 1, 2, 7];
+        let selected = hashes
+            .iter()
+            .enumerate()
+            .filter_map(|(pos, hash)| sampler.select(pos, Kmer::Bits(0), *hash))
+            .collect::<Vec<_>>();
+        // Windows: [5,3,4] [3,4,8] [4,8,9] [8,9,1] [9,1,2] [1,2,7]
+        assert_eq!(selected, vec![3, 4, 1]);
+
+        // A gap (skipped kmers) starts a new window
+        sampler.reset();
+        let selected = [(0, 5), (1, 3), (5, 4), (6, 8), (7, 9)]
+            .iter()
+            .filter_map(|(pos, hash)| sampler.select(*pos, Kmer::Bits(0), *hash))
+            .collect::<Vec<_>>();
+        assert_eq!(selected, vec![4]);
+    }
+
+    #[test]
+    fn test_syncmer() {
+        let seq = b"ACGTTGCATGCCATGACGTAGCTAGCTAGGCTAACGTTTGACCAGTAGCAT";
+        let select = |scheme, seq: &[u8]| {
+            let mut sampler = Sampler::new(scheme, 11, DEFAULT_SEED);
+            seq.windows(11)
+                .enumerate()
+                .filter(|(pos, kmer)| sampler.select(*pos, Kmer::Bytes(kmer), 0).is_some())
+                .map(|(_, kmer)| kmer.to_vec())
+                .collect::<Vec<_>>()
+        };
+        for scheme in [
+            SamplingScheme::OpenSyncmer { smer_size: 5 },
+            SamplingScheme::ClosedSyncmer { smer_size: 5 },
+        ] {
+            let selected = select(scheme, seq);
+            assert!(!selected.is_empty() && selected.len() < seq.len() - 10);
+            // The selection only depends on the kmer itself, not on its position
+            assert!(selected.ends_with(&select(scheme, &seq[7..])));
+        }
+
+        assert!(SamplingScheme::OpenSyncmer { smer_size: 21 }
+            .check_kmer_size(21)
+            .is_err());
+        assert!(SamplingScheme::new(&Sampling::Minimizer, None, None).is_err());
+    }
+}
@@ -1,21 +1,51 @@
-use crate::{cli::HashAlgorithms, sketch::Sketch};
+use crate::{
+    cli::{HashAlgorithms, Molecule},
+    hash_functions::{default_seed, DEFAULT_SEED},
+    sampling::SamplingScheme,
+    sketch::Sketch,
+};
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
+use sourmash::encodings::HashFunctions;
 use sourmash::signature::{Signature as SourmashSignature, SigsTrait};
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Signature {
     pub file_name: String,
     pub sketches: Vec<Sketch>,
     pub algorithm: HashAlgorithms,
-    pub kmer_size: u8,
+    pub kmer_size: u8, // In amino acids for protein, dayhoff and hp
     pub max_hash: u64,
+    #[serde(default)]
+    pub molecule: Molecule,
+    #[serde(default)]
+    pub scaling: Scaling,
+    #[serde(default)]
+    pub sampling: SamplingScheme,
+    #[serde(default = "default_seed")]
+    pub seed: u64, // Seed of the hash function, sketches with different seeds are independent
+}
+
+/// Sampling parameters in addition to the max hash (FracMinHash)
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Scaling {
+    pub nmin: Option<u64>, // Minimum number of hashes, filled with the smallest hashes above max hash
+    pub nmax: Option<u64>, // Maximum number of hashes (bottom-k)
+    pub kscale: Option<u64>, // Keep the smallest hashes of 1 / kscale of all kmers (KmerCountScaling)
+}
+
+impl Scaling {
+    /// Sketches of uniform (FracMinHash) signatures contain all hashes below max hash
+    pub fn is_uniform(&self) -> bool {
+        self.nmin.is_none() && self.nmax.is_none() && self.kscale.is_none()
+    }
 }
 
 impl From<Signature> for SourmashSignature {
     fn from(val: Signature) -> Self {
         SourmashSignature::builder()
-            .hash_function(format!("{:?}", val.algorithm))
+            .hash_function(val.algorithm.name().to_string())
             .filename(Some(val.file_name))
             .email("".to_string())
             .license("CC0".to_string())
@@ -23,92 +53,279 @@ impl From<Signature> for SourmashSignature {
             .signatures(
                 val.sketches
                     .into_iter()
-                    .map(|sketch| sketch.into_sourmash(val.max_hash))
+                    .map(|sketch| sketch.into_sourmash(val.max_hash, &val.molecule, val.seed))
                     .collect(),
             )
             .build()
     }
 }
 
-impl From<SourmashSignature> for Signature {
-    fn from(sourmash_signature: SourmashSignature) -> Self {
+impl TryFrom<SourmashSignature> for Signature {
+    type Error = anyhow::Error;
+
+    fn try_from(sourmash_signature: SourmashSignature) -> Result<Self> {
         let mut sketches = Vec::new();
         let mut max_hash = None;
         let mut kmer_size = None;
+        let mut molecule = None;
+        let mut seed = None;
         for sketch in sourmash_signature.sketches() {
             match sketch {
                 sourmash::sketch::Sketch::MinHash(mash) => {
                     if let Some(max_hash) = max_hash {
                         if max_hash != mash.max_hash() {
-                            panic!("Max hash of sketches is not equal");
+                            return Err(anyhow!("Max hash of sketches is not equal"));
                         }
                     } else {
                         max_hash = Some(mash.max_hash());
                     }
 
+                    if seed.is_some_and(|seed| seed != mash.seed()) {
+                        return Err(anyhow!("Seed of sketches is not equal"));
+                    }
+                    seed = Some(mash.seed());
+
+                    let mash_molecule = Molecule::from(mash.hash_function());
+                    if let Some(molecule) = &molecule {
+                        if molecule != &mash_molecule {
+                            return Err(anyhow!("Molecule of sketches is not equal"));
+                        }
+                    }
+                    // Sourmash stores the kmer size of amino acid sketches in nucleotides
+                    let ksize = match mash_molecule {
+                        Molecule::Dna => mash.ksize() as u8,
+                        _ => (mash.ksize() / 3) as u8,
+                    };
+                    molecule = Some(mash_molecule);
+
                     if let Some(kmer_size) = kmer_size {
-                        if kmer_size != mash.ksize() as u8 {
-                            panic!("Kmer size of sketches is not equal");
+                        if kmer_size != ksize {
+                            return Err(anyhow!("Kmer size of sketches is not equal"));
                         }
                     } else {
-                        kmer_size = Some(mash.ksize() as u8);
+                        kmer_size = Some(ksize);
                     }
 
-                    let mut sketch = Sketch::new(
-                        sourmash_signature.filename(),
-                        mash.mins().len(),
-                        mash.ksize() as u8,
-                    );
+                    let mut sketch =
+                        Sketch::new(sourmash_signature.filename(), mash.mins().len(), ksize);
                     sketch.hashes = mash.mins().into_iter().collect::<BTreeSet<u64>>();
+                    sketch.abundances = mash.abunds();
+                    sketch.max_hash = (mash.max_hash() > 0 && mash.max_hash() < u64::MAX)
+                        .then_some(mash.max_hash());
                     sketches.push(sketch);
                 }
                 sourmash::sketch::Sketch::LargeMinHash(mash) => {
                     if let Some(max_hash) = max_hash {
                         if max_hash != mash.max_hash() {
-                            panic!("Max hash of sketches is not equal");
+                            return Err(anyhow!("Max hash of sketches is not equal"));
                         }
                     } else {
                         max_hash = Some(mash.max_hash());
                     }
 
+                    if seed.is_some_and(|seed| seed != mash.seed()) {
+                        return Err(anyhow!("Seed of sketches is not equal"));
+                    }
+                    seed = Some(mash.seed());
+
+                    let mash_molecule = Molecule::from(mash.hash_function());
+                    if let Some(molecule) = &molecule {
+                        if molecule != &mash_molecule {
+                            return Err(anyhow!("Molecule of sketches is not equal"));
+                        }
+                    }
+                    // Sourmash stores the kmer size of amino acid sketches in nucleotides
+                    let ksize = match mash_molecule {
+                        Molecule::Dna => mash.ksize() as u8,
+                        _ => (mash.ksize() / 3) as u8,
+                    };
+                    molecule = Some(mash_molecule);
+
                     if let Some(kmer_size) = kmer_size {
-                        if kmer_size != mash.ksize() as u8 {
-                            panic!("Kmer size of sketches is not equal");
+                        if kmer_size != ksize {
+                            return Err(anyhow!("Kmer size of sketches is not equal"));
                         }
                     } else {
-                        kmer_size = Some(mash.ksize() as u8);
+                        kmer_size = Some(ksize);
                     }
 
-                    let mut sketch = Sketch::new(
-                        sourmash_signature.filename(),
-                        mash.mins().len(),
-                        mash.ksize() as u8,
-                    );
+                    let mut sketch =
+                        Sketch::new(sourmash_signature.filename(), mash.mins().len(), ksize);
                     sketch.hashes = mash.mins().into_iter().collect::<BTreeSet<u64>>();
+                    sketch.abundances = mash.abunds();
+                    sketch.max_hash = (mash.max_hash() > 0 && mash.max_hash() < u64::MAX)
+                        .then_some(mash.max_hash());
                     sketches.push(sketch);
                 }
                 sourmash::sketch::Sketch::HyperLogLog(_) => {
-                    unimplemented!("HyperLogLog sketches are not supported")
+                    return Err(anyhow!("HyperLogLog sketches are not supported"));
                 }
             }
         }
-        Signature {
+        Ok(Signature {
             file_name: sourmash_signature.filename(),
             sketches,
-            algorithm: HashAlgorithms::Murmur3,
-            kmer_size: kmer_size.expect("No sketch with kmer_size found"),
-            max_hash: max_hash.expect("No sketch with max hash found"),
+            // Unknown hash functions are treated as sourmash default (murmur3)
+            algorithm: parse_hash_function(&sourmash_signature.hash_function())
+                .unwrap_or(HashAlgorithms::Murmur3),
+            kmer_size: kmer_size.ok_or_else(|| anyhow!("No sketch with kmer_size found"))?,
+            max_hash: max_hash.ok_or_else(|| anyhow!("No sketch with max hash found"))?,
+            molecule: molecule.unwrap_or_default(),
+            scaling: Scaling::default(),
+            sampling: SamplingScheme::All,
+            seed: seed.unwrap_or(DEFAULT_SEED),
+        })
+    }
+}
+
+impl From<HashFunctions> for Molecule {
+    fn from(hash_function: HashFunctions) -> Self {
+        match hash_function {
+            HashFunctions::Murmur64Protein => Molecule::Protein,
+            HashFunctions::Murmur64Dayhoff => Molecule::Dayhoff,
+            HashFunctions::Murmur64Hp => Molecule::Hp,
+            _ => Molecule::Dna,
         }
     }
 }
 
+impl From<&Molecule> for HashFunctions {
+    fn from(molecule: &Molecule) -> Self {
+        match molecule {
+            Molecule::Dna => HashFunctions::Murmur64Dna,
+            Molecule::Protein => HashFunctions::Murmur64Protein,
+            Molecule::Dayhoff => HashFunctions::Murmur64Dayhoff,
+            Molecule::Hp => HashFunctions::Murmur64Hp,
+        }
+    }
+}
+
+/// Restricts the hash space to u64::MAX / fscale (FracMinHash)
+pub fn fscale_to_max_hash(fscale: Option<u64>) -> u64 {
+    if let Some(fscale) = fscale {
+        (u64::MAX as f64 / fscale as f64) as u64
+    } else {
+        u64::MAX
+    }
+}
+
+/// Parses the `hash_function` field of a sourmash signature
+/// Signatures created by sourmash itself use "0.murmur64"
+pub fn parse_hash_function(hash_function: &str) -> Option<HashAlgorithms> {
+    match hash_function {
+        "0.murmur64" => Some(HashAlgorithms::Murmur3),
+        _ => HashAlgorithms::from_name(hash_function),
+    }
+}
+
 impl Signature {
+    /// Inverse of the max_hash calculation in `FileHandler::sketch_file`
+    /// Sourmash uses max_hash = 0 for sketches without scaling
+    pub fn fscale(&self) -> Option<u64> {
+        if self.max_hash == 0 || self.max_hash == u64::MAX {
+            None
+        } else {
+            Some((u64::MAX as f64 / self.max_hash as f64).round() as u64)
+        }
+    }
+
+    /// One signature per (non-empty) sketch, e.g. to compare the windows of positional sketches separately
+    pub fn split(self) -> Vec<Signature> {
+        let Signature {
+            sketches,
+            algorithm,
+            kmer_size,
+            max_hash,
+            molecule,
+            scaling,
+            sampling,
+            seed,
+            ..
+        } = self;
+        sketches
+            .into_iter()
+            .filter(|sketch| !sketch.hashes.is_empty())
+            .map(|sketch| Signature {
+                file_name: sketch.name.clone(),
+                sketches: vec![sketch],
+                algorithm: algorithm.clone(),
+                kmer_size,
+                max_hash,
+                molecule: molecule.clone(),
+                scaling,
+                sampling,
+                seed,
+            })
+            .collect()
+    }
+
     pub fn collapse(&mut self) -> Sketch {
         let mut sketch = Sketch::new(self.file_name.to_string(), 0, self.kmer_size);
+        let track_abundance = self.sketches.iter().all(|s| s.abundances.is_some());
+        let mut abundances = BTreeMap::new();
+        // The union of bottom-k sketches is only complete up to the smallest bound,
+        // sketches without bound contain all hashes below the max hash of the signature
+        if self.sketches.iter().any(|s| s.max_hash.is_some()) {
+            sketch.max_hash = self
+                .sketches
+                .iter()
+                .map(|s| s.max_hash.unwrap_or(self.max_hash))
+                .min();
+        }
         for old_sketch in self.sketches.drain(..) {
+            if track_abundance {
+                for (hash, count) in old_sketch.abundance_map() {
+                    *abundances.entry(hash).or_insert(0) += count;
+                }
+            }
+            if let Some(stats) = old_sketch.stats {
+                sketch
+                    .stats
+                    .get_or_insert_with(Default::default)
+                    .add(&stats);
+            }
             sketch.hashes.extend(old_sketch.hashes);
             sketch.num_kmers += old_sketch.num_kmers;
         }
+        if track_abundance && !sketch.hashes.is_empty() {
+            sketch.abundances = Some(abundances.into_values().collect());
+        }
         sketch
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signature(sketches: Vec<Sketch>) -> Signature {
+        Signature {
+            file_name: "test".to_string(),
+            sketches,
+            algorithm: HashAlgorithms::Murmur3,
+            kmer_size: 21,
+            max_hash: fscale_to_max_hash(Some(1000)),
+            molecule: Molecule::Dna,
+            scaling: Scaling::default(),
+            sampling: SamplingScheme::All,
+            seed: DEFAULT_SEED,
+        }
+    }
+
+    #[test]
+    fn test_try_from_sourmash() {
+        let mut sketch = Sketch::new("test".to_string(), 0, 21);
+        sketch.hashes.extend([1, 2, 3]);
+        let sourmash: SourmashSignature = signature(vec![sketch.clone()]).into();
+        let converted = Signature::try_from(sourmash).unwrap();
+        assert_eq!(converted.kmer_size, 21);
+        assert_eq!(converted.sketches[0].hashes, sketch.hashes);
+
+        // Invalid user input is reported as error instead of a panic
+        let sourmash: SourmashSignature =
+            signature(vec![sketch, Sketch::new("test".to_string(), 0, 31)]).into();
+        assert!(Signature::try_from(sourmash).is_err());
+        let sourmash: SourmashSignature = signature(vec![]).into();
+        assert!(Signature::try_from(sourmash).is_err());
+    }
+}
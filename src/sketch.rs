@@ -1,7 +1,9 @@
+use crate::cli::Molecule;
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
+use sourmash::encodings::HashFunctions;
 use sourmash::sketch::{minhash::KmerMinHash, Sketch as SourmashSketch};
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct Sketch {
@@ -9,6 +11,51 @@ pub struct Sketch {
     pub hashes: BTreeSet<u64>, // Hashes with stats
     pub num_kmers: usize,      // Number of kmers (collected)
     pub kmer_size: u8,         // Kmer size
+    #[serde(default)]
+    pub abundances: Option<Vec<u64>>, // Counts per hash, same order as hashes
+    #[serde(default)]
+    pub stats: Option<Stats>, // Only available if sketched with --stats
+    // All hashes of the input below max_hash are included, capped (nmax, kscale)
+    // and extended (nmin) sketches end after their largest hash (bottom-k)
+    #[serde(default)]
+    pub max_hash: Option<u64>,
+}
+
+/// Length, GC and N content of the sequence(s) of a sketch
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub length: u64,
+    pub gc_count: u64, // G, C and S (strong) bases
+    pub n_count: u64,  // N and all other non ACGT characters
+}
+
+impl Stats {
+    pub fn from_sequence(seq: &[u8]) -> Self {
+        let mut stats = Stats {
+            length: seq.len() as u64,
+            ..Default::default()
+        };
+        for base in seq {
+            match base.to_ascii_uppercase() {
+                b'G' | b'C' | b'S' => stats.gc_count += 1,
+                b'A' | b'T' | b'U' | b'W' => {}
+                _ => stats.n_count += 1,
+            }
+        }
+        stats
+    }
+
+    pub fn add(&mut self, other: &Stats) {
+        self.length += other.length;
+        self.gc_count += other.gc_count;
+        self.n_count += other.n_count;
+    }
+
+    /// GC content (in percent) of all unambiguous bases
+    pub fn gc_content(&self) -> Option<f64> {
+        let bases = self.length - self.n_count;
+        (bases > 0).then(|| self.gc_count as f64 / bases as f64 * 100.0)
+    }
 }
 
 impl Sketch {
@@ -18,17 +65,47 @@ impl Sketch {
             num_kmers,
             kmer_size,
             hashes: BTreeSet::new(),
+            abundances: None,
+            stats: None,
+            max_hash: None,
+        }
+    }
+
+    /// Abundance of every hash, hashes without tracked abundance count once
+    pub fn abundance_map(&self) -> BTreeMap<u64, u64> {
+        match &self.abundances {
+            Some(abundances) => self
+                .hashes
+                .iter()
+                .copied()
+                .zip(abundances.iter().copied())
+                .collect(),
+            None => self.hashes.iter().map(|hash| (*hash, 1)).collect(),
         }
     }
 }
 
 impl Sketch {
-    pub fn into_sourmash(self, max_hash: u64) -> SourmashSketch {
+    pub fn into_sourmash(self, max_hash: u64, molecule: &Molecule, seed: u64) -> SourmashSketch {
+        // Sourmash expects the kmer size of amino acid sketches in nucleotides
+        let ksize = match molecule {
+            Molecule::Dna => self.kmer_size as u32,
+            _ => self.kmer_size as u32 * 3,
+        };
+        // Sourmash treats sketches with num > 0 as num sketches, scaled sketches have num = 0
+        let num = if max_hash == 0 {
+            self.hashes.len() as u32
+        } else {
+            0
+        };
         let sketch = KmerMinHash::builder()
-            .ksize(self.kmer_size as u32)
-            .num(self.hashes.len() as u32)
+            .ksize(ksize)
+            .hash_function(HashFunctions::from(molecule))
+            .seed(seed)
+            .num(num)
             .max_hash(max_hash)
             .mins(self.hashes.into_iter().sorted().collect::<Vec<u64>>())
+            .abunds(self.abundances)
             .build();
         SourmashSketch::MinHash(sketch)
     }
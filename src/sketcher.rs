@@ -1,24 +1,49 @@
-use crate::{cli::HashAlgorithms, hash_functions::Function, signature::Signature, sketch::Sketch};
+use crate::{
+    cli::{HashAlgorithms, Molecule},
+    file_io::DatabaseMeta,
+    hash_functions::{KmerEncoding, KmerHasher},
+    hasher::NoHashHasher,
+    sampling::{Kmer, Sampler, SamplingScheme},
+    signature::{fscale_to_max_hash, Scaling, Signature},
+    sketch::{Sketch, Stats},
+};
 use needletail::{parser::SequenceRecord, Sequence};
-use std::collections::BTreeSet;
+use sourmash::encodings::{aa_to_dayhoff, aa_to_hp, revcomp, to_aa};
+use std::{
+    collections::{BTreeSet, HashMap},
+    hash::BuildHasherDefault,
+    sync::Arc,
+};
 
 #[derive(Debug, Default)]
 struct SketchHelper {
     pub max_hash: u64,
     hit_counter: u64,
     kmer_seq_counter: u64,
+    pub nmin: u64,
     pub nmax: u64,
+    pub kscale: Option<u64>,
     pub btree: BTreeSet<u64>,
+    // Smallest hashes above max hash, used to fill sketches with less than nmin hashes
+    reserve: BTreeSet<u64>,
+    // The sketch was cut to the smallest hashes (nmax / kscale)
+    capped: bool,
+    // Only populated if abundances are tracked
+    pub counts: Option<HashMap<u64, u64, BuildHasherDefault<NoHashHasher>>>,
+    // Only populated if record statistics are kept
+    pub stats: Option<Stats>,
 }
 
 impl SketchHelper {
-    pub fn new(max_hash: u64, nmax: Option<u64>) -> Self {
+    pub fn new(max_hash: u64, settings: &DatabaseMeta) -> Self {
         SketchHelper {
-            nmax: nmax.unwrap_or(u64::MAX),
-            hit_counter: 0,
-            kmer_seq_counter: 0,
+            nmin: settings.nmin.unwrap_or_default(),
+            nmax: settings.nmax.unwrap_or(u64::MAX),
+            kscale: settings.kscale,
             max_hash,
-            btree: BTreeSet::new(),
+            counts: settings.track_abundance.then(HashMap::default),
+            stats: settings.stats.then(Stats::default),
+            ..Default::default()
         }
     }
 
@@ -28,105 +53,422 @@ impl SketchHelper {
         if hash < self.max_hash {
             self.hit_counter += 1;
             self.btree.insert(hash);
-            if self.btree.len() > self.nmax as usize {
-                self.btree.pop_last();
+            if let Some(counts) = self.counts.as_mut() {
+                *counts.entry(hash).or_insert(0) += 1;
+            }
+            self.truncate(self.nmax);
+        } else if self.nmin > 0 {
+            self.reserve.insert(hash);
+            if let Some(counts) = self.counts.as_mut() {
+                *counts.entry(hash).or_insert(0) += 1;
+            }
+            if self.reserve.len() > self.nmin as usize {
+                if let Some(removed) = self.reserve.pop_last() {
+                    if let Some(counts) = self.counts.as_mut() {
+                        counts.remove(&removed);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Keeps the smallest `limit` hashes
+    fn truncate(&mut self, limit: u64) {
+        while self.btree.len() > limit as usize {
+            if let Some(removed) = self.btree.pop_last() {
+                self.capped = true;
+                if let Some(counts) = self.counts.as_mut() {
+                    counts.remove(&removed);
+                }
+            }
+        }
+    }
+
+    /// Combines the hashes of two helpers with the same settings,
+    /// the result is identical to pushing all kmers into one helper
+    pub fn merge(&mut self, other: SketchHelper) {
+        self.kmer_seq_counter += other.kmer_seq_counter;
+        self.hit_counter += other.hit_counter;
+        self.capped |= other.capped;
+        if let (Some(counts), Some(other_counts)) = (self.counts.as_mut(), other.counts) {
+            for (hash, count) in other_counts {
+                *counts.entry(hash).or_insert(0) += count;
+            }
+        }
+        if let (Some(stats), Some(other_stats)) = (self.stats.as_mut(), other.stats) {
+            stats.add(&other_stats);
+        }
+        self.btree.extend(other.btree);
+        self.truncate(self.nmax);
+        self.reserve.extend(other.reserve);
+        while self.reserve.len() > self.nmin as usize {
+            if let Some(removed) = self.reserve.pop_last() {
+                if let Some(counts) = self.counts.as_mut() {
+                    counts.remove(&removed);
+                }
             }
         }
     }
 
     pub fn reset(&mut self) {
-        let nmax = self.nmax;
-        *self = Self::default();
-        self.nmax = nmax;
+        self.hit_counter = 0;
+        self.kmer_seq_counter = 0;
+        self.btree.clear();
+        self.reserve.clear();
+        self.capped = false;
+        if let Some(counts) = self.counts.as_mut() {
+            counts.clear();
+        }
+        if let Some(stats) = self.stats.as_mut() {
+            *stats = Stats::default();
+        }
     }
 
-    pub fn into_sketch(&mut self, name: String, kmer_size: u8) -> Sketch {
+    pub fn take_sketch(&mut self, name: String, kmer_size: u8) -> Sketch {
+        // KmerCountScaling: keep the smallest hashes of 1 / kscale of all kmers
+        if let Some(kscale) = self.kscale {
+            let limit = self.kmer_seq_counter.div_ceil(kscale.max(1)).max(self.nmin);
+            self.truncate(limit);
+        }
+        // MinMaxAbsoluteScaling: fill up with the smallest hashes above max hash
+        let mut extended = false;
+        while self.btree.len() < self.nmin as usize {
+            let Some(hash) = self.reserve.pop_first() else {
+                break;
+            };
+            self.btree.insert(hash);
+            extended = true;
+        }
+
         let mut sketch = Sketch::new(name, self.btree.len(), kmer_size);
-        let old_map = std::mem::replace(&mut self.btree, BTreeSet::new());
-        sketch.hashes = old_map.into_iter().collect();
+        let old_map = std::mem::take(&mut self.btree);
+        if let Some(counts) = self.counts.as_ref() {
+            sketch.abundances = Some(
+                old_map
+                    .iter()
+                    .map(|hash| counts.get(hash).copied().unwrap_or(1))
+                    .collect(),
+            );
+        }
+        sketch.max_hash = if self.capped || extended {
+            old_map.last().map(|hash| hash.saturating_add(1))
+        } else {
+            (self.max_hash < u64::MAX).then_some(self.max_hash)
+        };
+        sketch.hashes = old_map;
+        sketch.stats = self.stats.as_mut().map(std::mem::take);
         self.reset();
         sketch
     }
 }
 
-pub struct Sketcher<'a> {
-    name: String,
+/// Hasher, kmer selection, helper and finished sketches of one kmer size
+struct KmerSketcher {
     kmer_length: u8,
+    hasher: Arc<dyn KmerHasher>,
+    encoding: KmerEncoding, // Of DNA kmers, amino acid kmers are always bytes
+    sampler: Sampler,
     helper: SketchHelper,
     completed_sketches: Vec<Sketch>,
+}
+
+pub struct Sketcher {
+    name: String,
+    sketchers: Vec<KmerSketcher>,
     singleton: bool,
-    function: Function<'a>,
+    window: Option<(usize, usize)>, // Window size and step of positional sketches
+    scaling: Scaling,
+    sampling: SamplingScheme,
     algorithm: HashAlgorithms,
+    seed: u64,
+    molecule: Molecule,
+    input_is_protein: bool,
 }
 
-impl<'a> Sketcher<'a> {
+impl Sketcher {
+    /// Creates one sketch per kmer size, all other settings are shared
+    /// The hasher must be the one of the settings (`HashAlgorithms::hasher`)
     pub fn new(
-        kmer_length: u8,
         name: String,
-        singleton: bool,
-        max_hash: u64,
-        nmax: Option<u64>,
-        function: Function<'a>,
-        algorithm: HashAlgorithms,
+        settings: &DatabaseMeta,
+        hasher: Arc<dyn KmerHasher>,
+        kmer_sizes: &[u8],
+        input_is_protein: bool,
     ) -> Self {
+        let mut max_hash = fscale_to_max_hash(settings.fscale);
+        // Only hashes in twice the expected range of the smallest 1 / kscale hashes are kept
+        if let Some(kscale) = settings.kscale {
+            max_hash = max_hash.min(fscale_to_max_hash(Some(kscale)).saturating_mul(2));
+        }
         Sketcher {
             name,
-            kmer_length,
-            helper: SketchHelper::new(max_hash, nmax),
-            singleton,
-            completed_sketches: Vec::new(),
-            function,
-            algorithm,
+            sketchers: kmer_sizes
+                .iter()
+                .map(|kmer_length| KmerSketcher {
+                    kmer_length: *kmer_length,
+                    // Kmers that do not fit into the requested encoding are hashed as bytes
+                    encoding: match hasher.encoding(*kmer_length) {
+                        KmerEncoding::Small if *kmer_length < 32 => KmerEncoding::Small,
+                        KmerEncoding::Wide if *kmer_length < 64 => KmerEncoding::Wide,
+                        _ => KmerEncoding::Large,
+                    },
+                    hasher: hasher.clone(),
+                    sampler: Sampler::new(settings.sampling, *kmer_length, settings.seed),
+                    helper: SketchHelper::new(max_hash, settings),
+                    completed_sketches: Vec::new(),
+                })
+                .collect(),
+            singleton: settings.singleton,
+            window: settings.window.map(|window| {
+                (
+                    window as usize,
+                    settings.step.unwrap_or(window).max(1) as usize,
+                )
+            }),
+            scaling: Scaling {
+                nmin: settings.nmin,
+                nmax: settings.nmax,
+                kscale: settings.kscale,
+            },
+            sampling: settings.sampling,
+            algorithm: settings.algorithm.clone(),
+            seed: settings.seed,
+            molecule: settings.molecule.clone(),
+            input_is_protein,
         }
     }
 }
 
-impl Sketcher<'_> {
+impl Sketcher {
     // This is more or less derived from the `process` method in `finch-rs`:
     // https://github.com/onecodex/finch-rs/blob/master/lib/src/sketch_schemes/mash.rs
-    pub fn process<'seq, 'a, 'inner>(&'a mut self, seq: &'seq SequenceRecord<'inner>)
-    where
-        'a: 'seq,
-        'seq: 'inner,
-    {
-        let name = seq.id();
-        let seq = seq.normalize(false);
-        if self.kmer_length <= 31 {
-            let func_small = self.function.get_small().unwrap();
-            for (_, kmer, _) in seq.bit_kmers(self.kmer_length, true) {
-                self.helper.push(func_small(kmer.0));
+    pub fn process(&mut self, record: &SequenceRecord) {
+        self.process_sequence(record.id(), &record.seq());
+    }
+
+    /// Processes a single (owned) record, `seq` must not contain line breaks
+    /// With a window size one sketch per window is created, named `<id>:<start>-<end>` (1-based, inclusive)
+    pub fn process_sequence(&mut self, name: &[u8], seq: &[u8]) {
+        if let Some((window, step)) = self.window {
+            let name = String::from_utf8_lossy(name);
+            let id = name.split_whitespace().next().unwrap_or_default();
+            for (start, end) in windows(seq.len(), window, step) {
+                self.hash_sequence(&seq[start..end]);
+                self.complete_sketches(format!("{}:{}-{}", id, start + 1, end));
+            }
+            return;
+        }
+        self.hash_sequence(seq);
+        if self.singleton {
+            self.complete_sketches(String::from_utf8_lossy(name).to_string());
+        }
+    }
+
+    fn hash_sequence(&mut self, seq: &[u8]) {
+        let seed = self.seed;
+        // Statistics are calculated once and shared by all kmer sizes
+        let mut stats = None;
+        for sketcher in self.sketchers.iter_mut() {
+            if let Some(helper_stats) = sketcher.helper.stats.as_mut() {
+                helper_stats.add(stats.get_or_insert_with(|| Stats::from_sequence(seq)));
+            }
+        }
+        if self.molecule != Molecule::Dna {
+            let aa_seqs = self.amino_acids(seq);
+            for sketcher in self.sketchers.iter_mut() {
+                for aa_seq in aa_seqs.iter() {
+                    sketcher.sampler.reset();
+                    for (pos, kmer) in aa_seq.windows(sketcher.kmer_length as usize).enumerate() {
+                        if let Some(hash) = sketcher.sampler.select(
+                            pos,
+                            Kmer::Bytes(kmer),
+                            sketcher.hasher.hash_large(kmer, seed),
+                        ) {
+                            sketcher.helper.push(hash);
+                        }
+                    }
+                }
             }
         } else {
-            let func_large = self.function.get_large().unwrap();
-            let rc = seq.reverse_complement();
-            for (_, kmer, _) in seq.canonical_kmers(self.kmer_length, &rc) {
-                self.helper.push(func_large(kmer));
+            let seq = seq.normalize(false);
+            // Only calculated once and only if any kmer size requires it
+            let mut rc = None;
+            for sketcher in self.sketchers.iter_mut() {
+                sketcher.sampler.reset();
+                match sketcher.encoding {
+                    KmerEncoding::Small => {
+                        for (pos, kmer, _) in seq.bit_kmers(sketcher.kmer_length, true) {
+                            if let Some(hash) = sketcher.sampler.select(
+                                pos,
+                                Kmer::Bits(kmer.0),
+                                sketcher.hasher.hash_small(kmer.0, seed),
+                            ) {
+                                sketcher.helper.push(hash);
+                            }
+                        }
+                    }
+                    KmerEncoding::Wide => {
+                        for (pos, kmer) in canonical_wide_kmers(&seq, sketcher.kmer_length) {
+                            if let Some(hash) = sketcher.sampler.select(
+                                pos,
+                                Kmer::WideBits(kmer),
+                                sketcher.hasher.hash_wide(kmer, seed),
+                            ) {
+                                sketcher.helper.push(hash);
+                            }
+                        }
+                    }
+                    KmerEncoding::Large => {
+                        let rc = rc.get_or_insert_with(|| seq.reverse_complement());
+                        for (pos, kmer, _) in seq.canonical_kmers(sketcher.kmer_length, rc) {
+                            if let Some(hash) = sketcher.sampler.select(
+                                pos,
+                                Kmer::Bytes(kmer),
+                                sketcher.hasher.hash_large(kmer, seed),
+                            ) {
+                                sketcher.helper.push(hash);
+                            }
+                        }
+                    }
+                }
             }
         }
-        if self.singleton {
-            self.completed_sketches.push(
-                self.helper
-                    .into_sketch(String::from_utf8_lossy(name).to_string(), self.kmer_length),
+    }
+
+    /// Moves the current hashes of every kmer size into a completed sketch
+    fn complete_sketches(&mut self, name: String) {
+        for sketcher in self.sketchers.iter_mut() {
+            sketcher.completed_sketches.push(
+                sketcher
+                    .helper
+                    .take_sketch(name.to_string(), sketcher.kmer_length),
             );
         }
     }
 
-    pub fn finish(self) -> Signature {
-        let max_hash = self.helper.max_hash;
-        let file_name = self.name.to_string();
-        let algorithm = self.algorithm.clone();
-        let kmer_size = self.kmer_length;
-        let mut sketches = self.completed_sketches;
-        let mut helper = self.helper;
-        sketches.push(helper.into_sketch(self.name, self.kmer_length));
-        Signature {
-            file_name,
-            sketches,
-            max_hash,
-            algorithm,
-            kmer_size,
+    // Follows sourmash: protein input is hashed directly (after the alphabet reduction),
+    // DNA input is translated in all six frames
+    fn amino_acids(&self, seq: &[u8]) -> Vec<Vec<u8>> {
+        let seq = seq.to_ascii_uppercase();
+        if self.input_is_protein {
+            let aa_seq: Vec<u8> = match self.molecule {
+                Molecule::Dayhoff => seq.into_iter().map(aa_to_dayhoff).collect(),
+                Molecule::Hp => seq.into_iter().map(aa_to_hp).collect(),
+                _ => seq,
+            };
+            vec![aa_seq]
+        } else {
+            let dayhoff = self.molecule == Molecule::Dayhoff;
+            let hp = self.molecule == Molecule::Hp;
+            let rc = revcomp(&seq);
+            let mut frames = Vec::with_capacity(6);
+            for frame in 0..3 {
+                for strand in [&seq, &rc] {
+                    if strand.len() <= frame {
+                        continue;
+                    }
+                    // to_aa only fails for invalid codon lengths, chunks(3) never yields them
+                    if let Ok(aa_seq) = to_aa(&strand[frame..], dayhoff, hp) {
+                        frames.push(aa_seq);
+                    }
+                }
+            }
+            frames
         }
     }
+
+    /// Merges a sketcher with the same settings that processed other records of the same input
+    /// Completed (singleton) sketches of `other` are appended
+    pub fn merge(&mut self, other: Sketcher) {
+        for (sketcher, other) in self.sketchers.iter_mut().zip(other.sketchers) {
+            sketcher.helper.merge(other.helper);
+            sketcher.completed_sketches.extend(other.completed_sketches);
+        }
+    }
+
+    /// Removes the completed (singleton) sketches, one list per kmer size
+    pub fn take_completed(&mut self) -> Vec<Vec<Sketch>> {
+        self.sketchers
+            .iter_mut()
+            .map(|sketcher| std::mem::take(&mut sketcher.completed_sketches))
+            .collect()
+    }
+
+    /// Appends completed sketches in the layout of `take_completed`
+    pub fn extend_completed(&mut self, completed: Vec<Vec<Sketch>>) {
+        for (sketcher, sketches) in self.sketchers.iter_mut().zip(completed) {
+            sketcher.completed_sketches.extend(sketches);
+        }
+    }
+
+    /// One signature per kmer size, in the order of the kmer sizes
+    pub fn finish(self) -> Vec<Signature> {
+        let name = self.name;
+        let algorithm = self.algorithm;
+        let molecule = self.molecule;
+        let window = self.window;
+        let scaling = self.scaling;
+        let sampling = self.sampling;
+        let seed = self.seed;
+        self.sketchers
+            .into_iter()
+            .map(|sketcher| {
+                let mut sketches = sketcher.completed_sketches;
+                let mut helper = sketcher.helper;
+                // All hashes of positional sketches are already part of a window
+                if window.is_none() {
+                    sketches.push(helper.take_sketch(name.to_string(), sketcher.kmer_length));
+                }
+                Signature {
+                    file_name: name.to_string(),
+                    sketches,
+                    max_hash: helper.max_hash,
+                    algorithm: algorithm.clone(),
+                    kmer_size: sketcher.kmer_length,
+                    molecule: molecule.clone(),
+                    scaling,
+                    sampling,
+                    seed,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Start (0-based) and end (exclusive) of all windows of a sequence,
+/// the last window is shorter if the sequence is not covered by full windows
+fn windows(len: usize, window: usize, step: usize) -> impl Iterator<Item = (usize, usize)> {
+    let mut start = Some(0).filter(|_| len > 0);
+    std::iter::from_fn(move || {
+        let current = start?;
+        let end = (current + window).min(len);
+        start = (end < len).then_some(current + step);
+        Some((current, end))
+    })
+}
+
+/// Start positions and canonical 2-bit encoded kmers (32 <= k < 64) of a normalized sequence
+/// Same encoding as the u64 `bit_kmers` of needletail, kmers with other bases than ACGT are skipped
+fn canonical_wide_kmers(seq: &[u8], kmer_size: u8) -> impl Iterator<Item = (usize, u128)> + '_ {
+    let kmer_size = kmer_size as usize;
+    let mask = u128::MAX >> (128 - 2 * kmer_size);
+    let (mut forward, mut reverse, mut valid) = (0u128, 0u128, 0usize);
+    seq.iter().enumerate().filter_map(move |(idx, base)| {
+        let code = match base {
+            b'A' => 0,
+            b'C' => 1,
+            b'G' => 2,
+            b'T' => 3,
+            _ => {
+                valid = 0;
+                return None;
+            }
+        };
+        forward = ((forward << 2) | code) & mask;
+        reverse = (reverse >> 2) | ((3 - code) << (2 * (kmer_size - 1)));
+        valid += 1;
+        (valid >= kmer_size).then(|| (idx + 1 - kmer_size, forward.min(reverse)))
+    })
 }
 
 // #[cfg(test)]
@@ -141,7 +483,7 @@ impl Sketcher<'_> {
 //         helper.push(2);
 //         helper.push(3);
 //         assert_eq!(
-//             helper.into_sketch("sketch".to_string(), 1),
+//             helper.take_sketch("sketch".to_string(), 1),
 //             Sketch {
 //                 name: "sketch".to_string(),
 //                 hashes: HashMap::from_iter(vec![(1, Some(Stats::new(0, 0)))]),
@@ -0,0 +1,538 @@
+//! Lineages of database sketches and lowest common ancestor (LCA) classification
+//!
+//! Lineages are stored as `;` separated taxon names in the order of `RANKS`,
+//! missing intermediate ranks are kept as empty names.
+use crate::compare::LmdbComparator;
+use crate::file_io::TsvHeader;
+use crate::sketch::Sketch;
+use anyhow::anyhow;
+use anyhow::Result;
+use rayon::prelude::IntoParallelRefIterator;
+use rayon::prelude::ParallelIterator;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Supported ranks, `domain` is treated as an alias of `superkingdom`
+pub const RANKS: [&str; 8] = [
+    "superkingdom",
+    "phylum",
+    "class",
+    "order",
+    "family",
+    "genus",
+    "species",
+    "strain",
+];
+
+/// Extensions that are removed to match file names against lineage identifiers
+const EXTENSIONS: [&str; 9] = [
+    ".gz", ".fasta", ".fna", ".fa", ".faa", ".fastq", ".fq", ".sig", ".json",
+];
+
+fn rank_index(rank: &str) -> Option<usize> {
+    let rank = rank.trim().to_ascii_lowercase();
+    match rank.as_str() {
+        "domain" => Some(0),
+        rank => RANKS.iter().position(|r| *r == rank),
+    }
+}
+
+/// Name of the rank of a lineage with `depth` taxa
+pub fn rank_name(depth: usize) -> &'static str {
+    match depth {
+        0 => "root",
+        depth => RANKS.get(depth - 1).copied().unwrap_or("strain"),
+    }
+}
+
+/// Joins taxa to a lineage, trailing missing ranks are removed
+fn join_lineage<S: AsRef<str>>(taxa: &[S]) -> String {
+    let len = taxa
+        .iter()
+        .rposition(|taxon| !taxon.as_ref().is_empty())
+        .map(|idx| idx + 1)
+        .unwrap_or(0);
+    taxa[..len]
+        .iter()
+        .map(|taxon| taxon.as_ref().trim())
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+/// Lowest common ancestor of multiple lineages, `None` if no lineage is given
+pub fn lca<'a>(lineages: impl IntoIterator<Item = &'a str>) -> Option<String> {
+    let mut common: Option<Vec<&str>> = None;
+    for lineage in lineages {
+        let taxa = lineage.split(';').collect::<Vec<_>>();
+        common = Some(match common {
+            None => taxa,
+            Some(common) => common
+                .into_iter()
+                .zip(taxa)
+                .take_while(|(a, b)| a == b)
+                .map(|(a, _)| a)
+                .collect(),
+        });
+    }
+    common.map(|taxa| join_lineage(&taxa))
+}
+
+fn split_dmp(line: &str) -> Vec<&str> {
+    line.trim_end_matches(['\t', '|', '\n', '\r'])
+        .split("\t|\t")
+        .collect()
+}
+
+/// Lineage per identifier (sequence id, accession or file name)
+#[derive(Debug, Default, Clone)]
+pub struct Lineages {
+    lineages: HashMap<String, String>,
+}
+
+impl Lineages {
+    /// Reads a csv (or tab separated) file with a header and the identifier in the first column
+    ///
+    /// The lineage is either a `;` separated string in the second column,
+    /// one column per rank (e.g. `ident,superkingdom,phylum,...`) or a NCBI taxid
+    /// in the second column if a NCBI taxdump folder (`nodes.dmp`, `names.dmp`) is provided
+    pub fn from_file(path: &Path, taxdump: Option<&Path>) -> Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Err(anyhow!("Lineage file {:?} is empty", path));
+        }
+        let delimiter = if header.contains('\t') { b'\t' } else { b',' };
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(delimiter)
+            .flexible(true)
+            .trim(csv::Trim::All)
+            .from_reader(header.as_bytes().chain(reader));
+        let columns = reader.headers()?.clone();
+        if columns.len() < 2 {
+            return Err(anyhow!(
+                "Lineage file {:?} requires an identifier and a lineage column",
+                path
+            ));
+        }
+        // Column index -> rank index
+        let rank_columns = columns
+            .iter()
+            .enumerate()
+            .skip(1)
+            .filter_map(|(idx, column)| rank_index(column).map(|rank| (idx, rank)))
+            .collect::<Vec<_>>();
+        let taxdump = taxdump.map(TaxDump::from_folder).transpose()?;
+
+        let mut lineages = HashMap::new();
+        for record in reader.records() {
+            let record = record?;
+            let Some(ident) = record.get(0).filter(|ident| !ident.is_empty()) else {
+                continue;
+            };
+            let lineage = if let Some(taxdump) = &taxdump {
+                let taxid = record
+                    .get(1)
+                    .and_then(|taxid| taxid.parse::<u64>().ok())
+                    .ok_or_else(|| anyhow!("Invalid taxid for {} in {:?}", ident, path))?;
+                taxdump.lineage(taxid)?
+            } else if rank_columns.is_empty() {
+                let taxa = record.get(1).unwrap_or_default().split(';');
+                join_lineage(&taxa.collect::<Vec<_>>())
+            } else {
+                let mut taxa = vec![""; RANKS.len()];
+                for (idx, rank) in rank_columns.iter() {
+                    taxa[*rank] = record.get(*idx).unwrap_or_default();
+                }
+                join_lineage(&taxa)
+            };
+            lineages.insert(ident.to_string(), lineage);
+        }
+        Ok(Lineages { lineages })
+    }
+
+    /// Looks up the full name, the first word of the name (sequence id)
+    /// and the file name without folders and extensions
+    pub fn get(&self, name: &str) -> Option<&String> {
+        if let Some(lineage) = self.lineages.get(name) {
+            return Some(lineage);
+        }
+        if let Some(lineage) = name
+            .split_whitespace()
+            .next()
+            .and_then(|id| self.lineages.get(id))
+        {
+            return Some(lineage);
+        }
+        let mut file_name = Path::new(name)
+            .file_name()
+            .map(|f| f.to_string_lossy().to_string())
+            .unwrap_or_default();
+        while let Some(stripped) = EXTENSIONS
+            .iter()
+            .find_map(|ext| file_name.strip_suffix(ext))
+        {
+            file_name = stripped.to_string();
+        }
+        self.lineages.get(&file_name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.lineages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lineages.is_empty()
+    }
+}
+
+/// Minimal parser for the NCBI taxonomy dump (`nodes.dmp` and scientific names of `names.dmp`)
+struct TaxDump {
+    nodes: HashMap<u64, (u64, String)>, // taxid -> (parent, rank)
+    names: HashMap<u64, String>,
+}
+
+impl TaxDump {
+    fn from_folder(folder: &Path) -> Result<Self> {
+        let mut nodes = HashMap::new();
+        for line in BufReader::new(File::open(folder.join("nodes.dmp"))?).lines() {
+            let line = line?;
+            let fields = split_dmp(&line);
+            if fields.len() < 3 {
+                continue;
+            }
+            nodes.insert(
+                fields[0].parse()?,
+                (fields[1].parse()?, fields[2].to_string()),
+            );
+        }
+        let mut names = HashMap::new();
+        for line in BufReader::new(File::open(folder.join("names.dmp"))?).lines() {
+            let line = line?;
+            let fields = split_dmp(&line);
+            if fields.len() < 4 || fields[3] != "scientific name" {
+                continue;
+            }
+            names.insert(fields[0].parse()?, fields[1].to_string());
+        }
+        Ok(TaxDump { nodes, names })
+    }
+
+    fn lineage(&self, taxid: u64) -> Result<String> {
+        let mut taxa = vec![String::new(); RANKS.len()];
+        let mut visited = HashSet::new();
+        let mut current = taxid;
+        loop {
+            if !visited.insert(current) {
+                return Err(anyhow!(
+                    "Taxid {} has a cyclic lineage in taxdump (at taxid {})",
+                    taxid,
+                    current
+                ));
+            }
+            let (parent, rank) = self
+                .nodes
+                .get(&current)
+                .ok_or_else(|| anyhow!("Taxid {} not found in taxdump", current))?;
+            if let Some(rank) = rank_index(rank) {
+                taxa[rank] = self.names.get(&current).cloned().unwrap_or_default();
+            }
+            if *parent == current {
+                break;
+            }
+            current = *parent;
+        }
+        Ok(join_lineage(&taxa))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TaxonResult {
+    pub query_name: String,
+    pub rank: String,
+    pub lineage: String,
+    pub num_hashes: usize,   // Query hashes with an LCA within this taxon
+    pub query_hashes: usize, // Number of hashes in the query sketch
+    pub f_hashes: f64,       // num_hashes / query_hashes (in percent)
+    pub f_abundance: f64,    // Fraction of the query abundance (in percent)
+}
+
+impl Display for TaxonResult {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}\t{:.2}\t{:.2}",
+            self.query_name,
+            self.rank,
+            self.lineage,
+            self.num_hashes,
+            self.query_hashes,
+            self.f_hashes,
+            self.f_abundance,
+        )
+    }
+}
+
+impl TsvHeader for TaxonResult {
+    fn tsv_header() -> &'static str {
+        "query_name\trank\tlineage\tnum_hashes\tquery_hashes\tf_hashes\tf_abundance"
+    }
+}
+
+/// Hashes and abundance per LCA lineage of one query sketch
+struct QueryTaxa {
+    query_name: String,
+    query_hashes: usize,
+    total_abundance: u64,
+    classified: (usize, u64), // Hashes and abundance found in any lineage
+    // Lineage -> (hashes, abundance), including all ancestors of the LCAs
+    taxa: BTreeMap<String, (usize, u64)>,
+}
+
+impl QueryTaxa {
+    fn result(&self, lineage: &str, depth: usize, counts: (usize, u64)) -> TaxonResult {
+        TaxonResult {
+            query_name: self.query_name.clone(),
+            rank: rank_name(depth).to_string(),
+            lineage: lineage.to_string(),
+            num_hashes: counts.0,
+            query_hashes: self.query_hashes,
+            f_hashes: counts.0 as f64 / self.query_hashes.max(1) as f64 * 100.0,
+            f_abundance: counts.1 as f64 / self.total_abundance.max(1) as f64 * 100.0,
+        }
+    }
+}
+
+/// Assigns the hashes of query sketches to the LCA of all database sketches
+/// with a lineage that contain the hash
+pub struct Classifier<'a> {
+    comparator: &'a LmdbComparator,
+    min_fraction: f64,
+}
+
+impl<'a> Classifier<'a> {
+    /// A query is assigned to the lowest taxon that contains at least
+    /// `min_fraction` of the classified hashes
+    pub fn new(comparator: &'a LmdbComparator, min_fraction: f64) -> Self {
+        Classifier {
+            comparator,
+            min_fraction,
+        }
+    }
+
+    /// One assignment per query sketch, unclassified queries have an empty lineage
+    pub fn classify(&self) -> Result<Vec<TaxonResult>> {
+        Ok(self
+            .query_taxa()?
+            .into_iter()
+            .map(|query| {
+                if query.classified.0 == 0 {
+                    return TaxonResult {
+                        rank: "unclassified".to_string(),
+                        ..query.result("", 0, (0, 0))
+                    };
+                }
+                let required = self.min_fraction * query.classified.0 as f64;
+                let best = query
+                    .taxa
+                    .iter()
+                    .filter(|(_, (count, _))| *count as f64 >= required)
+                    .map(|(lineage, counts)| (lineage.split(';').count(), lineage, *counts))
+                    .max_by(|a, b| a.0.cmp(&b.0).then(a.2 .0.cmp(&b.2 .0)));
+                match best {
+                    Some((depth, lineage, counts)) => query.result(lineage, depth, counts),
+                    None => query.result("", 0, query.classified),
+                }
+            })
+            .collect())
+    }
+
+    /// Hashes and abundance of every taxon per rank and query
+    pub fn summarize(&self) -> Result<Vec<TaxonResult>> {
+        let mut results = Vec::new();
+        for query in self.query_taxa()? {
+            let mut taxa = query
+                .taxa
+                .iter()
+                .map(|(lineage, counts)| query.result(lineage, lineage.split(';').count(), *counts))
+                .collect::<Vec<_>>();
+            taxa.sort_by(|a, b| {
+                rank_index(&a.rank)
+                    .cmp(&rank_index(&b.rank))
+                    .then(b.num_hashes.cmp(&a.num_hashes))
+                    .then(a.lineage.cmp(&b.lineage))
+            });
+            results.extend(taxa);
+        }
+        Ok(results)
+    }
+
+    fn query_taxa(&self) -> Result<Vec<QueryTaxa>> {
+        self.comparator.check_signatures()?;
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.comparator.threads)
+            .build()?;
+        let results = Mutex::new(Vec::new());
+        pool.install(|| {
+            self.comparator
+                .signatures
+                .par_iter()
+                .try_for_each(|origin| {
+                    origin.sketches.par_iter().try_for_each(|query| {
+                        let taxa = self.classify_sketch(query)?;
+                        results.lock().unwrap().push(taxa);
+                        Ok::<(), anyhow::Error>(())
+                    })
+                })
+        })?;
+        let mut results = results.into_inner().expect("poisoned lock");
+        results.sort_by(|a, b| a.query_name.cmp(&b.query_name));
+        Ok(results)
+    }
+
+    fn classify_sketch(&self, query: &Sketch) -> Result<QueryTaxa> {
+        let txn = self.comparator.lmdb_env.read_txn()?;
+        let hashes = self.comparator.hashes_db(&txn)?;
+        let infos = self.comparator.infos.read().expect("poisoned lock");
+
+        let mut taxa: BTreeMap<String, (usize, u64)> = BTreeMap::new();
+        let mut classified = (0, 0);
+        let mut total_abundance = 0;
+        for (hash, abundance) in query.abundance_map() {
+            total_abundance += abundance;
+            let Some(matches) = hashes.get_duplicates(&txn, &hash)? else {
+                continue;
+            };
+            let mut lineages = Vec::new();
+            for item in matches {
+                let (_, sketch) = item?;
+                if let Some(lineage) = infos.get(&sketch).and_then(|i| i.lineage.as_ref()) {
+                    lineages.push(lineage.as_str());
+                }
+            }
+            let Some(lca) = lca(lineages) else {
+                continue;
+            };
+            classified.0 += 1;
+            classified.1 += abundance;
+            if lca.is_empty() {
+                continue;
+            }
+            // Every ancestor of the LCA contains the hash
+            let lca = lca.split(';').collect::<Vec<_>>();
+            for depth in 1..=lca.len() {
+                let entry = taxa.entry(lca[..depth].join(";")).or_insert((0, 0));
+                entry.0 += 1;
+                entry.1 += abundance;
+            }
+        }
+        Ok(QueryTaxa {
+            query_name: query.name.clone(),
+            query_hashes: query.hashes.len(),
+            total_abundance,
+            classified,
+            taxa,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lca() {
+        assert_eq!(lca(Vec::<&str>::new()), None);
+        assert_eq!(lca(["a;b;c"]), Some("a;b;c".to_string()));
+        assert_eq!(lca(["a;b;c", "a;b;d", "a;b"]), Some("a;b".to_string()));
+        assert_eq!(lca(["a;b", "x;b"]), Some("".to_string()));
+        // Missing intermediate ranks are kept
+        assert_eq!(lca(["a;;c;d", "a;;c;e"]), Some("a;;c".to_string()));
+        assert_eq!(rank_name(0), "root");
+        assert_eq!(rank_name(2), "phylum");
+    }
+
+    #[test]
+    fn test_lineage_lookup() {
+        let lineages = Lineages {
+            lineages: HashMap::from([
+                ("GCF_1.1".to_string(), "Bacteria;Pseudomonadota".to_string()),
+                ("seq1".to_string(), "Archaea".to_string()),
+            ]),
+        };
+        assert_eq!(
+            lineages.get("data/GCF_1.1.fna.gz").map(String::as_str),
+            Some("Bacteria;Pseudomonadota")
+        );
+        assert_eq!(
+            lineages.get("seq1 some description").map(String::as_str),
+            Some("Archaea")
+        );
+        assert_eq!(lineages.get("seq2"), None);
+    }
+
+    #[test]
+    fn test_taxdump_lineage() {
+        assert_eq!(
+            split_dmp("562\t|\t561\t|\tspecies\t|\tEC\t|"),
+            vec!["562", "561", "species", "EC"]
+        );
+        let taxdump = TaxDump {
+            nodes: HashMap::from([
+                (1, (1, "no rank".to_string())),
+                (2, (131567, "domain".to_string())),
+                (131567, (1, "cellular root".to_string())),
+                (561, (2, "genus".to_string())),
+                (562, (561, "species".to_string())),
+            ]),
+            names: HashMap::from([
+                (2, "Bacteria".to_string()),
+                (561, "Escherichia".to_string()),
+                (562, "Escherichia coli".to_string()),
+            ]),
+        };
+        assert_eq!(
+            taxdump.lineage(562).unwrap(),
+            "Bacteria;;;;;Escherichia;Escherichia coli"
+        );
+        assert!(taxdump.lineage(3).is_err());
+
+        // Cyclic parents must not loop forever
+        let taxdump = TaxDump {
+            nodes: HashMap::from([
+                (10, (11, "genus".to_string())),
+                (11, (10, "family".to_string())),
+            ]),
+            names: HashMap::new(),
+        };
+        assert!(taxdump.lineage(10).is_err());
+    }
+
+    #[test]
+    fn test_lineage_file() {
+        let path = std::env::temp_dir().join("jam_lineage_file.csv");
+        std::fs::write(
+            &path,
+            "ident,superkingdom,phylum,species\n\
+             seq1,Bacteria,Pseudomonadota,\"Escherichia coli, strain K-12\"\n\
+             \n\
+             \"seq2, plasmid\",Archaea,,\n",
+        )
+        .unwrap();
+        let lineages = Lineages::from_file(&path, None).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(lineages.len(), 2);
+        assert_eq!(
+            lineages.get("seq1").map(String::as_str),
+            Some("Bacteria;Pseudomonadota;;;;;Escherichia coli, strain K-12")
+        );
+        assert_eq!(
+            lineages.get("seq2, plasmid").map(String::as_str),
+            Some("Archaea")
+        );
+    }
+}
@@ -3,6 +3,7 @@ use std::borrow::Cow;
 use heed::BoxedError;
 use integer_encoding::{VarInt, VarIntReader};
 
+#[allow(dead_code)]
 pub struct VarIntEncoder;
 
 impl heed::BytesEncode<'_> for VarIntEncoder {
@@ -0,0 +1,42 @@
+use jam_rs::{
+    cli::{ClusterMethod, HashAlgorithms},
+    cluster::Clusters,
+    compare::LmdbComparator,
+    file_io::{DatabaseMeta, FileHandler},
+};
+use std::{fs, path::PathBuf};
+
+#[test]
+fn test_cluster_dereplicate() {
+    let inputs = [
+        PathBuf::from("tests/testfiles/short.fa"),
+        PathBuf::from("tests/testfiles/test.small.fa"),
+        PathBuf::from("tests/testfiles/short.fa"),
+    ];
+    let signatures = FileHandler::load_or_sketch_files(
+        &inputs,
+        &DatabaseMeta::new(HashAlgorithms::Murmur3, 21, None, None, false),
+    )
+    .unwrap();
+    let clusters = Clusters::new(signatures, 0.99, &ClusterMethod::Greedy, 2).unwrap();
+    assert_eq!(clusters.num_clusters(), 2);
+    // Identical inputs are in the same cluster
+    let short = clusters
+        .members
+        .iter()
+        .filter(|m| m.name == "tests/testfiles/short.fa")
+        .collect::<Vec<_>>();
+    assert_eq!(short.len(), 2);
+    assert_eq!(short[0].cluster, short[1].cluster);
+    assert_eq!(short[1].ani, 1.0);
+
+    fs::create_dir_all("cluster_derep").unwrap();
+    FileHandler::write_lmdb(clusters.representatives(), PathBuf::from("cluster_derep")).unwrap();
+    let comparator =
+        LmdbComparator::new(PathBuf::from("cluster_derep/compact.mdb"), 1, 0.0, true).unwrap();
+    assert_eq!(comparator.infos.read().unwrap().len(), 2);
+    assert_eq!(comparator.kmer_size, 21);
+    assert_eq!(comparator.algorithm, HashAlgorithms::Murmur3);
+
+    fs::remove_dir_all("cluster_derep").unwrap();
+}
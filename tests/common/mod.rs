@@ -0,0 +1,94 @@
+use jam_rs::cli::{Commands, HashAlgorithms, Molecule, OutputFormats, Sampling};
+use jam_rs::hash_functions::DEFAULT_SEED;
+use std::path::PathBuf;
+
+/// Arguments of `jam sketch` with the defaults of the command line
+/// Tests only set the fields they exercise: `SketchArgs { .., ..Default::default() }.into()`
+pub struct SketchArgs {
+    pub input: Vec<PathBuf>,
+    pub output: Option<PathBuf>,
+    pub name: Option<String>,
+    pub kmer_size: Vec<u8>,
+    pub fscale: Option<u64>,
+    pub nmax: Option<u64>,
+    pub nmin: Option<u64>,
+    pub kscale: Option<u64>,
+    pub format: OutputFormats,
+    pub algorithm: HashAlgorithms,
+    pub singleton: bool,
+    pub molecule: Molecule,
+    pub input_is_protein: bool,
+    pub track_abundance: bool,
+    pub append: bool,
+    pub lineages: Option<PathBuf>,
+    pub taxdump: Option<PathBuf>,
+    pub window: Option<u64>,
+    pub step: Option<u64>,
+    pub stats: bool,
+    pub sampling: Sampling,
+    pub smer_size: Option<u8>,
+    pub minimizer_window: Option<u8>,
+    pub seed: u64,
+}
+
+impl Default for SketchArgs {
+    fn default() -> Self {
+        SketchArgs {
+            input: Vec::new(),
+            output: None,
+            name: None,
+            kmer_size: vec![21],
+            fscale: None,
+            nmax: None,
+            nmin: None,
+            kscale: None,
+            format: OutputFormats::Lmdb,
+            algorithm: HashAlgorithms::Default,
+            singleton: false,
+            molecule: Molecule::Dna,
+            input_is_protein: false,
+            track_abundance: false,
+            append: false,
+            lineages: None,
+            taxdump: None,
+            window: None,
+            step: None,
+            stats: false,
+            sampling: Sampling::All,
+            smer_size: None,
+            minimizer_window: None,
+            seed: DEFAULT_SEED,
+        }
+    }
+}
+
+impl From<SketchArgs> for Commands {
+    fn from(args: SketchArgs) -> Self {
+        Commands::Sketch {
+            input: args.input,
+            output: args.output,
+            name: args.name,
+            kmer_size: args.kmer_size,
+            fscale: args.fscale,
+            nmax: args.nmax,
+            nmin: args.nmin,
+            kscale: args.kscale,
+            format: args.format,
+            algorithm: args.algorithm,
+            singleton: args.singleton,
+            molecule: args.molecule,
+            input_is_protein: args.input_is_protein,
+            track_abundance: args.track_abundance,
+            append: args.append,
+            lineages: args.lineages,
+            taxdump: args.taxdump,
+            window: args.window,
+            step: args.step,
+            stats: args.stats,
+            sampling: args.sampling,
+            smer_size: args.smer_size,
+            minimizer_window: args.minimizer_window,
+            seed: args.seed,
+        }
+    }
+}
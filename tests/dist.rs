@@ -0,0 +1,174 @@
+mod common;
+
+use common::SketchArgs;
+use jam_rs::{
+    cli::HashAlgorithms,
+    compare::{LmdbComparator, ResultFilter},
+    file_io::{DatabaseMeta, FileHandler},
+};
+use std::{fs, path::PathBuf};
+
+#[test]
+fn test_lmdb_top_n() {
+    fs::create_dir_all("dist_top_n_db").unwrap();
+    FileHandler::sketch_files(
+        SketchArgs {
+            input: vec![PathBuf::from("tests/testfiles/test.small.fa")],
+            output: Some(PathBuf::from("dist_top_n_db")),
+            algorithm: HashAlgorithms::Murmur3,
+            singleton: true,
+            ..Default::default()
+        }
+        .into(),
+        None,
+    )
+    .unwrap();
+
+    let mut comparator =
+        LmdbComparator::new(PathBuf::from("dist_top_n_db/compact.mdb"), 2, 0.0, true).unwrap();
+    let query = FileHandler::sketch_file(
+        &PathBuf::from("tests/testfiles/test.small.fa"),
+        &DatabaseMeta::new(HashAlgorithms::Murmur3, 21, None, None, false),
+        false,
+        false,
+    )
+    .unwrap();
+    comparator.set_signatures(vec![query]);
+    let all = comparator.compare().unwrap();
+    assert_eq!(all.len(), 4);
+
+    comparator.set_filter(ResultFilter {
+        top_n: Some(2),
+        ..Default::default()
+    });
+    let top = comparator.compare().unwrap();
+    assert_eq!(top.len(), 2);
+    assert!(top[0].estimated_containment >= top[1].estimated_containment);
+    // The best hits of all results
+    let mut containments = all
+        .iter()
+        .map(|r| r.estimated_containment)
+        .collect::<Vec<_>>();
+    containments.sort_by(|a, b| b.total_cmp(a));
+    assert_eq!(top[0].estimated_containment, containments[0]);
+    assert_eq!(top[1].estimated_containment, containments[1]);
+
+    fs::remove_dir_all("dist_top_n_db").unwrap();
+}
+
+#[test]
+fn test_lmdb_windows() {
+    fs::create_dir_all("dist_windows_db").unwrap();
+    FileHandler::sketch_files(
+        SketchArgs {
+            input: vec![PathBuf::from("tests/testfiles/test.small.fa")],
+            output: Some(PathBuf::from("dist_windows_db")),
+            algorithm: HashAlgorithms::Murmur3,
+            singleton: true,
+            ..Default::default()
+        }
+        .into(),
+        None,
+    )
+    .unwrap();
+
+    let mut comparator =
+        LmdbComparator::new(PathBuf::from("dist_windows_db/compact.mdb"), 2, 0.0, true).unwrap();
+    let settings = DatabaseMeta {
+        window: Some(50000),
+        ..DatabaseMeta::new(HashAlgorithms::Murmur3, 21, None, None, false)
+    };
+    // No additional (empty) sketch is created for the whole file
+    let signature = FileHandler::sketch_file(
+        &PathBuf::from("tests/testfiles/test.small.fa"),
+        &settings,
+        false,
+        false,
+    )
+    .unwrap();
+    assert_eq!(signature.sketches.len(), 5 + 4 + 4 + 1);
+    let windows = FileHandler::load_or_sketch_file(
+        &PathBuf::from("tests/testfiles/test.small.fa"),
+        &settings,
+    )
+    .unwrap();
+    // 221938, 198222, 167214 and 12360 bases
+    assert_eq!(windows.len(), 5 + 4 + 4 + 1);
+    assert_eq!(
+        windows[0].file_name,
+        "NODE_1_length_221938_cov_19.431827:1-50000"
+    );
+    assert_eq!(
+        windows[4].file_name,
+        "NODE_1_length_221938_cov_19.431827:200001-221938"
+    );
+
+    comparator.set_signatures(windows);
+    comparator.set_filter(ResultFilter {
+        top_n: Some(1),
+        ..Default::default()
+    });
+    let results = comparator.compare().unwrap();
+    assert_eq!(results.len(), 14);
+    // Every window is fully contained in its own record
+    for result in results {
+        let record = result.from_name.split(':').next().unwrap();
+        assert!(result.to_name.starts_with(record));
+        assert_eq!(result.estimated_containment, 100.0);
+    }
+
+    fs::remove_dir_all("dist_windows_db").unwrap();
+}
+
+#[test]
+fn test_lmdb_gc_filter() {
+    fs::create_dir_all("dist_gc_db").unwrap();
+    FileHandler::sketch_files(
+        SketchArgs {
+            input: vec![PathBuf::from("tests/testfiles/test.small.fa")],
+            output: Some(PathBuf::from("dist_gc_db")),
+            algorithm: HashAlgorithms::Murmur3,
+            singleton: true,
+            stats: true,
+            ..Default::default()
+        }
+        .into(),
+        None,
+    )
+    .unwrap();
+
+    let mut comparator =
+        LmdbComparator::new(PathBuf::from("dist_gc_db/compact.mdb"), 2, 0.0, true).unwrap();
+    let gc_contents = comparator
+        .infos
+        .read()
+        .unwrap()
+        .values()
+        .filter(|info| info.num_hashes > 0)
+        .map(|info| info.stats.unwrap().gc_content().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(gc_contents.len(), 4);
+    let query = FileHandler::sketch_file(
+        &PathBuf::from("tests/testfiles/test.small.fa"),
+        &DatabaseMeta::new(HashAlgorithms::Murmur3, 21, None, None, false),
+        false,
+        false,
+    )
+    .unwrap();
+    comparator.set_signatures(vec![query]);
+
+    // Only matches within the bounds are reported
+    let lowest = gc_contents.iter().copied().fold(f64::MAX, f64::min);
+    comparator.set_filter(ResultFilter {
+        gc_lower: Some(lowest + 1e-9),
+        ..Default::default()
+    });
+    assert_eq!(comparator.compare().unwrap().len(), 3);
+    comparator.set_filter(ResultFilter {
+        gc_upper: Some(lowest),
+        ..Default::default()
+    });
+    assert_eq!(comparator.compare().unwrap().len(), 1);
+
+    fs::remove_dir_all("dist_gc_db").unwrap();
+}
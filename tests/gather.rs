@@ -0,0 +1,71 @@
+mod common;
+
+use common::SketchArgs;
+use jam_rs::{
+    cli::HashAlgorithms,
+    compare::{Gather, LmdbComparator},
+    file_io::{DatabaseMeta, FileHandler},
+};
+use std::{fs, path::PathBuf};
+
+#[test]
+fn test_gather_singleton_database() {
+    fs::create_dir_all("gather_db").unwrap();
+    // One database sketch per record
+    FileHandler::sketch_files(
+        SketchArgs {
+            input: vec![PathBuf::from("tests/testfiles/test.small.fa")],
+            output: Some(PathBuf::from("gather_db")),
+            algorithm: HashAlgorithms::Murmur3,
+            singleton: true,
+            ..Default::default()
+        }
+        .into(),
+        None,
+    )
+    .unwrap();
+
+    let mut comparator =
+        LmdbComparator::new(PathBuf::from("gather_db/compact.mdb"), 1, 0.0, true).unwrap();
+    // Singleton databases contain an additional (empty) sketch for the whole file
+    let num_records = comparator
+        .infos
+        .read()
+        .unwrap()
+        .values()
+        .filter(|info| info.num_hashes > 0)
+        .count();
+
+    // The whole file must be explained by its records
+    let query = FileHandler::sketch_file(
+        &PathBuf::from("tests/testfiles/test.small.fa"),
+        &DatabaseMeta::new(HashAlgorithms::Murmur3, 21, None, None, false),
+        false,
+        false,
+    )
+    .unwrap();
+    let query_hashes = query.sketches[0].hashes.len();
+    comparator.set_signatures(vec![query]);
+
+    let results = Gather::new(&comparator, 1).gather().unwrap();
+    assert_eq!(results.len(), num_records);
+    assert_eq!(
+        results.iter().map(|r| r.unique_overlap).sum::<usize>(),
+        query_hashes
+    );
+    for (rank, result) in results.iter().enumerate() {
+        assert_eq!(result.rank, rank);
+        assert_eq!(result.f_match, 100.0);
+        assert!(result.unique_overlap <= result.total_overlap);
+        if rank > 0 {
+            assert!(result.unique_overlap <= results[rank - 1].unique_overlap);
+        }
+    }
+    assert!((results.last().unwrap().f_query_explained - 100.0).abs() < 1e-9);
+
+    // Nothing is reported if no database sketch reaches the minimum overlap
+    let results = Gather::new(&comparator, query_hashes + 1).gather().unwrap();
+    assert!(results.is_empty());
+
+    fs::remove_dir_all("gather_db").unwrap();
+}
@@ -3,6 +3,8 @@
 // - https://www.itl.nist.gov/div898/handbook/eda/section3/eda35g.htm
 // - https://onlinecourses.science.psu.edu/stat414/node/322/
 
+use jam_rs::hash_functions::{ahash, xxhash3, DEFAULT_SEED};
+
 /// Hash a sequence of values, returning the hashes sorted.
 #[inline]
 fn do_hashes_bytes(fcn: fn(&[u8]) -> u64, data: &[Vec<u8>]) -> Vec<u64> {
@@ -21,7 +23,7 @@ fn do_hashes_u64(fcn: fn(u64) -> u64, data: &[u64]) -> Vec<u64> {
 /// Cumulative Distribution Function for the Uniform Distribution.
 fn cdf_uniform(x: u64) -> f64 {
     // Wish we had f128s. Gonna be issues here.
-    (x as f64) / (std::u64::MAX as f64)
+    (x as f64) / (u64::MAX as f64)
 }
 
 /// Compute the Kolmogorov-Smirnov test.
@@ -34,7 +36,7 @@ fn cdf_uniform(x: u64) -> f64 {
 fn ks(samples: &[u64]) -> f64 {
     let n = samples.len() as f64;
     let mut last_ecdf = 0.0f64;
-    let mut ks = std::f64::MIN;
+    let mut ks = f64::MIN;
     for (i, x) in samples.iter().enumerate() {
         let tcdf = (i as f64) / n;
         let next_ecdf = cdf_uniform(*x);
@@ -72,13 +74,13 @@ fn run_ks() {
     print_ks(
         "xxhash3",
         ks(&do_hashes_bytes(
-            jam_rs::hash_functions::xxhash3,
+            |kmer| xxhash3(kmer, DEFAULT_SEED),
             samples_bytes.as_slice(),
         )),
     );
     print_ks(
         "ahash",
-        ks(&do_hashes_u64(jam_rs::hash_functions::ahash, &samples)),
+        ks(&do_hashes_u64(|kmer| ahash(kmer, DEFAULT_SEED), &samples)),
     );
     print_ks(
         "murmur3_old",
@@ -105,9 +107,9 @@ fn test_bit_distribution() {
     let mut murmur3_new_bits = [0u64; 64];
 
     for x in 0..samples.len() {
-        let xx = jam_rs::hash_functions::xxhash3(samples_bytes[x].as_slice());
+        let xx = xxhash3(samples_bytes[x].as_slice(), DEFAULT_SEED);
         unrolled_64bits(xx, &mut xxhash3_bits);
-        let ah = jam_rs::hash_functions::ahash(samples[x]);
+        let ah = ahash(samples[x], DEFAULT_SEED);
         unrolled_64bits(ah, &mut ahash_bits);
         let mo = murmur3_old(samples_bytes[x].as_slice());
         unrolled_64bits(mo, &mut murmur3_old_bits);
@@ -137,9 +139,9 @@ fn test_bit_distribution() {
 }
 
 fn unrolled_64bits(num: u64, nums: &mut [u64; 64]) {
-    for i in 0..64 {
+    for (i, count) in nums.iter_mut().enumerate() {
         if num & (1u64 << i) != 0 {
-            nums[i] += 1;
+            *count += 1;
         }
     }
 }
@@ -0,0 +1,150 @@
+mod common;
+
+use common::SketchArgs;
+use jam_rs::{
+    cli::{HashAlgorithms, OutputFormats, Sampling},
+    compare::LmdbComparator,
+    file_io::{DatabaseMeta, FileHandler},
+};
+use std::{
+    fs,
+    path::{self, PathBuf},
+};
+
+fn sketch(output: &str, kmer_size: u8, format: OutputFormats) {
+    FileHandler::sketch_files(
+        SketchArgs {
+            input: vec![PathBuf::from("tests/testfiles/short.fa")],
+            output: Some(PathBuf::from(output)),
+            kmer_size: vec![kmer_size],
+            format,
+            algorithm: HashAlgorithms::Murmur3,
+            ..Default::default()
+        }
+        .into(),
+        None,
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_merge_sourmash() {
+    sketch("merge_a.sig", 21, OutputFormats::Sourmash);
+    sketch("merge_b.sig", 21, OutputFormats::Sourmash);
+    sketch("merge_c.sig", 31, OutputFormats::Sourmash);
+
+    FileHandler::concat(
+        vec![PathBuf::from("merge_a.sig"), PathBuf::from("merge_b.sig")],
+        PathBuf::from("merge_ab.sig"),
+    )
+    .unwrap();
+    let merged =
+        sourmash::signature::Signature::from_path(path::Path::new("merge_ab.sig")).unwrap();
+    assert_eq!(merged.len(), 2);
+
+    // Different kmer sizes can not be merged
+    assert!(FileHandler::concat(
+        vec![PathBuf::from("merge_a.sig"), PathBuf::from("merge_c.sig")],
+        PathBuf::from("merge_ac.sig"),
+    )
+    .is_err());
+
+    // Different sampling schemes can not be merged
+    FileHandler::sketch_files(
+        SketchArgs {
+            input: vec![PathBuf::from("tests/testfiles/short.fa")],
+            output: Some(PathBuf::from("merge_d.sig")),
+            format: OutputFormats::Sourmash,
+            algorithm: HashAlgorithms::Murmur3,
+            sampling: Sampling::OpenSyncmer,
+            smer_size: Some(11),
+            ..Default::default()
+        }
+        .into(),
+        None,
+    )
+    .unwrap();
+    assert!(FileHandler::concat(
+        vec![PathBuf::from("merge_a.sig"), PathBuf::from("merge_d.sig")],
+        PathBuf::from("merge_ad.sig"),
+    )
+    .is_err());
+
+    // Different seeds can not be merged
+    FileHandler::sketch_files(
+        SketchArgs {
+            input: vec![PathBuf::from("tests/testfiles/short.fa")],
+            output: Some(PathBuf::from("merge_e.sig")),
+            format: OutputFormats::Sourmash,
+            algorithm: HashAlgorithms::Murmur3,
+            seed: 7,
+            ..Default::default()
+        }
+        .into(),
+        None,
+    )
+    .unwrap();
+    assert!(FileHandler::concat(
+        vec![PathBuf::from("merge_a.sig"), PathBuf::from("merge_e.sig")],
+        PathBuf::from("merge_ae.sig"),
+    )
+    .is_err());
+
+    for file in [
+        "merge_a.sig",
+        "merge_b.sig",
+        "merge_c.sig",
+        "merge_d.sig",
+        "merge_e.sig",
+        "merge_ab.sig",
+    ] {
+        fs::remove_file(file).unwrap();
+    }
+    let _ = fs::remove_file("merge_ac.sig");
+    let _ = fs::remove_file("merge_ad.sig");
+    let _ = fs::remove_file("merge_ae.sig");
+}
+
+#[test]
+fn test_merge_lmdb() {
+    for dir in ["merge_lmdb_a", "merge_lmdb_b", "merge_lmdb_out"] {
+        fs::create_dir_all(dir).unwrap();
+    }
+    sketch("merge_lmdb_a", 21, OutputFormats::Lmdb);
+    sketch("merge_lmdb_b", 21, OutputFormats::Lmdb);
+
+    FileHandler::concat(
+        vec![
+            PathBuf::from("merge_lmdb_a/compact.mdb"),
+            PathBuf::from("merge_lmdb_b/compact.mdb"),
+        ],
+        PathBuf::from("merge_lmdb_out"),
+    )
+    .unwrap();
+
+    let mut comparator =
+        LmdbComparator::new(PathBuf::from("merge_lmdb_out/compact.mdb"), 1, 0.0, true).unwrap();
+    assert_eq!(comparator.infos.read().unwrap().len(), 2);
+
+    // Both (identical) sketches must be found for every hash
+    let query = FileHandler::sketch_file(
+        &PathBuf::from("tests/testfiles/short.fa"),
+        &DatabaseMeta::new(HashAlgorithms::Murmur3, 21, None, None, false),
+        false,
+        false,
+    )
+    .unwrap();
+    comparator.set_signatures(vec![query]);
+    let results = comparator.compare().unwrap();
+    assert_eq!(results.len(), 2);
+    for result in results {
+        assert_eq!(result.num_common, result.num_kmers);
+        assert_eq!(result.jaccard, 1.0);
+        assert_eq!(result.ani, 1.0);
+        assert_eq!(result.mash_distance, 0.0);
+    }
+
+    for dir in ["merge_lmdb_a", "merge_lmdb_b", "merge_lmdb_out"] {
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,3 +1,6 @@
+mod common;
+
+use common::SketchArgs;
 use jam_rs::file_io::FileHandler;
 use sourmash::sketch::Sketch;
 use std::{
@@ -15,18 +18,17 @@ fn get_hashes_sketch(sketch: &Sketch) -> Vec<u64> {
 
 #[test]
 fn test_file_sketching_basic() {
-    let input_file = "tests/testfiles/test.small.fa";
+    let input_file = "tests/testfiles/short.fa";
     FileHandler::sketch_files(
-        jam_rs::cli::Commands::Sketch {
+        SketchArgs {
             input: vec![PathBuf::from(input_file)],
             output: Some(PathBuf::from("test.small.fa.test")),
-            kmer_size: 33,
-            fscale: None,
-            nmax: None,
+            kmer_size: vec![33],
             format: jam_rs::cli::OutputFormats::Sourmash,
             algorithm: jam_rs::cli::HashAlgorithms::Murmur3,
-            singleton: false,
-        },
+            ..Default::default()
+        }
+        .into(),
         None,
     )
     .unwrap();
@@ -41,7 +43,7 @@ fn test_file_sketching_basic() {
             .unwrap();
 
     let expected_sketch = sourmash::signature::Signature::from_path(path::Path::new(
-        "tests/testfiles/test.small.fasta.sourmash_k33.sig",
+        "tests/testfiles/test.short.fa.sourmash_k33.sig",
     ))
     .unwrap()
     .pop()
@@ -52,31 +54,54 @@ fn test_file_sketching_basic() {
 
     for (created, expected) in get_hashes_sketch(&created_sketch)
         .into_iter()
-        .zip(get_hashes_sketch(&expected_sketch).into_iter())
+        .zip(get_hashes_sketch(&expected_sketch))
     {
         println!("{} == {}", created, expected);
         assert_eq!(created, expected);
     }
+    // Scaled sketches have num = 0, otherwise sourmash treats them as num sketches
+    if let (Sketch::MinHash(created), Sketch::MinHash(expected)) =
+        (&created_sketch, &expected_sketch)
+    {
+        assert_eq!(created.num(), 0);
+        assert_eq!(created.num(), expected.num());
+    }
+    fs::remove_file("test.small.fa.test").unwrap();
 }
 
 #[test]
 fn test_file_sketching_lmdb() {
     let input_file = "tests/testfiles/test.small.fa";
-    fs::create_dir("testout").unwrap();
+    fs::create_dir_all("testout").unwrap();
     FileHandler::sketch_files(
-        jam_rs::cli::Commands::Sketch {
+        SketchArgs {
             input: vec![PathBuf::from(input_file)],
             output: Some(PathBuf::from("testout")),
-            kmer_size: 33,
-            fscale: None,
-            nmax: None,
-            format: jam_rs::cli::OutputFormats::Lmdb,
+            kmer_size: vec![33],
             algorithm: jam_rs::cli::HashAlgorithms::Murmur3,
-            singleton: false,
-        },
+            ..Default::default()
+        }
+        .into(),
         None,
     )
     .unwrap();
+
+    let meta = jam_rs::heed::HeedHandler::new_ro(PathBuf::from("testout/compact.mdb"))
+        .unwrap()
+        .get_meta()
+        .unwrap()
+        .unwrap();
+    assert_eq!(meta.version, jam_rs::file_io::META_VERSION);
+    assert_eq!(meta.algorithm, jam_rs::cli::HashAlgorithms::Murmur3);
+    assert_eq!(meta.kmer_size, 33);
+    assert_eq!(meta.fscale, None);
+
+    let comparator =
+        jam_rs::compare::LmdbComparator::new(PathBuf::from("testout/compact.mdb"), 1, 0.0, true)
+            .unwrap();
+    assert_eq!(comparator.algorithm, jam_rs::cli::HashAlgorithms::Murmur3);
+    assert_eq!(comparator.kmer_size, 33);
+    fs::remove_dir_all("testout").unwrap();
 }
 
 // #[test]
@@ -103,13 +128,13 @@ fn test_file_sketching_lmdb() {
 //     let signature = signature.pop().unwrap();
 
 //     let expected_signature = sourmash::signature::Signature::from_path(path::Path::new(
-//         "tests/testfiles/test.small.fasta.sourmash_k33.sig",
+//         "tests/testfiles/test.short.fa.sourmash_k33.sig",
 //     ))
 //     .unwrap()
 //     .pop()
 //     .unwrap();
 
-//     let expected_signature = jam_rs::signature::Signature::from(expected_signature);
+//     let expected_signature = jam_rs::signature::Signature::try_from(expected_signature).unwrap();
 
 //     assert_eq!(signature.max_hash, expected_signature.max_hash);
 //     assert_eq!(signature.kmer_size, expected_signature.kmer_size);
@@ -119,3 +144,848 @@ fn test_file_sketching_lmdb() {
 //         expected_signature.sketches[0].hashes
 //     );
 // }
+
+#[test]
+fn test_file_sketching_lmdb_append() {
+    let sketch = |kmer_size: u8, append: bool| {
+        FileHandler::sketch_files(
+            SketchArgs {
+                input: vec![PathBuf::from("tests/testfiles/short.fa")],
+                output: Some(PathBuf::from("testout_append")),
+                kmer_size: vec![kmer_size],
+                algorithm: jam_rs::cli::HashAlgorithms::Murmur3,
+                append,
+                ..Default::default()
+            }
+            .into(),
+            None,
+        )
+    };
+    fs::create_dir_all("testout_append").unwrap();
+    sketch(21, false).unwrap();
+    sketch(21, true).unwrap();
+
+    // Mismatching kmer sizes are rejected and leave the database untouched
+    assert!(sketch(31, true).is_err());
+    // Settings that are only stored in the header must match as well
+    for args in [
+        SketchArgs {
+            nmax: Some(10),
+            ..Default::default()
+        },
+        SketchArgs {
+            track_abundance: true,
+            ..Default::default()
+        },
+        SketchArgs {
+            window: Some(100),
+            ..Default::default()
+        },
+        SketchArgs {
+            stats: true,
+            ..Default::default()
+        },
+        SketchArgs {
+            kscale: Some(10),
+            ..Default::default()
+        },
+    ] {
+        assert!(FileHandler::sketch_files(
+            SketchArgs {
+                input: vec![PathBuf::from("tests/testfiles/short.fa")],
+                output: Some(PathBuf::from("testout_append")),
+                algorithm: jam_rs::cli::HashAlgorithms::Murmur3,
+                append: true,
+                ..args
+            }
+            .into(),
+            None,
+        )
+        .is_err());
+    }
+
+    let comparator = jam_rs::compare::LmdbComparator::new(
+        PathBuf::from("testout_append/compact.mdb"),
+        1,
+        0.0,
+        true,
+    )
+    .unwrap();
+    let infos = comparator.infos.read().unwrap();
+    assert_eq!(infos.len(), 2);
+    assert!(infos.contains_key(&0));
+    assert!(infos.contains_key(&1));
+    drop(infos);
+    fs::remove_dir_all("testout_append").unwrap();
+}
+
+#[test]
+fn test_file_sketching_sourmash_k21() {
+    use sourmash::signature::SigsTrait;
+
+    let input_file = PathBuf::from("tests/testfiles/short.fa");
+    let signature = FileHandler::sketch_file(
+        &input_file,
+        &jam_rs::file_io::DatabaseMeta::new(
+            jam_rs::cli::HashAlgorithms::Murmur3,
+            21,
+            None,
+            None,
+            false,
+        ),
+        false,
+        false,
+    )
+    .unwrap();
+
+    let mut expected = sourmash::sketch::minhash::KmerMinHash::new(
+        1,
+        21,
+        sourmash::encodings::HashFunctions::Murmur64Dna,
+        42,
+        false,
+        0,
+    );
+    let mut reader = needletail::parse_fastx_file(&input_file).unwrap();
+    while let Some(record) = reader.next() {
+        expected
+            .add_sequence(&record.unwrap().seq(), false)
+            .unwrap();
+    }
+
+    let created = signature.sketches[0]
+        .hashes
+        .iter()
+        .copied()
+        .collect::<Vec<u64>>();
+    assert!(!created.is_empty());
+    assert_eq!(created, expected.mins());
+
+    // The settings for query sketches are derived from the (sourmash) database
+    let settings = jam_rs::file_io::DatabaseMeta::from_signatures(&[signature]).unwrap();
+    assert_eq!(settings.algorithm, jam_rs::cli::HashAlgorithms::Murmur3);
+    assert_eq!(settings.kmer_size, 21);
+    assert_eq!(settings.fscale, None);
+}
+
+#[test]
+fn test_load_precomputed_sketches() {
+    let input_file = PathBuf::from("tests/testfiles/short.fa");
+    fs::create_dir_all("testout_load").unwrap();
+    FileHandler::sketch_files(
+        SketchArgs {
+            input: vec![input_file.clone()],
+            output: Some(PathBuf::from("testout_load")),
+            algorithm: jam_rs::cli::HashAlgorithms::Murmur3,
+            ..Default::default()
+        }
+        .into(),
+        None,
+    )
+    .unwrap();
+
+    let settings = jam_rs::file_io::DatabaseMeta::new(
+        jam_rs::cli::HashAlgorithms::Murmur3,
+        21,
+        None,
+        None,
+        false,
+    );
+    let sketched = FileHandler::load_or_sketch_file(&input_file, &settings).unwrap();
+    let loaded =
+        FileHandler::load_or_sketch_file(&PathBuf::from("testout_load/compact.mdb"), &settings)
+            .unwrap();
+    assert_eq!(loaded.len(), 1);
+    assert_eq!(loaded[0].sketches[0].hashes, sketched[0].sketches[0].hashes);
+
+    // Sourmash signatures with a different kmer size are rejected
+    let sourmash = FileHandler::load_or_sketch_file(
+        &PathBuf::from("tests/testfiles/test.short.fa.sourmash_k33.sig"),
+        &settings,
+    );
+    assert!(sourmash.is_err());
+    fs::remove_dir_all("testout_load").unwrap();
+}
+
+#[test]
+fn test_file_sketching_abundance() {
+    let input_file = PathBuf::from("tests/testfiles/short.fa");
+    let sketch = |output: &str, format: jam_rs::cli::OutputFormats| {
+        FileHandler::sketch_files(
+            SketchArgs {
+                input: vec![input_file.clone()],
+                output: Some(PathBuf::from(output)),
+                format,
+                algorithm: jam_rs::cli::HashAlgorithms::Murmur3,
+                track_abundance: true,
+                ..Default::default()
+            }
+            .into(),
+            None,
+        )
+        .unwrap();
+    };
+    fs::create_dir_all("testout_abundance").unwrap();
+    sketch("testout_abundance", jam_rs::cli::OutputFormats::Lmdb);
+    sketch("test.abundance.sig", jam_rs::cli::OutputFormats::Sourmash);
+
+    // Sourmash output contains the abundances
+    let sourmash = FileHandler::read_signatures(&PathBuf::from("test.abundance.sig")).unwrap();
+    let abundances = sourmash[0].sketches[0].abundances.clone().unwrap();
+    assert_eq!(abundances.len(), sourmash[0].sketches[0].hashes.len());
+    assert!(abundances.iter().all(|a| *a >= 1));
+
+    // Lmdb round trip keeps the abundances
+    let settings = jam_rs::file_io::DatabaseMeta::new(
+        jam_rs::cli::HashAlgorithms::Murmur3,
+        21,
+        None,
+        None,
+        false,
+    );
+    let loaded = FileHandler::load_or_sketch_file(
+        &PathBuf::from("testout_abundance/compact.mdb"),
+        &settings,
+    )
+    .unwrap();
+    assert_eq!(loaded[0].sketches[0].abundances, Some(abundances));
+
+    // A query against itself explains all of its abundance
+    let mut comparator = jam_rs::compare::LmdbComparator::new(
+        PathBuf::from("testout_abundance/compact.mdb"),
+        1,
+        0.0,
+        true,
+    )
+    .unwrap();
+    comparator.set_signatures(sourmash);
+    let result = comparator.compare().unwrap();
+    assert_eq!(result[0].abundance_containment, Some(100.0));
+    assert!(result[0].mean_coverage.unwrap() >= 1.0);
+
+    fs::remove_dir_all("testout_abundance").unwrap();
+    fs::remove_file("test.abundance.sig").unwrap();
+}
+
+#[test]
+fn test_file_sketching_protein() {
+    use sourmash::signature::SigsTrait;
+
+    let sketch = |input: &str, output: &str, molecule, input_is_protein| {
+        FileHandler::sketch_files(
+            SketchArgs {
+                input: vec![PathBuf::from(input)],
+                output: Some(PathBuf::from(output)),
+                kmer_size: vec![10],
+                format: jam_rs::cli::OutputFormats::Sourmash,
+                algorithm: jam_rs::cli::HashAlgorithms::Murmur3,
+                molecule,
+                input_is_protein,
+                ..Default::default()
+            }
+            .into(),
+            None,
+        )
+        .unwrap();
+    };
+
+    // Six frame translation of DNA input
+    sketch(
+        "tests/testfiles/short.fa",
+        "test.protein.sig",
+        jam_rs::cli::Molecule::Protein,
+        false,
+    );
+    let mut expected = sourmash::sketch::minhash::KmerMinHash::new(
+        1,
+        30,
+        sourmash::encodings::HashFunctions::Murmur64Protein,
+        42,
+        false,
+        0,
+    );
+    let mut reader = needletail::parse_fastx_file("tests/testfiles/short.fa").unwrap();
+    while let Some(record) = reader.next() {
+        expected
+            .add_sequence(&record.unwrap().seq(), false)
+            .unwrap();
+    }
+    let created = FileHandler::read_signatures(&PathBuf::from("test.protein.sig")).unwrap();
+    assert_eq!(created[0].molecule, jam_rs::cli::Molecule::Protein);
+    assert_eq!(created[0].kmer_size, 10);
+    let hashes = created[0].sketches[0]
+        .hashes
+        .iter()
+        .copied()
+        .collect::<Vec<u64>>();
+    assert!(!hashes.is_empty());
+    assert_eq!(hashes, expected.mins());
+
+    // Protein input with reduced alphabet
+    let protein = "MKVLAAGIVGLLLAACSSEKPQTEEQAKHLNDAVRELIKQPEFKKLLENQELRPKEA";
+    fs::write("test.protein.faa", format!(">protein\n{}\n", protein)).unwrap();
+    sketch(
+        "test.protein.faa",
+        "test.dayhoff.sig",
+        jam_rs::cli::Molecule::Dayhoff,
+        true,
+    );
+    let mut expected = sourmash::sketch::minhash::KmerMinHash::new(
+        1,
+        30,
+        sourmash::encodings::HashFunctions::Murmur64Dayhoff,
+        42,
+        false,
+        0,
+    );
+    expected.add_protein(protein.as_bytes()).unwrap();
+    let created = FileHandler::read_signatures(&PathBuf::from("test.dayhoff.sig")).unwrap();
+    assert_eq!(created[0].molecule, jam_rs::cli::Molecule::Dayhoff);
+    let hashes = created[0].sketches[0]
+        .hashes
+        .iter()
+        .copied()
+        .collect::<Vec<u64>>();
+    assert_eq!(hashes, expected.mins());
+
+    // Protein and DNA sketches can not be compared
+    let settings = jam_rs::file_io::DatabaseMeta::new(
+        jam_rs::cli::HashAlgorithms::Murmur3,
+        10,
+        None,
+        None,
+        false,
+    );
+    assert!(
+        FileHandler::load_or_sketch_file(&PathBuf::from("test.dayhoff.sig"), &settings).is_err()
+    );
+
+    for file in ["test.protein.sig", "test.protein.faa", "test.dayhoff.sig"] {
+        fs::remove_file(file).unwrap();
+    }
+}
+
+#[test]
+fn test_file_sketching_multiple_kmer_sizes() {
+    let input_file = PathBuf::from("tests/testfiles/short.fa");
+    let sketch = |output: &str, kmer_size: Vec<u8>, format| {
+        FileHandler::sketch_files(
+            SketchArgs {
+                input: vec![input_file.clone()],
+                output: Some(PathBuf::from(output)),
+                kmer_size,
+                format,
+                algorithm: jam_rs::cli::HashAlgorithms::Murmur3,
+                ..Default::default()
+            }
+            .into(),
+            None,
+        )
+        .unwrap();
+    };
+
+    // One lmdb database per kmer size
+    fs::create_dir_all("testout_multik").unwrap();
+    sketch(
+        "testout_multik",
+        vec![21, 31],
+        jam_rs::cli::OutputFormats::Lmdb,
+    );
+    for kmer_size in [21, 31] {
+        let path = PathBuf::from(format!("testout_multik/k{}/compact.mdb", kmer_size));
+        let handler = jam_rs::heed::HeedHandler::new_ro(path).unwrap();
+        assert_eq!(handler.get_meta().unwrap().unwrap().kmer_size, kmer_size);
+        assert_eq!(handler.load_signatures().unwrap().len(), 1);
+    }
+
+    // Sourmash output is identical to separate runs
+    sketch(
+        "test.multik.sig",
+        vec![31, 21],
+        jam_rs::cli::OutputFormats::Sourmash,
+    );
+    sketch(
+        "test.k21.sig",
+        vec![21],
+        jam_rs::cli::OutputFormats::Sourmash,
+    );
+    sketch(
+        "test.k31.sig",
+        vec![31],
+        jam_rs::cli::OutputFormats::Sourmash,
+    );
+    let multi = FileHandler::read_signatures(&PathBuf::from("test.multik.sig")).unwrap();
+    assert_eq!(multi.len(), 2);
+    for single in ["test.k21.sig", "test.k31.sig"] {
+        let single = FileHandler::read_signatures(&PathBuf::from(single)).unwrap();
+        let found = multi
+            .iter()
+            .find(|sig| sig.kmer_size == single[0].kmer_size)
+            .unwrap();
+        assert_eq!(found.sketches[0].hashes, single[0].sketches[0].hashes);
+    }
+
+    fs::remove_dir_all("testout_multik").unwrap();
+    for file in ["test.multik.sig", "test.k21.sig", "test.k31.sig"] {
+        fs::remove_file(file).unwrap();
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_file_sketching_named_pipe() {
+    let fifo = PathBuf::from("test.sketch.fifo");
+    let _ = fs::remove_file(&fifo);
+    assert!(std::process::Command::new("mkfifo")
+        .arg(&fifo)
+        .status()
+        .unwrap()
+        .success());
+    assert!(jam_rs::file_io::test_stream(&fifo));
+
+    let writer_path = fifo.clone();
+    let writer = std::thread::spawn(move || {
+        let content = fs::read("tests/testfiles/short.fa").unwrap();
+        fs::write(writer_path, content).unwrap();
+    });
+    FileHandler::sketch_files(
+        SketchArgs {
+            input: vec![fifo.clone()],
+            output: Some(PathBuf::from("test.fifo.sig")),
+            name: Some("streamed".to_string()),
+            format: jam_rs::cli::OutputFormats::Sourmash,
+            algorithm: jam_rs::cli::HashAlgorithms::Murmur3,
+            ..Default::default()
+        }
+        .into(),
+        None,
+    )
+    .unwrap();
+    writer.join().unwrap();
+
+    let streamed = FileHandler::read_signatures(&PathBuf::from("test.fifo.sig")).unwrap();
+    assert_eq!(streamed[0].file_name, "streamed");
+    let expected = FileHandler::sketch_file(
+        &PathBuf::from("tests/testfiles/short.fa"),
+        &jam_rs::file_io::DatabaseMeta::new(
+            jam_rs::cli::HashAlgorithms::Murmur3,
+            21,
+            None,
+            None,
+            false,
+        ),
+        false,
+        false,
+    )
+    .unwrap();
+    assert_eq!(streamed[0].sketches[0].hashes, expected.sketches[0].hashes);
+
+    fs::remove_file(fifo).unwrap();
+    fs::remove_file("test.fifo.sig").unwrap();
+}
+
+#[test]
+fn test_file_sketching_parallel() {
+    // Pseudo random records, large enough to be split into multiple chunks
+    let mut state = 42u64;
+    let mut content = String::new();
+    for record in 0..400 {
+        content.push_str(&format!(">record_{}\n", record));
+        for _ in 0..10_000 {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            content.push(['A', 'C', 'G', 'T'][(state >> 62) as usize]);
+        }
+        content.push('\n');
+    }
+    let input_file = PathBuf::from("test.parallel.fa");
+    fs::write(&input_file, content).unwrap();
+
+    let settings = [
+        jam_rs::file_io::DatabaseMeta {
+            track_abundance: true,
+            ..jam_rs::file_io::DatabaseMeta::new(
+                jam_rs::cli::HashAlgorithms::Default,
+                21,
+                Some(100),
+                Some(5000),
+                false,
+            )
+        },
+        jam_rs::file_io::DatabaseMeta::new(
+            jam_rs::cli::HashAlgorithms::Murmur3,
+            21,
+            Some(100),
+            None,
+            true,
+        ),
+    ];
+    for settings in settings {
+        let sketch = |threads| {
+            FileHandler::sketch_file_kmers(
+                &input_file,
+                &settings,
+                &[21, 31],
+                None,
+                false,
+                threads,
+                false,
+            )
+            .unwrap()
+        };
+        let single = sketch(1);
+        let parallel = sketch(4);
+        assert_eq!(single.len(), parallel.len());
+        for (single, parallel) in single.iter().zip(parallel.iter()) {
+            assert_eq!(single.kmer_size, parallel.kmer_size);
+            assert_eq!(single.sketches.len(), parallel.sketches.len());
+            for (a, b) in single.sketches.iter().zip(parallel.sketches.iter()) {
+                assert_eq!(a.name, b.name);
+                assert_eq!(a.num_kmers, b.num_kmers);
+                assert_eq!(a.hashes, b.hashes);
+                assert_eq!(a.abundances, b.abundances);
+            }
+        }
+    }
+    fs::remove_file(input_file).unwrap();
+}
+
+#[test]
+fn test_sketch_stats() {
+    let sketch = |format, output: &str| {
+        FileHandler::sketch_files(
+            SketchArgs {
+                input: vec![PathBuf::from("tests/testfiles/test.small.fa")],
+                output: Some(PathBuf::from(output)),
+                format,
+                algorithm: jam_rs::cli::HashAlgorithms::Murmur3,
+                singleton: true,
+                stats: true,
+                ..Default::default()
+            }
+            .into(),
+            None,
+        )
+        .unwrap();
+    };
+    let lengths = vec![221938, 198222, 167214, 12360];
+
+    // Sourmash signatures keep the stats as additional field
+    sketch(jam_rs::cli::OutputFormats::Sourmash, "testout_stats.sig");
+    let signatures = FileHandler::read_signatures(&PathBuf::from("testout_stats.sig")).unwrap();
+    let stats = signatures[0]
+        .sketches
+        .iter()
+        .map(|sketch| sketch.stats.unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        stats.iter().take(4).map(|s| s.length).collect::<Vec<_>>(),
+        lengths
+    );
+    for stats in stats.iter().take(4) {
+        let gc = stats.gc_content().unwrap();
+        assert!(gc > 20.0 && gc < 80.0);
+    }
+    // The signature is still readable by sourmash
+    let sourmash = sourmash::signature::Signature::from_path("testout_stats.sig").unwrap();
+    assert_eq!(sourmash.len(), 1);
+    fs::remove_file("testout_stats.sig").unwrap();
+
+    fs::create_dir_all("testout_stats").unwrap();
+    sketch(jam_rs::cli::OutputFormats::Lmdb, "testout_stats");
+    let loaded = FileHandler::load_or_sketch_file(
+        &PathBuf::from("testout_stats/compact.mdb"),
+        &jam_rs::file_io::DatabaseMeta::new(
+            jam_rs::cli::HashAlgorithms::Murmur3,
+            21,
+            None,
+            None,
+            false,
+        ),
+    )
+    .unwrap();
+    assert_eq!(
+        loaded
+            .iter()
+            .take(4)
+            .map(|sig| sig.sketches[0].stats.unwrap().length)
+            .collect::<Vec<_>>(),
+        lengths
+    );
+    for (sig, stats) in loaded.iter().zip(stats) {
+        assert_eq!(sig.sketches[0].stats, Some(stats));
+    }
+    fs::remove_dir_all("testout_stats").unwrap();
+}
+
+#[test]
+fn test_sketch_bias_scaling() {
+    let sketch = |fscale, nmin, kscale, output: &str| {
+        FileHandler::sketch_files(
+            SketchArgs {
+                input: vec![PathBuf::from("tests/testfiles/test.small.fa")],
+                output: Some(PathBuf::from(output)),
+                fscale,
+                format: jam_rs::cli::OutputFormats::Sourmash,
+                algorithm: jam_rs::cli::HashAlgorithms::Murmur3,
+                singleton: true,
+                nmin,
+                kscale,
+                ..Default::default()
+            }
+            .into(),
+            None,
+        )
+        .unwrap();
+        let signatures = FileHandler::read_signatures(&PathBuf::from(output)).unwrap();
+        fs::remove_file(output).unwrap();
+        signatures.into_iter().next().unwrap()
+    };
+
+    // KmerCountScaling: the number of hashes is proportional to the number of kmers
+    let signature = sketch(None, None, Some(1000), "testout_kscale.sig");
+    assert_eq!(signature.scaling.kscale, Some(1000));
+    let lengths = [221938, 198222, 167214, 12360];
+    for (sketch, length) in signature.sketches.iter().zip(lengths) {
+        let expected = (length - 20usize).div_ceil(1000);
+        assert!(sketch.hashes.len() <= expected && sketch.hashes.len() > expected * 9 / 10);
+        assert_eq!(
+            sketch.max_hash,
+            sketch.hashes.last().map(|hash| hash + 1),
+            "{}",
+            sketch.name
+        );
+    }
+
+    // MinMaxAbsoluteScaling: small records are filled up to nmin hashes
+    let uniform = sketch(Some(10000), None, None, "testout_fscale.sig");
+    let signature = sketch(Some(10000), Some(50), None, "testout_nmin.sig");
+    assert_eq!(signature.scaling.nmin, Some(50));
+    for (sketch, uniform) in signature
+        .sketches
+        .iter()
+        .zip(uniform.sketches.iter())
+        .take(4)
+    {
+        assert!(sketch.hashes.len() >= 50);
+        // Hashes below the fscale max hash are identical
+        assert!(uniform.hashes.is_subset(&sketch.hashes));
+        if uniform.hashes.len() < 50 {
+            assert_eq!(sketch.max_hash, sketch.hashes.last().map(|hash| hash + 1));
+        } else {
+            assert_eq!(sketch.hashes, uniform.hashes);
+            assert_eq!(sketch.max_hash, uniform.max_hash);
+        }
+    }
+}
+
+#[test]
+fn test_sketch_sampling() {
+    let sketch = |sampling, smer_size, minimizer_window, output: &str| {
+        FileHandler::sketch_files(
+            SketchArgs {
+                input: vec![PathBuf::from("tests/testfiles/test.small.fa")],
+                output: Some(PathBuf::from(output)),
+                format: jam_rs::cli::OutputFormats::Sourmash,
+                algorithm: jam_rs::cli::HashAlgorithms::Murmur3,
+                sampling,
+                smer_size,
+                minimizer_window,
+                ..Default::default()
+            }
+            .into(),
+            None,
+        )?;
+        let signatures = FileHandler::read_signatures(&PathBuf::from(output)).unwrap();
+        fs::remove_file(output).unwrap();
+        Ok::<_, anyhow::Error>(signatures.into_iter().next().unwrap())
+    };
+    let all = sketch(jam_rs::cli::Sampling::All, None, None, "testout_all.sig").unwrap();
+
+    // Selected kmers are a subset of all kmers, the scheme is kept in the signature
+    for (sampling, smer_size, window, max_density) in [
+        (jam_rs::cli::Sampling::Minimizer, None, Some(10), 0.25),
+        (jam_rs::cli::Sampling::OpenSyncmer, Some(16), None, 0.25),
+        (jam_rs::cli::Sampling::ClosedSyncmer, Some(16), None, 0.5),
+    ] {
+        let sampled = sketch(sampling.clone(), smer_size, window, "testout_sampled.sig").unwrap();
+        assert_eq!(
+            sampled.sampling,
+            jam_rs::sampling::SamplingScheme::new(&sampling, smer_size, window).unwrap()
+        );
+        let (sampled_hashes, all_hashes) = (&sampled.sketches[0].hashes, &all.sketches[0].hashes);
+        assert!(sampled_hashes.is_subset(all_hashes));
+        let density = sampled_hashes.len() as f64 / all_hashes.len() as f64;
+        assert!(
+            density > 0.05 && density < max_density,
+            "{sampling:?}: {density}"
+        );
+
+        // Signatures with different schemes can not be compared
+        assert!(jam_rs::compare::MultiComp::new(vec![sampled], vec![all.clone()], 1, 0.0).is_err());
+    }
+
+    // S-mers must be smaller than the kmers
+    assert!(sketch(
+        jam_rs::cli::Sampling::OpenSyncmer,
+        Some(21),
+        None,
+        "testout_invalid.sig"
+    )
+    .is_err());
+}
+
+#[test]
+fn test_sketch_wide_kmers() {
+    let input = PathBuf::from("tests/testfiles/test.small.fa");
+    let sketch = |algorithm| {
+        let settings = jam_rs::file_io::DatabaseMeta::new(algorithm, 32, None, None, true);
+        FileHandler::sketch_file_kmers(&input, &settings, &[32, 51, 63], None, false, 1, false)
+            .unwrap()
+    };
+    // Murmur3 hashes the canonical kmer bytes (sourmash compatible)
+    let bytes = sketch(jam_rs::cli::HashAlgorithms::Murmur3);
+    for algorithm in [
+        jam_rs::cli::HashAlgorithms::Default,
+        jam_rs::cli::HashAlgorithms::Ahash,
+        jam_rs::cli::HashAlgorithms::Xxhash,
+    ] {
+        let wide = sketch(algorithm.clone());
+        assert_eq!(wide.len(), 3);
+        // 2-bit encoded canonical kmers are the same kmers as the canonical kmer bytes
+        for (wide, bytes) in wide.iter().zip(bytes.iter()) {
+            assert_eq!(wide.kmer_size, bytes.kmer_size);
+            for (wide, bytes) in wide.sketches.iter().zip(bytes.sketches.iter()) {
+                assert_eq!(wide.hashes.len(), bytes.hashes.len(), "{algorithm:?}");
+                if !wide.hashes.is_empty() {
+                    assert_ne!(wide.hashes, bytes.hashes);
+                }
+            }
+        }
+    }
+
+    // Databases of older versions hashed these kmers as bytes
+    let old = jam_rs::file_io::DatabaseMeta {
+        version: 1,
+        ..jam_rs::file_io::DatabaseMeta::new(
+            jam_rs::cli::HashAlgorithms::Default,
+            51,
+            None,
+            None,
+            false,
+        )
+    };
+    assert!(old.check_kmer_encoding().is_err());
+    let old = jam_rs::file_io::DatabaseMeta {
+        kmer_size: 21,
+        ..old
+    };
+    assert!(old.check_kmer_encoding().is_ok());
+}
+
+#[test]
+fn test_sketch_custom_hasher() {
+    // Downstream crates can plug in their own hash functions
+    struct Fnv;
+    impl jam_rs::hash_functions::KmerHasher for Fnv {
+        fn name(&self) -> &str {
+            "fnv1a"
+        }
+        fn hash_large(&self, kmer: &[u8], seed: u64) -> u64 {
+            kmer.iter().fold(0xcbf29ce484222325 ^ seed, |hash, byte| {
+                (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+            })
+        }
+    }
+    let algorithm = jam_rs::cli::HashAlgorithms::Custom("fnv1a".to_string());
+    let settings = jam_rs::file_io::DatabaseMeta::new(algorithm.clone(), 21, None, None, false);
+    let input = PathBuf::from("tests/testfiles/short.fa");
+    assert!(FileHandler::sketch_file(&input, &settings, false, false).is_err());
+
+    jam_rs::hash_functions::register_hasher(std::sync::Arc::new(Fnv)).unwrap();
+    let signature = FileHandler::sketch_file(&input, &settings, false, false).unwrap();
+    let murmur3 = FileHandler::sketch_file(
+        &input,
+        &jam_rs::file_io::DatabaseMeta::new(
+            jam_rs::cli::HashAlgorithms::Murmur3,
+            21,
+            None,
+            None,
+            false,
+        ),
+        false,
+        false,
+    )
+    .unwrap();
+    // Both hash the canonical kmer bytes
+    assert_eq!(
+        signature.sketches[0].hashes.len(),
+        murmur3.sketches[0].hashes.len()
+    );
+
+    // The algorithm is stored by name
+    FileHandler::sketch_files(
+        SketchArgs {
+            input: vec![input],
+            output: Some(PathBuf::from("testout_custom.sig")),
+            format: jam_rs::cli::OutputFormats::Sourmash,
+            algorithm: algorithm.clone(),
+            ..Default::default()
+        }
+        .into(),
+        None,
+    )
+    .unwrap();
+    let loaded = FileHandler::read_signatures(&PathBuf::from("testout_custom.sig")).unwrap();
+    assert_eq!(loaded[0].algorithm, algorithm);
+    assert_eq!(loaded[0].sketches[0].hashes, signature.sketches[0].hashes);
+    fs::remove_file("testout_custom.sig").unwrap();
+}
+
+#[test]
+fn test_sketch_seed() {
+    let input = PathBuf::from("tests/testfiles/short.fa");
+    let settings = |seed| jam_rs::file_io::DatabaseMeta {
+        seed,
+        ..jam_rs::file_io::DatabaseMeta::new(
+            jam_rs::cli::HashAlgorithms::Default,
+            21,
+            None,
+            None,
+            false,
+        )
+    };
+    let default = FileHandler::sketch_file(&input, &settings(42), false, false).unwrap();
+    let seeded = FileHandler::sketch_file(&input, &settings(7), false, false).unwrap();
+    assert_eq!(seeded.seed, 7);
+    // Same number of kmers, but independent hashes
+    assert_eq!(
+        default.sketches[0].hashes.len(),
+        seeded.sketches[0].hashes.len()
+    );
+    assert_ne!(default.sketches[0].hashes, seeded.sketches[0].hashes);
+    assert!(settings(42).check_compatible(&settings(7)).is_err());
+    assert!(jam_rs::compare::MultiComp::new(vec![default], vec![seeded.clone()], 1, 0.0).is_err());
+
+    // The seed is stored as the seed of the sourmash sketches
+    FileHandler::sketch_files(
+        SketchArgs {
+            input: vec![input],
+            output: Some(PathBuf::from("testout_seed.sig")),
+            format: jam_rs::cli::OutputFormats::Sourmash,
+            seed: 7,
+            ..Default::default()
+        }
+        .into(),
+        None,
+    )
+    .unwrap();
+    let json: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string("testout_seed.sig").unwrap()).unwrap();
+    assert_eq!(json[0]["signatures"][0]["seed"], 7);
+    let loaded = FileHandler::read_signatures(&PathBuf::from("testout_seed.sig")).unwrap();
+    assert_eq!(loaded[0].seed, 7);
+    assert_eq!(loaded[0].sketches[0].hashes, seeded.sketches[0].hashes);
+    fs::remove_file("testout_seed.sig").unwrap();
+}
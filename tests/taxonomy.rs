@@ -0,0 +1,81 @@
+mod common;
+
+use common::SketchArgs;
+use jam_rs::{
+    cli::HashAlgorithms,
+    compare::LmdbComparator,
+    file_io::{DatabaseMeta, FileHandler},
+    taxonomy::Classifier,
+};
+use std::{fs, path::PathBuf};
+
+#[test]
+fn test_classify_lineages() {
+    fs::create_dir_all("classify_db").unwrap();
+    fs::write(
+        "classify_lineages.csv",
+        "ident,lineage\n\
+         NODE_1_length_221938_cov_19.431827,Bacteria;P1;C1\n\
+         NODE_2_length_198222_cov_23.271988,Bacteria;P1;C2\n\
+         NODE_3_length_167214_cov_10.505285,Bacteria;P2\n\
+         NODE_4_length_163266_cov_19.477302,Archaea\n",
+    )
+    .unwrap();
+    FileHandler::sketch_files(
+        SketchArgs {
+            input: vec![PathBuf::from("tests/testfiles/test.small.fa")],
+            output: Some(PathBuf::from("classify_db")),
+            algorithm: HashAlgorithms::Murmur3,
+            singleton: true,
+            lineages: Some(PathBuf::from("classify_lineages.csv")),
+            ..Default::default()
+        }
+        .into(),
+        None,
+    )
+    .unwrap();
+
+    let mut comparator =
+        LmdbComparator::new(PathBuf::from("classify_db/compact.mdb"), 1, 0.0, true).unwrap();
+    // The additional whole file sketch of singleton databases has no lineage
+    assert_eq!(
+        comparator
+            .infos
+            .read()
+            .unwrap()
+            .values()
+            .filter(|info| info.lineage.is_some())
+            .count(),
+        4
+    );
+
+    let query = FileHandler::sketch_file(
+        &PathBuf::from("tests/testfiles/test.small.fa"),
+        &DatabaseMeta::new(HashAlgorithms::Murmur3, 21, None, None, false),
+        false,
+        false,
+    )
+    .unwrap();
+    comparator.set_signatures(vec![query]);
+
+    // NODE_1 and NODE_2 contain the majority of all hashes
+    let classifier = Classifier::new(&comparator, 0.5);
+    let results = classifier.classify().unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].rank, "phylum");
+    assert_eq!(results[0].lineage, "Bacteria;P1");
+
+    let summary = classifier.summarize().unwrap();
+    let superkingdoms = summary
+        .iter()
+        .filter(|r| r.rank == "superkingdom")
+        .collect::<Vec<_>>();
+    assert_eq!(superkingdoms.len(), 2);
+    assert_eq!(superkingdoms[0].lineage, "Bacteria");
+    assert_eq!(superkingdoms[1].lineage, "Archaea");
+    assert!((superkingdoms.iter().map(|r| r.f_hashes).sum::<f64>() - 100.0).abs() < 1e-9);
+    assert_eq!(summary.iter().filter(|r| r.rank == "class").count(), 2);
+
+    fs::remove_dir_all("classify_db").unwrap();
+    fs::remove_file("classify_lineages.csv").unwrap();
+}